@@ -0,0 +1,309 @@
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+
+use crate::logging::{LogFormat, LogProperties};
+
+const DEFAULT_CONFIG_PATH: &str = ".config/rust-dev-sync-config.yaml";
+const DEFAULT_SOCKET_NAME: &str = "rust-dev-sync.sock";
+
+/// Watches local workspaces and syncs them to other places as files change
+#[derive(Debug, Parser)]
+#[clap(name = "rust-dev-sync", version)]
+pub struct Cli {
+    /// Path to the config file. Defaults to ~/.config/rust-dev-sync-config.yaml
+    #[clap(long, global = true)]
+    pub config: Option<String>,
+
+    /// Path of the socket the daemon listens on for commands. Defaults to
+    /// $XDG_RUNTIME_DIR/rust-dev-sync.sock, or ~/.rust-dev-sync.sock when that isn't set
+    #[clap(long, global = true)]
+    pub socket: Option<PathBuf>,
+
+    /// Log more detailed output. Shorthand for --log-level debug
+    #[clap(short, long, global = true)]
+    pub verbose: bool,
+
+    /// Log level, e.g. debug, or directives in the RUST_LOG format. Overrides the config file
+    #[clap(long, global = true)]
+    pub log_level: Option<String>,
+
+    /// Format of the logs. Overrides the config file
+    #[clap(long, arg_enum, global = true)]
+    pub log_format: Option<LogFormat>,
+
+    /// Only run the given workspace (by name or source directory). Can be repeated
+    #[clap(short, long = "workspace", global = true)]
+    pub workspaces: Vec<String>,
+
+    #[clap(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Watch workspaces and sync them whenever files change
+    Sync,
+    /// Sync every workspace in full once and exit
+    Once,
+    /// Show the state of each workspace and syncer of the running daemon
+    Status,
+    /// Make the running daemon sync workspaces in full
+    Resync,
+    /// Make the running daemon hold back syncing until resumed. Changes keep being collected and
+    /// are synced once resumed
+    Pause {
+        /// Only pause the syncer with the given name, as shown by `status`
+        #[clap(long)]
+        syncer: Option<String>,
+    },
+    /// Resume syncing paused workspaces of the running daemon
+    Resume {
+        /// Only resume the syncer with the given name, as shown by `status`
+        #[clap(long)]
+        syncer: Option<String>,
+    },
+    /// Make the running daemon reload the config file
+    Reload,
+    /// Stop the running daemon
+    Shutdown,
+    /// Check that the config file is valid
+    Validate,
+    /// List the snapshots taken of a workspace by its snapshot syncer
+    Snapshots {
+        /// Name or source directory of the workspace
+        workspace: String,
+    },
+    /// Restore a workspace from the last snapshot taken at or before the given time
+    Restore {
+        /// Name or source directory of the workspace
+        workspace: String,
+        /// Seconds since the unix epoch, a UTC date like 2024-05-01T13:45:00Z, or how long ago
+        /// like 30m, 2h or 1d. Defaults to the last snapshot
+        #[clap(long)]
+        at: Option<String>,
+        /// Restore into this directory instead of the workspace. Nothing is deleted from it
+        #[clap(long)]
+        to: Option<PathBuf>,
+        /// Delete the files of the workspace that aren't in the snapshot, unless ignored by the
+        /// workspace. They are kept otherwise
+        #[clap(long, conflicts_with = "to")]
+        delete: bool,
+        /// List the files that would be written and deleted, without changing anything
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Inspect or modify the config file
+    #[clap(subcommand)]
+    Config(ConfigCommand),
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigCommand {
+    /// Print the location of the config file in use
+    Path,
+    /// List the workspaces and syncers in the config file
+    List,
+    /// Add a workspace to the config file. The config file is created if it doesn't exist
+    AddWorkspace {
+        /// Local directory to watch and sync
+        src_dir: String,
+        /// Name to refer to the workspace by. Defaults to the name of the directory
+        #[clap(long)]
+        name: Option<String>,
+        /// Glob of files to ignore in the workspace, or of files to sync anyway when starting with !. Can be repeated
+        #[clap(long)]
+        ignore: Vec<String>,
+    },
+    /// Remove a workspace from the config file
+    RemoveWorkspace {
+        /// Name or source directory of the workspace
+        workspace: String,
+    },
+    /// Add a syncer to a workspace, e.g. `config add-syncer project rsync dst_dir=/remote/dir`
+    AddSyncer {
+        /// Name or source directory of the workspace
+        workspace: String,
+        /// Type of syncer to add
+        #[clap(name = "TYPE")]
+        syncer_type: String,
+        /// Syncer properties as key=value pairs. Values are parsed as YAML
+        properties: Vec<String>,
+    },
+    /// Set a global config value, e.g. `config set-global rsync.default_dst_host my.host.com`
+    SetGlobal {
+        /// Dotted path of the value to set
+        key: String,
+        /// Value to set, parsed as YAML. Use null to remove the value
+        value: String,
+    },
+}
+
+impl Cli {
+    pub fn config_path(&self) -> String {
+        self.config.clone().unwrap_or_else(|| {
+            home::home_dir()
+                .unwrap()
+                .join(DEFAULT_CONFIG_PATH)
+                .into_os_string()
+                .into_string()
+                .unwrap()
+        })
+    }
+
+    pub fn socket_path(&self) -> PathBuf {
+        self.socket
+            .clone()
+            .unwrap_or_else(|| match std::env::var_os("XDG_RUNTIME_DIR") {
+                Some(runtime_dir) => PathBuf::from(runtime_dir).join(DEFAULT_SOCKET_NAME),
+                None => home::home_dir()
+                    .unwrap()
+                    .join(format!(".{}", DEFAULT_SOCKET_NAME)),
+            })
+    }
+
+    // Logging options given on the command line, which take precedence over the config file
+    pub fn log_overrides(&self) -> LogProperties {
+        LogProperties {
+            level: self
+                .log_level
+                .clone()
+                .or_else(|| self.verbose.then(|| String::from("debug"))),
+            format: self.log_format,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cli::{Cli, Command, ConfigCommand};
+    use crate::logging::LogFormat;
+    use clap::Parser;
+    use std::path::PathBuf;
+
+    #[test]
+    fn global_flags_can_follow_subcommand() {
+        let cli = Cli::parse_from([
+            "rust-dev-sync",
+            "sync",
+            "--config",
+            "/tmp/config.yaml",
+            "-w",
+            "project",
+            "--workspace",
+            "/tmp/other",
+            "-v",
+        ]);
+        assert!(matches!(cli.command, Command::Sync));
+        assert_eq!(cli.config_path(), "/tmp/config.yaml");
+        assert_eq!(cli.workspaces, vec!["project", "/tmp/other"]);
+        assert!(cli.verbose);
+        assert_eq!(cli.log_overrides().level.as_deref(), Some("debug"));
+    }
+
+    #[test]
+    fn log_level_takes_precedence_over_verbose() {
+        let cli = Cli::parse_from([
+            "rust-dev-sync",
+            "-v",
+            "--log-level",
+            "trace",
+            "--log-format",
+            "json",
+            "sync",
+        ]);
+        let overrides = cli.log_overrides();
+        assert_eq!(overrides.level.as_deref(), Some("trace"));
+        assert_eq!(overrides.format, Some(LogFormat::Json));
+    }
+
+    #[test]
+    fn pause_targets_workspaces_and_syncer() {
+        let cli = Cli::parse_from([
+            "rust-dev-sync",
+            "pause",
+            "-w",
+            "project",
+            "--syncer",
+            "rsync:/remote/dir",
+        ]);
+        assert_eq!(cli.workspaces, vec!["project"]);
+        match cli.command {
+            Command::Pause { syncer } => assert_eq!(syncer.as_deref(), Some("rsync:/remote/dir")),
+            command => panic!("Unexpected command: {:?}", command),
+        }
+    }
+
+    #[test]
+    fn restore_takes_a_time_and_destination() {
+        let cli = Cli::parse_from([
+            "rust-dev-sync",
+            "restore",
+            "project",
+            "--at",
+            "2h",
+            "--to",
+            "/tmp/restored",
+        ]);
+        match cli.command {
+            Command::Restore {
+                workspace,
+                at,
+                to,
+                delete,
+                dry_run,
+            } => {
+                assert_eq!(workspace, "project");
+                assert_eq!(at.as_deref(), Some("2h"));
+                assert_eq!(to, Some(PathBuf::from("/tmp/restored")));
+                assert!(!delete && !dry_run);
+            }
+            command => panic!("Unexpected command: {:?}", command),
+        }
+        // Nothing is deleted from another directory
+        assert!(Cli::try_parse_from([
+            "rust-dev-sync",
+            "restore",
+            "project",
+            "--to",
+            "/tmp/restored",
+            "--delete",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn config_path_defaults_to_home_config_dir() {
+        let cli = Cli::parse_from(["rust-dev-sync", "config", "path"]);
+        assert!(matches!(cli.command, Command::Config(ConfigCommand::Path)));
+        assert!(cli
+            .config_path()
+            .ends_with("/.config/rust-dev-sync-config.yaml"));
+        assert!(!cli.config_path().contains('~'));
+    }
+
+    #[test]
+    fn config_subcommands_parse() {
+        let cli = Cli::parse_from([
+            "rust-dev-sync",
+            "config",
+            "add-syncer",
+            "project",
+            "rsync",
+            "dst_dir=/remote/dir",
+            "dst_host=host",
+        ]);
+        match cli.command {
+            Command::Config(ConfigCommand::AddSyncer {
+                workspace,
+                syncer_type,
+                properties,
+            }) => {
+                assert_eq!(workspace, "project");
+                assert_eq!(syncer_type, "rsync");
+                assert_eq!(properties, vec!["dst_dir=/remote/dir", "dst_host=host"]);
+            }
+            command => panic!("Unexpected command: {:?}", command),
+        }
+    }
+}
@@ -1,129 +1,545 @@
+use crate::config_writer;
+use crate::debounce::{DebounceProperties, DebounceSettings};
+use crate::error::{self, Error};
+use crate::filter::{Filter, FilterAction, FilterRuleProperties};
+use crate::ignore_files::IgnoreFiles;
+use crate::logging::LogProperties;
+use crate::retry::{RetryPolicy, RetryProperties};
+use crate::suppress::EventSuppressor;
+use crate::syncers::container::ContainerProperties;
+use crate::syncers::local::LocalProperties;
+use crate::syncers::puller::RsyncPullProperties;
 use crate::syncers::rsyncer::{RsyncGlobalProperties, RsyncProperties};
-use crate::Syncer;
-use globset::{Glob, GlobSet, GlobSetBuilder};
-use serde::{Deserialize, Deserializer};
+use crate::syncers::s3::S3Properties;
+use crate::syncers::sftp::SftpProperties;
+use crate::syncers::snapshot::store::SnapshotStore;
+use crate::syncers::snapshot::SnapshotProperties;
+use crate::syncers::ssh_tar::TarProperties;
+use crate::syncers::Syncer;
+use serde::{Deserialize, Serialize};
+use serde_yaml::{Mapping, Value};
 use std::fmt::Debug;
-use std::io::{Error as IOError, ErrorKind};
+use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::sync::Arc;
+use std::time::Duration;
+use tracing::warn;
 
-fn canonicalize<'de, D>(deserializer: D) -> Result<String, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let local_dir = String::deserialize(deserializer)?;
-    Path::new(&local_dir)
+const DEFAULT_SHUTDOWN_TIMEOUT_MS: u64 = 30000;
+
+fn canonical_dir(local_dir: &str) -> Result<String, &'static str> {
+    Path::new(local_dir)
         .canonicalize()
-        .or_else(|_e| {
-            Err(serde::de::Error::invalid_value(
-                serde::de::Unexpected::Str(&local_dir),
-                &"path must exist",
-            ))
-        })?
+        .map_err(|_e| "path must exist")?
         .into_os_string()
         .into_string()
         .map(|mut path| {
-            if !path.ends_with("/") {
+            if !path.ends_with('/') {
                 path.push('/');
             }
             path
         })
-        .or_else(|_e| {
-            Err(serde::de::Error::invalid_value(
-                serde::de::Unexpected::Str(&local_dir),
-                &"Invalid path",
-            ))
-        })
+        .map_err(|_e| "Invalid path")
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(tag = "type")]
 enum WorkspaceSyncer {
-    #[serde(rename(deserialize = "rsync"))]
+    #[serde(rename = "rsync")]
     Rsync(RsyncProperties),
+    #[serde(rename = "rsync_pull")]
+    RsyncPull(RsyncPullProperties),
+    #[serde(rename = "local")]
+    Local(LocalProperties),
+    #[serde(rename = "s3")]
+    S3(S3Properties),
+    #[serde(rename = "snapshot")]
+    Snapshot(SnapshotProperties),
+    #[serde(rename = "ssh_tar")]
+    SshTar(TarProperties),
+    #[serde(rename = "sftp")]
+    Sftp(SftpProperties),
+    #[serde(rename = "container")]
+    Container(ContainerProperties),
+}
+
+// Options that apply to every type of syncer live next to the syncer's own properties
+#[derive(Debug, Deserialize, Serialize)]
+struct SyncerConfig {
+    #[serde(flatten)]
+    syncer: WorkspaceSyncer,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry: Option<RetryProperties>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 struct WorkspaceConfig {
-    #[serde(deserialize_with = "canonicalize")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    // Kept as written, so that saving the config leaves it alone. See path()
     src_dir: String,
-    syncers: Vec<WorkspaceSyncer>,
+    syncers: Vec<SyncerConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     ignore: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    filter: Option<Vec<FilterRuleProperties>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    debounce: Option<DebounceProperties>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    initial_sync: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    respect_ignore_files: Option<bool>,
+}
+
+impl WorkspaceConfig {
+    // The canonical source directory, with symlinks resolved and a trailing /
+    fn path(&self) -> error::Result<String> {
+        canonical_dir(&self.src_dir).map_err(|e| Error::Config(format!("{}: {}", self.src_dir, e)))
+    }
+
+    fn name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| {
+            let path = self.path().unwrap_or_else(|_| self.src_dir.clone());
+            Path::new(&path)
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or(path)
+        })
+    }
+
+    fn is_named(&self, name_or_path: &str) -> bool {
+        self.name() == name_or_path
+            || Path::new(&self.src_dir) == Path::new(name_or_path)
+            || self
+                .path()
+                .is_ok_and(|path| canonical_dir(name_or_path) == Ok(path))
+    }
 }
 
 impl WorkspaceSyncer {
-    fn as_syncer(&self, global_config: &GlobalConfig) -> Box<dyn Syncer> {
+    fn as_syncer(
+        &self,
+        global_config: &GlobalConfig,
+        filter: &Arc<Filter>,
+        suppressor: &Arc<EventSuppressor>,
+    ) -> error::Result<Box<dyn Syncer>> {
         match self {
-            WorkspaceSyncer::Rsync(props) => {
-                Box::new(props.as_syncer(global_config.rsync.as_ref()))
+            WorkspaceSyncer::Rsync(props) => Ok(Box::new(
+                props
+                    .as_syncer(global_config.rsync.as_ref())?
+                    .with_filter(filter.clone()),
+            )),
+            WorkspaceSyncer::RsyncPull(props) => Ok(Box::new(props.as_syncer(
+                global_config.rsync.as_ref(),
+                filter.clone(),
+                suppressor.clone(),
+            )?)),
+            WorkspaceSyncer::Local(props) => {
+                Ok(Box::new(props.as_syncer().with_filter(filter.clone())))
+            }
+            WorkspaceSyncer::S3(props) => {
+                Ok(Box::new(props.as_syncer()?.with_filter(filter.clone())))
+            }
+            WorkspaceSyncer::Snapshot(props) => {
+                Ok(Box::new(props.as_syncer().with_filter(filter.clone())))
+            }
+            WorkspaceSyncer::SshTar(props) => Ok(Box::new(
+                props
+                    .as_syncer(global_config.rsync.as_ref())?
+                    .with_filter(filter.clone()),
+            )),
+            WorkspaceSyncer::Sftp(props) => Ok(Box::new(
+                props
+                    .as_syncer(global_config.rsync.as_ref())?
+                    .with_filter(filter.clone()),
+            )),
+            WorkspaceSyncer::Container(props) => {
+                Ok(Box::new(props.as_syncer()?.with_filter(filter.clone())))
             }
         }
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize, Serialize)]
 pub struct Config {
+    #[serde(default)]
     global_config: GlobalConfig,
     workspaces: Vec<WorkspaceConfig>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize, Serialize)]
 struct GlobalConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
     ignore: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    filter: Option<Vec<FilterRuleProperties>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     rsync: Option<RsyncGlobalProperties>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    debounce: Option<DebounceProperties>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    initial_sync: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry: Option<RetryProperties>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    log: Option<LogProperties>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shutdown_timeout_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    respect_ignore_files: Option<bool>,
+}
+
+// A syncer along with the settings it should be run with
+pub struct ConfiguredSyncer {
+    // Not quite sure if Pin is necessary, but it just feels right
+    pub syncer: Arc<Pin<Box<dyn Syncer>>>,
+    pub retry: RetryPolicy,
 }
 
 pub struct Workspace {
+    pub name: String,
     pub path: String,
-    // Not quite sure if Pin is necessary, but it just feels right
-    pub syncers: Vec<Arc<Pin<Box<dyn Syncer>>>>,
-    ignore: GlobSet,
+    pub syncers: Vec<ConfiguredSyncer>,
+    pub debounce: DebounceSettings,
+    // Whether the whole workspace should be synced when the daemon starts
+    pub initial_sync: bool,
+    // Shared with the syncers, which apply the same rules when transferring files
+    pub filter: Arc<Filter>,
+    // Shared with the syncers writing into the workspace, so that what they write isn't synced
+    pub suppressor: Arc<EventSuppressor>,
+    // The settings the workspace was built from, to tell whether it changed when reloading
+    definition: Value,
 }
 
 impl Workspace {
     pub fn should_sync(&self, path: &Path) -> bool {
-        !self.ignore.is_match(path)
+        self.filter.should_sync(path)
+    }
+
+    // Workspaces can be referred to either by name or by their source directory
+    pub fn is_named(&self, name_or_path: &str) -> bool {
+        self.name == name_or_path || Path::new(&self.path) == Path::new(name_or_path)
+    }
+
+    pub fn same_config(&self, other: &Workspace) -> bool {
+        self.path == other.path && self.definition == other.definition
     }
 }
 
+// Patterns starting with ! are exceptions, for files to sync even though an earlier pattern
+// ignores them
+fn ignore_rules<'a>(
+    ignore: impl IntoIterator<Item = &'a String>,
+) -> impl Iterator<Item = (FilterAction, &'a str)> {
+    ignore
+        .into_iter()
+        .map(|pattern| match pattern.strip_prefix('!') {
+            Some(pattern) => (FilterAction::Include, pattern),
+            None => (FilterAction::Exclude, pattern.as_str()),
+        })
+}
+
+fn parse_value(value: &str) -> error::Result<Value> {
+    serde_yaml::from_str(value)
+        .map_err(|e| Error::Config(format!("Invalid value '{}': {}", value, e)))
+}
+
+fn to_value<T: Serialize>(value: &T) -> error::Result<Value> {
+    serde_yaml::to_value(value).map_err(|e| Error::Config(format!("Invalid config: {}", e)))
+}
+
 impl Config {
-    pub async fn parse(path: &str) -> std::io::Result<Config> {
-        let path = PathBuf::from(path);
-        let data = tokio::fs::read(path).await?;
-        serde_yaml::from_slice(&data).or_else(|e| Err(IOError::new(ErrorKind::InvalidData, e)))
+    pub async fn parse(path: &str) -> error::Result<Config> {
+        let data = tokio::fs::read(PathBuf::from(path))
+            .await
+            .map_err(Error::io(format!("failed to read config file {}", path)))?;
+        serde_yaml::from_slice(&data).map_err(|source| Error::ConfigFile {
+            path: path.to_string(),
+            source,
+        })
+    }
+
+    // Writes the config back to disk, keeping the ordering and comments of the existing file
+    // where possible. The file is replaced atomically so a failed write can't corrupt it
+    pub async fn save(&self, path: &str) -> error::Result {
+        let original = match tokio::fs::read_to_string(path).await {
+            Ok(original) => Some(original),
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => return Err(Error::io(format!("failed to read config file {}", path))(e)),
+        };
+        let data = config_writer::to_yaml(original.as_deref(), self).map_err(|source| {
+            Error::ConfigFile {
+                path: path.to_string(),
+                source,
+            }
+        })?;
+        let tmp_path = format!("{}.tmp", path);
+        tokio::fs::write(&tmp_path, data)
+            .await
+            .map_err(Error::io(format!(
+                "failed to write config file {}",
+                tmp_path
+            )))?;
+        tokio::fs::rename(&tmp_path, path)
+            .await
+            .map_err(Error::io(format!("failed to replace config file {}", path)))
+    }
+
+    pub fn add_workspace(
+        &mut self,
+        src_dir: &str,
+        name: Option<&str>,
+        ignore: &[String],
+    ) -> error::Result {
+        let src_dir =
+            canonical_dir(src_dir).map_err(|e| Error::Config(format!("{}: {}", src_dir, e)))?;
+        let workspace = WorkspaceConfig {
+            name: name.map(String::from),
+            src_dir,
+            syncers: vec![],
+            ignore: if ignore.is_empty() {
+                None
+            } else {
+                Some(ignore.to_vec())
+            },
+            debounce: None,
+            initial_sync: None,
+            respect_ignore_files: None,
+            filter: None,
+        };
+        if let Some(existing) = self.workspaces.iter().find(|ws| {
+            ws.path().is_ok_and(|path| path == workspace.src_dir) || ws.name() == workspace.name()
+        }) {
+            return Err(Error::Config(format!(
+                "Workspace already exists: {}",
+                existing.name()
+            )));
+        }
+        self.workspaces.push(workspace);
+        Ok(())
+    }
+
+    pub fn remove_workspace(&mut self, name_or_path: &str) -> error::Result {
+        let count = self.workspaces.len();
+        self.workspaces.retain(|ws| !ws.is_named(name_or_path));
+        if self.workspaces.len() == count {
+            return Err(Error::Config(format!(
+                "Unknown workspace: {}",
+                name_or_path
+            )));
+        }
+        Ok(())
+    }
+
+    // Properties are given as key=value pairs where the value is parsed as YAML, so that
+    // lists like additional_flags=[Recursive, Compress] can be set too
+    pub fn add_syncer(
+        &mut self,
+        workspace: &str,
+        syncer_type: &str,
+        properties: &[String],
+    ) -> error::Result {
+        let mut mapping = Mapping::new();
+        mapping.insert(Value::from("type"), Value::from(syncer_type));
+        for property in properties {
+            let (key, value) = property.split_once('=').ok_or_else(|| {
+                Error::Config(format!("Expected key=value but got: {}", property))
+            })?;
+            mapping.insert(Value::from(key), parse_value(value)?);
+        }
+        let syncer: SyncerConfig = serde_yaml::from_value(Value::Mapping(mapping))
+            .map_err(|e| Error::Config(format!("Invalid {} syncer: {}", syncer_type, e)))?;
+        let ws_config = self
+            .workspaces
+            .iter_mut()
+            .find(|ws| ws.is_named(workspace))
+            .ok_or_else(|| Error::Config(format!("Unknown workspace: {}", workspace)))?;
+        ws_config.syncers.push(syncer);
+        Ok(())
+    }
+
+    // Sets a global config value using a dotted key, e.g. rsync.default_dst_host. Setting a
+    // value to null removes it
+    pub fn set_global(&mut self, key: &str, value: &str) -> error::Result {
+        let mut global =
+            serde_yaml::to_value(&self.global_config).map_err(|e| Error::Config(e.to_string()))?;
+        let mut current = &mut global;
+        for part in key.split('.') {
+            if !current.is_mapping() {
+                *current = Value::Mapping(Mapping::new());
+            }
+            let mapping = current.as_mapping_mut().unwrap();
+            let part = Value::from(part);
+            if !mapping.contains_key(&part) {
+                mapping.insert(part.clone(), Value::Null);
+            }
+            current = mapping.get_mut(&part).unwrap();
+        }
+        *current = parse_value(value)?;
+        self.global_config = serde_yaml::from_value(global)
+            .map_err(|e| Error::Config(format!("Invalid value for {}: {}", key, e)))?;
+        Ok(())
+    }
+
+    // How long the daemon waits for running syncs to finish when shutting down
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_millis(
+            self.global_config
+                .shutdown_timeout_ms
+                .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT_MS),
+        )
+    }
+
+    pub fn log(&self) -> LogProperties {
+        self.global_config.log.clone().unwrap_or_default()
+    }
+
+    // Short description of each workspace and its syncers, straight from the config
+    pub fn describe(&self) -> error::Result<String> {
+        Ok(self
+            .workspaces()?
+            .iter()
+            .map(|workspace| {
+                let syncers = workspace
+                    .syncers
+                    .iter()
+                    .map(|configured| format!("\n  - {}", configured.syncer.name()))
+                    .collect::<String>();
+                format!("{} ({}){}", workspace.name, workspace.path, syncers)
+            })
+            .collect::<Vec<String>>()
+            .join("\n"))
+    }
+
+    // The workspaces referred to by the given names or source directories, or all of them if
+    // none are given
+    pub fn select_workspaces(&self, selected: &[String]) -> error::Result<Vec<Workspace>> {
+        let workspaces = self.workspaces()?;
+        if let Some(unknown) = selected
+            .iter()
+            .find(|name| !workspaces.iter().any(|ws| ws.is_named(name)))
+        {
+            return Err(Error::Config(format!("Unknown workspace: {}", unknown)));
+        }
+        Ok(workspaces
+            .into_iter()
+            .filter(|ws| selected.is_empty() || selected.iter().any(|name| ws.is_named(name)))
+            .collect())
+    }
+
+    // Where the first snapshot syncer of the workspace keeps its snapshots
+    pub fn snapshot_store(&self, workspace: &str) -> error::Result<Box<dyn SnapshotStore>> {
+        let ws_config = self
+            .workspaces
+            .iter()
+            .find(|ws| ws.is_named(workspace))
+            .ok_or_else(|| Error::Config(format!("Unknown workspace: {}", workspace)))?;
+        ws_config
+            .syncers
+            .iter()
+            .find_map(|syncer_config| match &syncer_config.syncer {
+                WorkspaceSyncer::Snapshot(props) => Some(props.as_store()),
+                _ => None,
+            })
+            .ok_or_else(|| {
+                Error::Config(format!(
+                    "Workspace {} has no snapshot syncer",
+                    ws_config.name()
+                ))
+            })
     }
 
-    pub fn workspaces(&self) -> Vec<Workspace> {
+    pub fn workspaces(&self) -> error::Result<Vec<Workspace>> {
+        // Logging is set up once for the whole process, so it plays no part in a workspace's
+        // definition
+        let mut global_definition = to_value(&self.global_config)?;
+        if let Value::Mapping(global) = &mut global_definition {
+            global.remove(&Value::from("log"));
+        }
+        let global_rsync_excludes = self
+            .global_config
+            .rsync
+            .as_ref()
+            .map(RsyncGlobalProperties::excludes)
+            .unwrap_or_default();
+        if !global_rsync_excludes.is_empty() {
+            warn!("The global rsync excludes are deprecated and apply to every syncer, use ignore instead");
+        }
         self.workspaces
             .iter()
             .map(|ws_config| {
-                let ws_path = Path::new(&ws_config.src_dir);
+                let path = ws_config.path()?;
+                let ws_path = Path::new(&path);
+                let ignore_files = ws_config
+                    .respect_ignore_files
+                    .or(self.global_config.respect_ignore_files)
+                    .unwrap_or(false)
+                    .then(|| IgnoreFiles::load(ws_path));
+                // Workspace rules take precedence over global ones, and filter rules over ignore
+                // lists. The deprecated global rsync excludes come first, as an ignore list of its own
+                let rules = ignore_rules(global_rsync_excludes)
+                    .chain(ignore_rules(self.global_config.ignore.iter().flatten()))
+                    .chain(
+                        self.global_config
+                            .filter
+                            .iter()
+                            .flatten()
+                            .map(FilterRuleProperties::as_rule),
+                    )
+                    .chain(ignore_rules(ws_config.ignore.iter().flatten()))
+                    .chain(
+                        ws_config
+                            .filter
+                            .iter()
+                            .flatten()
+                            .map(FilterRuleProperties::as_rule),
+                    );
+                let filter = Arc::new(Filter::new(ws_path, rules, ignore_files)?);
+                let suppressor = Arc::new(EventSuppressor::new());
                 let syncers = ws_config
                     .syncers
                     .iter()
-                    .map(|properties| Arc::new(properties.as_syncer(&self.global_config).into()))
-                    .collect();
-                let mut builder = GlobSetBuilder::new();
-                let mut add = |ignore: &Vec<String>| {
-                    ignore.iter().for_each(|pattern| {
-                        let path = Path::new(pattern);
-                        if path.is_absolute() {
-                            builder.add(Glob::new(pattern).unwrap());
-                        } else {
-                            let path = ws_path.join(path).into_os_string().into_string().unwrap();
-                            builder.add(Glob::new(&path).unwrap());
-                        }
+                    .map(|syncer_config| {
+                        let retry = self
+                            .global_config
+                            .retry
+                            .clone()
+                            .unwrap_or_default()
+                            .merge(syncer_config.retry.as_ref())
+                            .as_policy();
+                        syncer_config
+                            .syncer
+                            .as_syncer(&self.global_config, &filter, &suppressor)
+                            .map(|syncer| ConfiguredSyncer {
+                                syncer: Arc::new(syncer.into()),
+                                retry,
+                            })
                     })
-                };
-                self.global_config.ignore.as_ref().map(|i| add(i));
-                ws_config.ignore.as_ref().map(|i| add(i));
-                let ignores = builder.build().unwrap();
-                Workspace {
-                    path: ws_config.src_dir.clone(),
+                    .collect::<error::Result<Vec<_>>>()?;
+                let debounce = self
+                    .global_config
+                    .debounce
+                    .clone()
+                    .unwrap_or_default()
+                    .merge(ws_config.debounce.as_ref())
+                    .as_settings();
+                let initial_sync = ws_config
+                    .initial_sync
+                    .or(self.global_config.initial_sync)
+                    .unwrap_or(true);
+                let definition =
+                    Value::Sequence(vec![global_definition.clone(), to_value(ws_config)?]);
+                Ok(Workspace {
+                    name: ws_config.name(),
+                    path: path.clone(),
                     syncers,
-                    ignore: ignores,
-                }
+                    debounce,
+                    initial_sync,
+                    filter,
+                    suppressor,
+                    definition,
+                })
             })
             .collect()
     }
@@ -131,8 +547,11 @@ impl Config {
 
 #[cfg(test)]
 mod tests {
-    use crate::config::{Config, GlobalConfig, WorkspaceConfig};
+    use crate::config::{Config, GlobalConfig, Workspace, WorkspaceConfig};
+    use crate::debounce::DebounceSettings;
+    use crate::rsync::cli::RsyncOption;
     use std::path::Path;
+    use std::time::Duration;
 
     #[tokio::test]
     async fn it_works() {
@@ -140,29 +559,329 @@ mod tests {
         assert_eq!(config.workspaces.len(), 2);
     }
 
+    #[tokio::test]
+    async fn config_can_be_edited() {
+        let mut config = Config::parse("examples/schema.config.yaml").await.unwrap();
+        assert!(config.add_workspace("/tmp/localdir4", None, &[]).is_err());
+        assert!(config.add_workspace("/does/not/exist", None, &[]).is_err());
+        config.remove_workspace("localdir4").unwrap();
+        assert!(config.remove_workspace("localdir4").is_err());
+        config
+            .add_workspace("/tmp/localdir4/", Some("other"), &["target".to_string()])
+            .unwrap();
+        config
+            .add_syncer(
+                "other",
+                "rsync",
+                &[
+                    "dst_dir=/remote/other".to_string(),
+                    "additional_flags=[Recursive, Compress]".to_string(),
+                ],
+            )
+            .unwrap();
+        assert!(config
+            .add_syncer("other", "rsync", &["dst_host=no-dst-dir".to_string()])
+            .is_err());
+        config
+            .set_global("rsync.default_dst_host", "other.host.com")
+            .unwrap();
+        config
+            .set_global("debounce.quiet_period_ms", "100")
+            .unwrap();
+        assert!(config
+            .set_global("debounce.quiet_period_ms", "soon")
+            .is_err());
+
+        let workspaces = config.workspaces().unwrap();
+        let other = workspaces.iter().find(|ws| ws.name == "other").unwrap();
+        assert_eq!(other.path, "/tmp/localdir4/");
+        assert!(!other.should_sync(Path::new("/tmp/localdir4/target")));
+        assert_eq!(
+            other.syncers.first().unwrap().syncer.name(),
+            "rsync:other.host.com:/remote/other"
+        );
+        assert_eq!(other.debounce.quiet_period, Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn config_save_round_trips() {
+        let mut config = Config::parse("examples/schema.config.yaml").await.unwrap();
+        config
+            .set_global("debounce.quiet_period_ms", "100")
+            .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.yaml");
+        let path = path.to_str().unwrap();
+        tokio::fs::copy("examples/schema.config.yaml", path)
+            .await
+            .unwrap();
+        config.save(path).await.unwrap();
+        let saved = tokio::fs::read_to_string(path).await.unwrap();
+        let reparsed = Config::parse(path).await.unwrap();
+        assert!(saved.contains("# List of workspaces to apply syncers to\nworkspaces:\n"));
+        assert!(saved.contains("    quiet_period_ms: 100\n"));
+        assert_eq!(reparsed.workspaces.len(), 2);
+        assert_eq!(
+            reparsed.workspaces().unwrap()[0].debounce.quiet_period,
+            Duration::from_millis(500)
+        );
+    }
+
+    #[tokio::test]
+    async fn src_dirs_are_saved_as_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        std::fs::create_dir(root.join("sub")).unwrap();
+        let src_dir = format!("{}/sub/../", root.display());
+        let mut config = Config::parse("examples/schema.config.yaml").await.unwrap();
+        config.workspaces[0].src_dir = src_dir.clone();
+        let path = root.join("config.yaml");
+        let path = path.to_str().unwrap();
+        config.save(path).await.unwrap();
+        let reparsed = Config::parse(path).await.unwrap();
+        assert_eq!(reparsed.workspaces[0].src_dir, src_dir);
+        assert_eq!(
+            reparsed.workspaces().unwrap()[0].path,
+            format!("{}/", root.display())
+        );
+    }
+
     #[test]
     fn workspaces_should_sync() {
+        let workspace = tempfile::tempdir().unwrap();
+        let root = workspace.path().canonicalize().unwrap();
         let config = Config {
             global_config: GlobalConfig {
                 ignore: None,
                 rsync: None,
+                debounce: None,
+                initial_sync: None,
+                respect_ignore_files: None,
+                filter: None,
+                retry: None,
+                log: None,
+                shutdown_timeout_ms: None,
             },
             workspaces: vec![WorkspaceConfig {
-                src_dir: "/local/dir1".to_string(),
+                name: None,
+                src_dir: root.to_str().unwrap().to_string(),
                 syncers: vec![],
                 ignore: Some(vec![
                     "*/ignore-1/*".to_string(),
                     "ignore-2/*".to_string(),
+                    "!ignore-2/keep".to_string(),
                     "ignore-3".to_string(),
                 ]),
+                debounce: None,
+                initial_sync: None,
+                respect_ignore_files: None,
+                filter: None,
             }],
         };
-        let workspaces = config.workspaces();
-        let only_workspace = workspaces.get(0).unwrap();
-        assert!(only_workspace.should_sync(Path::new("/local/dir1/random-file")));
-        assert!(!only_workspace.should_sync(Path::new("/local/dir1/subdir1/ignore-1/file1")));
-        assert!(!only_workspace.should_sync(Path::new("/local/dir1/subdir2/ignore-1/file2")));
-        assert!(!only_workspace.should_sync(Path::new("/local/dir1/ignore-2/file2")));
-        assert!(!only_workspace.should_sync(Path::new("/local/dir1/ignore-3")));
+        let workspaces = config.workspaces().unwrap();
+        let only_workspace = workspaces.first().unwrap();
+        assert!(only_workspace.should_sync(&root.join("random-file")));
+        assert!(!only_workspace.should_sync(&root.join("subdir1/ignore-1/file1")));
+        assert!(!only_workspace.should_sync(&root.join("subdir2/ignore-1/file2")));
+        assert!(!only_workspace.should_sync(&root.join("ignore-2/file2")));
+        assert!(!only_workspace.should_sync(&root.join("ignore-3")));
+        assert!(only_workspace.should_sync(&root.join("ignore-2/keep")));
+    }
+
+    #[test]
+    fn global_rsync_excludes_are_folded_into_the_filter() {
+        let workspace = tempfile::tempdir().unwrap();
+        let root = workspace.path().canonicalize().unwrap();
+        let config: Config = serde_yaml::from_str(&format!(
+            "
+global_config:
+  rsync:
+    excludes: ['build/*']
+workspaces:
+  - src_dir: {}
+    syncers:
+      - type: rsync
+        dst_dir: /remote/dir
+        additional_excludes: ['*.log']
+      - type: local
+        dst_dir: /elsewhere
+    ignore: ['!build/config.json']
+",
+            root.display()
+        ))
+        .unwrap();
+        let workspaces = config.workspaces().unwrap();
+        let only_workspace = workspaces.first().unwrap();
+        assert!(!only_workspace.should_sync(&root.join("build/out")));
+        assert!(only_workspace.should_sync(&root.join("build/config.json")));
+        // Only the rsync syncer leaves out what its own excludes do
+        assert!(only_workspace.should_sync(&root.join("app.log")));
+        assert_eq!(
+            only_workspace.filter.rsync_options(),
+            vec![
+                RsyncOption::Include("/build/config.json".to_string()),
+                RsyncOption::Exclude("/build/**".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn workspaces_initial_sync_overrides_global() {
+        let config = Config::parse("examples/schema.config.yaml").await.unwrap();
+        let workspaces = config.workspaces().unwrap();
+        let overridden = workspaces.iter().find(|ws| ws.name == "project").unwrap();
+        assert!(overridden.initial_sync);
+        let inherited = workspaces.iter().find(|ws| ws.name == "localdir4").unwrap();
+        assert!(!inherited.initial_sync);
+    }
+
+    #[tokio::test]
+    async fn workspaces_filter_overrides_global() {
+        let config = Config::parse("examples/schema.config.yaml").await.unwrap();
+        let workspaces = config.workspaces().unwrap();
+        let overridden = workspaces.iter().find(|ws| ws.name == "project").unwrap();
+        assert!(!overridden.should_sync(Path::new("/tmp/localdir3/file3")));
+        assert!(!overridden.should_sync(Path::new("/tmp/localdir3/notes.swp")));
+        assert!(overridden.should_sync(Path::new("/tmp/localdir3/keep.swp")));
+        assert!(!overridden.should_sync(Path::new("/tmp/localdir3/build/app")));
+        assert!(overridden.should_sync(Path::new("/tmp/localdir3/build/config.json")));
+        let inherited = workspaces.iter().find(|ws| ws.name == "localdir4").unwrap();
+        assert!(!inherited.should_sync(Path::new("/tmp/localdir4/keep.swp")));
+    }
+
+    #[tokio::test]
+    async fn syncers_retry_overrides_global() {
+        let config = Config::parse("examples/schema.config.yaml").await.unwrap();
+        let workspaces = config.workspaces().unwrap();
+        let overridden = workspaces.iter().find(|ws| ws.name == "project").unwrap();
+        let retry = overridden.syncers.first().unwrap().retry;
+        assert_eq!(retry.max_attempts, 10);
+        assert_eq!(retry.base_delay, Duration::from_millis(1000));
+        let inherited = workspaces.iter().find(|ws| ws.name == "localdir4").unwrap();
+        assert_eq!(inherited.syncers.first().unwrap().retry.max_attempts, 5);
+    }
+
+    #[tokio::test]
+    async fn pull_syncers_poll_instead_of_syncing_changes() {
+        let mut config = Config::parse("examples/schema.config.yaml").await.unwrap();
+        let workspaces = config.workspaces().unwrap();
+        let project = workspaces.iter().find(|ws| ws.name == "project").unwrap();
+        let pull = &project.syncers[1].syncer;
+        assert_eq!(
+            pull.name(),
+            "rsync-pull:sanjams.host.com:/tmp/remotedir4-outputs"
+        );
+        assert!(!pull.syncs_changes());
+        assert_eq!(pull.poll_interval(), Some(Duration::from_millis(10000)));
+        assert!(project.syncers[0].syncer.syncs_changes());
+        assert_eq!(project.syncers[0].syncer.poll_interval(), None);
+
+        config
+            .add_syncer(
+                "localdir4",
+                "rsync_pull",
+                &[
+                    "dst_dir=/remote/other".to_string(),
+                    "bidirectional={}".to_string(),
+                ],
+            )
+            .unwrap();
+        assert!(config.workspaces().is_err());
+    }
+
+    #[tokio::test]
+    async fn snapshot_stores_are_found_by_workspace() {
+        let mut config = Config::parse("examples/schema.config.yaml").await.unwrap();
+        let store = config.snapshot_store("project").unwrap();
+        assert_eq!(store.name(), "local:/tmp/rust-dev-sync-snapshots/project");
+        assert!(config.snapshot_store("localdir4").is_err());
+
+        config
+            .add_syncer(
+                "localdir4",
+                "snapshot",
+                &["store={type: s3, bucket: dev-snapshots, prefix: localdir4/}".to_string()],
+            )
+            .unwrap();
+        let workspaces = config.workspaces().unwrap();
+        let localdir4 = workspaces.iter().find(|ws| ws.name == "localdir4").unwrap();
+        assert_eq!(
+            localdir4.syncers.last().unwrap().syncer.name(),
+            "snapshot:s3://dev-snapshots/localdir4"
+        );
+    }
+
+    #[tokio::test]
+    async fn workspaces_tell_whether_their_config_changed() {
+        let mut config = Config::parse("examples/schema.config.yaml").await.unwrap();
+        let before = config.workspaces().unwrap();
+        config.set_global("log.level", "debug").unwrap();
+        let after = config.workspaces().unwrap();
+        assert!(before[0].same_config(&after[0]));
+        assert!(before[1].same_config(&after[1]));
+        assert!(!before[0].same_config(&after[1]));
+
+        config
+            .add_syncer("localdir4", "rsync", &["dst_dir=/remote/other".to_string()])
+            .unwrap();
+        let after = config.workspaces().unwrap();
+        let changed = |ws: &Workspace| ws.name == "localdir4";
+        assert!(!before
+            .iter()
+            .find(|ws| changed(ws))
+            .unwrap()
+            .same_config(after.iter().find(|ws| changed(ws)).unwrap()));
+        assert!(before
+            .iter()
+            .find(|ws| !changed(ws))
+            .unwrap()
+            .same_config(after.iter().find(|ws| !changed(ws)).unwrap()));
+
+        config.set_global("debounce.quiet_period_ms", "10").unwrap();
+        let after = config.workspaces().unwrap();
+        assert!(!before[0].same_config(&after[0]));
+    }
+
+    #[tokio::test]
+    async fn workspaces_are_named() {
+        let config = Config::parse("examples/schema.config.yaml").await.unwrap();
+        let workspaces = config.workspaces().unwrap();
+        let named = workspaces.iter().find(|ws| ws.name == "project").unwrap();
+        assert!(named.is_named("project"));
+        assert!(named.is_named("/tmp/localdir3"));
+        assert!(!named.is_named("localdir3"));
+        let unnamed = workspaces
+            .iter()
+            .find(|ws| ws.path == "/tmp/localdir4/")
+            .unwrap();
+        assert_eq!(unnamed.name, "localdir4");
+    }
+
+    #[tokio::test]
+    async fn workspaces_debounce_overrides_global() {
+        let config = Config::parse("examples/schema.config.yaml").await.unwrap();
+        let workspaces = config.workspaces().unwrap();
+        let overridden = workspaces
+            .iter()
+            .find(|ws| ws.path == "/tmp/localdir3/")
+            .unwrap();
+        assert_eq!(
+            overridden.debounce,
+            DebounceSettings {
+                quiet_period: Duration::from_millis(500),
+                max_latency: Duration::from_millis(5000),
+            }
+        );
+        let inherited = workspaces
+            .iter()
+            .find(|ws| ws.path == "/tmp/localdir4/")
+            .unwrap();
+        assert_eq!(
+            inherited.debounce,
+            DebounceSettings {
+                quiet_period: Duration::from_millis(200),
+                max_latency: Duration::from_millis(5000),
+            }
+        );
     }
 }
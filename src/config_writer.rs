@@ -0,0 +1,258 @@
+use std::collections::{HashMap, VecDeque};
+
+use serde::Serialize;
+use serde_yaml::{Mapping, Value};
+
+// Renders a config as YAML while keeping as much of the original file as possible: keys are
+// emitted in the order they were originally written in, and comment lines are re-attached to the
+// line they used to precede. Inline comments are kept too, as long as the line they were on
+// still exists
+pub fn to_yaml<T: Serialize>(
+    original: Option<&str>,
+    config: &T,
+) -> Result<String, serde_yaml::Error> {
+    let mut value = serde_yaml::to_value(config)?;
+    let original = match original {
+        Some(original) => original,
+        None => return serde_yaml::to_string(&value),
+    };
+    if let Ok(template) = serde_yaml::from_str::<Value>(original) {
+        reorder_like(&mut value, &template);
+    }
+    let updated = serde_yaml::to_string(&value)?;
+    Ok(restore_comments(original, &updated))
+}
+
+fn reorder_like(value: &mut Value, template: &Value) {
+    match (value, template) {
+        (Value::Mapping(mapping), Value::Mapping(template)) => {
+            let mut reordered = Mapping::new();
+            for (key, template_value) in template.iter() {
+                if let Some(mut v) = mapping.remove(key) {
+                    reorder_like(&mut v, template_value);
+                    reordered.insert(key.clone(), v);
+                }
+            }
+            for (key, v) in mapping.iter() {
+                reordered.insert(key.clone(), v.clone());
+            }
+            *mapping = reordered;
+        }
+        (Value::Sequence(items), Value::Sequence(template)) => {
+            // Items are matched up by position. New items use the first item as their template
+            // since items in the same list tend to be written the same way
+            for (i, item) in items.iter_mut().enumerate() {
+                if let Some(item_template) = template.get(i).or_else(|| template.first()) {
+                    reorder_like(item, item_template);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+#[derive(Default)]
+struct Annotations {
+    comments: Vec<String>,
+    inline_comment: Option<String>,
+}
+
+struct Line {
+    indent: usize,
+    is_item: bool,
+    key: String,
+}
+
+// Tracks the chain of parent lines for each line of a YAML document, purely based on indentation
+#[derive(Default)]
+struct Ancestry {
+    stack: Vec<Line>,
+}
+
+impl Ancestry {
+    fn push(&mut self, content: &str) -> String {
+        let indent = content.len() - content.trim_start().len();
+        let is_item = content.trim_start().starts_with('-');
+        while let Some(top) = self.stack.last() {
+            // List items are allowed to be written at the same indentation as their parent key
+            let is_parent =
+                top.indent < indent || (top.indent == indent && is_item && !top.is_item);
+            if is_parent {
+                break;
+            }
+            self.stack.pop();
+        }
+        self.stack.push(Line {
+            indent,
+            is_item,
+            key: normalize(content),
+        });
+        self.stack
+            .iter()
+            .map(|line| line.key.as_str())
+            .collect::<Vec<&str>>()
+            .join("\n")
+    }
+}
+
+// Makes lines comparable between the original and re-rendered documents. Mapping entries are
+// identified by their key alone so that comments survive a value being changed. List items are
+// identified by their whole content, ignoring differences in quoting and in trailing slashes of
+// paths
+fn normalize(content: &str) -> String {
+    let content = content.trim();
+    let content = match content.split_once(':') {
+        Some((key, _value)) if !content.starts_with('-') => key,
+        _ => content,
+    };
+    content
+        .chars()
+        .filter(|c| *c != '"' && *c != '\'')
+        .collect::<String>()
+        .trim_end_matches('/')
+        .to_string()
+}
+
+fn split_inline_comment(line: &str) -> (&str, Option<&str>) {
+    let mut in_single = false;
+    let mut in_double = false;
+    let bytes = line.as_bytes();
+    for (i, c) in line.char_indices() {
+        match c {
+            '\'' if !in_double => in_single = !in_single,
+            '"' if !in_single => in_double = !in_double,
+            '#' if !in_single && !in_double && i > 0 && bytes[i - 1] == b' ' => {
+                return (line[..i].trim_end(), Some(&line[i..]));
+            }
+            _ => {}
+        }
+    }
+    (line.trim_end(), None)
+}
+
+fn restore_comments(original: &str, updated: &str) -> String {
+    let mut annotations: HashMap<String, VecDeque<Annotations>> = HashMap::new();
+    let mut pending = Vec::new();
+    let mut ancestry = Ancestry::default();
+    for line in original.lines() {
+        let trimmed = line.trim_start();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            pending.push(line.to_string());
+            continue;
+        }
+        let (content, inline_comment) = split_inline_comment(line);
+        let path = ancestry.push(content);
+        annotations.entry(path).or_default().push_back(Annotations {
+            comments: std::mem::take(&mut pending),
+            inline_comment: inline_comment.map(String::from),
+        });
+    }
+
+    let mut output = Vec::new();
+    let mut ancestry = Ancestry::default();
+    for line in updated.lines() {
+        let path = ancestry.push(line);
+        match annotations.get_mut(&path).and_then(|a| a.pop_front()) {
+            Some(annotation) => {
+                output.extend(annotation.comments);
+                match annotation.inline_comment {
+                    Some(comment) => output.push(format!("{} {}", line, comment)),
+                    None => output.push(line.to_string()),
+                }
+            }
+            None => output.push(line.to_string()),
+        }
+    }
+    // Comments at the very end of the file have no line to be attached to
+    output.extend(pending);
+    output.join("\n") + "\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config_writer::{restore_comments, to_yaml};
+    use serde_yaml::Value;
+
+    const ORIGINAL: &str = r#"---
+# Global options
+global_config:
+  # Ignored everywhere
+  ignore:
+    - "*/file4"
+  rsync:
+    ssh:
+      options:
+        - ControlMaster: "auto" # reuse connections
+    # Default host
+    default_dst_host: host.com
+# List of workspaces
+workspaces:
+  - src_dir: /tmp/dir1
+    # Syncers for dir1
+    syncers:
+      - type: rsync
+        dst_dir: /remote/dir1
+  # The second workspace
+  - src_dir: /tmp/dir2
+    syncers:
+      - type: rsync
+        dst_dir: /remote/dir2
+# Trailing comment
+"#;
+
+    #[test]
+    fn comments_are_reattached_to_their_lines() {
+        let updated = r#"---
+global_config:
+  ignore:
+    - "*/file4"
+    - added
+  rsync:
+    ssh:
+      options:
+        - ControlMaster: auto
+    default_dst_host: other.com
+workspaces:
+  - src_dir: /tmp/dir2/
+    syncers:
+      - type: rsync
+        dst_dir: /remote/dir2
+"#;
+        let expected = r#"---
+# Global options
+global_config:
+  # Ignored everywhere
+  ignore:
+    - "*/file4"
+    - added
+  rsync:
+    ssh:
+      options:
+        - ControlMaster: auto # reuse connections
+    # Default host
+    default_dst_host: other.com
+# List of workspaces
+workspaces:
+  # The second workspace
+  - src_dir: /tmp/dir2/
+    syncers:
+      - type: rsync
+        dst_dir: /remote/dir2
+# Trailing comment
+"#;
+        assert_eq!(restore_comments(ORIGINAL, updated), expected);
+    }
+
+    #[test]
+    fn keys_keep_their_original_order() {
+        let mut value: Value = serde_yaml::from_str(ORIGINAL).unwrap();
+        // Round trip through a struct-like ordering where default_dst_host comes first
+        let rsync = value["global_config"]["rsync"].as_mapping_mut().unwrap();
+        let ssh = rsync.remove(&Value::from("ssh")).unwrap();
+        rsync.insert(Value::from("ssh"), ssh);
+        let rendered = to_yaml(Some(ORIGINAL), &value).unwrap();
+        assert!(rendered.find("ssh:").unwrap() < rendered.find("default_dst_host:").unwrap());
+        assert!(rendered.contains("# The second workspace\n  - src_dir: /tmp/dir2\n"));
+        assert!(rendered.ends_with("# Trailing comment\n"));
+    }
+}
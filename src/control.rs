@@ -0,0 +1,229 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::sync::{oneshot, RwLock};
+use tracing::{debug, warn};
+
+use crate::error::{Error, Result};
+use crate::status::WorkspaceStatus;
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(tag = "request", rename_all = "snake_case")]
+pub enum Request {
+    Status,
+    // Syncs the given workspace, or every workspace, in full
+    Resync {
+        workspace: Option<String>,
+    },
+    // Holds back syncing of the given workspace (or every workspace), optionally only for one of
+    // its syncers, until resumed
+    Pause {
+        workspace: Option<String>,
+        syncer: Option<String>,
+    },
+    Resume {
+        workspace: Option<String>,
+        syncer: Option<String>,
+    },
+    // Re-reads the config file and restarts the workspaces with it
+    Reload,
+    Shutdown,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(tag = "response", rename_all = "snake_case")]
+pub enum Response {
+    Status { workspaces: Vec<WorkspaceStatus> },
+    Ok,
+    Error { message: String },
+}
+
+// A request received on the control socket, along with where its response should be sent
+pub type ControlRequest = (Request, oneshot::Sender<Response>);
+
+// Listens on a unix socket for requests to the running daemon. Requests and responses are JSON
+// objects, one per line. The socket file is removed when the server is dropped
+pub struct ControlServer {
+    path: PathBuf,
+    // Held for reading by every connection while it answers a request
+    responding: Arc<RwLock<()>>,
+}
+
+impl ControlServer {
+    pub async fn bind(path: &Path) -> Result<(Self, UnboundedReceiver<ControlRequest>)> {
+        if path.exists() {
+            // A socket that is still listened on belongs to another daemon. Otherwise it was left
+            // behind by a daemon that didn't exit cleanly and can be replaced
+            if UnixStream::connect(path).await.is_ok() {
+                return Err(Error::DaemonRunning(path.to_path_buf()));
+            }
+            std::fs::remove_file(path).map_err(Error::io(format!(
+                "failed to remove stale control socket {:?}",
+                path
+            )))?;
+        }
+        let listener = UnixListener::bind(path).map_err(Error::io(format!(
+            "failed to listen on control socket {:?}",
+            path
+        )))?;
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        let responding = Arc::new(RwLock::new(()));
+        tokio::spawn(accept(listener, sender, responding.clone()));
+        let server = ControlServer {
+            path: path.to_path_buf(),
+            responding,
+        };
+        Ok((server, receiver))
+    }
+
+    // Stops taking requests and waits for the responses that were already given to be written
+    // back, so that e.g. a shutdown request is acknowledged before the daemon exits
+    pub async fn close(self, requests: UnboundedReceiver<ControlRequest>) {
+        drop(requests);
+        let _ = self.responding.write().await;
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!("Error removing control socket: {:?}", e);
+        }
+    }
+}
+
+async fn accept(
+    listener: UnixListener,
+    requests: UnboundedSender<ControlRequest>,
+    responding: Arc<RwLock<()>>,
+) {
+    while !requests.is_closed() {
+        match listener.accept().await {
+            Ok((stream, _addr)) => {
+                tokio::spawn(serve(stream, requests.clone(), responding.clone()));
+            }
+            Err(e) => warn!("Error accepting control connection: {:?}", e),
+        }
+    }
+}
+
+async fn serve(
+    stream: UnixStream,
+    requests: UnboundedSender<ControlRequest>,
+    responding: Arc<RwLock<()>>,
+) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let _responding = responding.read().await;
+        debug!(request = %line, "Received control request");
+        let response = match serde_json::from_str::<Request>(&line) {
+            Err(e) => Response::Error {
+                message: format!("Invalid request: {}", e),
+            },
+            Ok(request) => {
+                let (sender, receiver) = oneshot::channel();
+                if requests.send((request, sender)).is_err() {
+                    return;
+                }
+                receiver.await.unwrap_or_else(|_| Response::Error {
+                    message: String::from("The daemon is shutting down"),
+                })
+            }
+        };
+        let mut data = match serde_json::to_vec(&response) {
+            Ok(data) => data,
+            Err(e) => {
+                warn!("Error encoding control response: {:?}", e);
+                return;
+            }
+        };
+        data.push(b'\n');
+        if writer.write_all(&data).await.is_err() {
+            return;
+        }
+    }
+}
+
+// Sends a single request to the daemon listening on the given socket and waits for its response
+pub async fn request(path: &Path, request: &Request) -> Result<Response> {
+    let stream = UnixStream::connect(path)
+        .await
+        .map_err(|source| Error::DaemonUnreachable {
+            path: path.to_path_buf(),
+            source,
+        })?;
+    let (reader, mut writer) = stream.into_split();
+    let mut data = serde_json::to_vec(request).map_err(Error::ControlMessage)?;
+    data.push(b'\n');
+    writer
+        .write_all(&data)
+        .await
+        .map_err(Error::io("failed to send control request"))?;
+    let line = BufReader::new(reader)
+        .lines()
+        .next_line()
+        .await
+        .map_err(Error::io("failed to read control response"))?
+        .unwrap_or_default();
+    serde_json::from_str(&line).map_err(Error::ControlMessage)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::control::{request, ControlServer, Request, Response};
+    use crate::error::Error;
+
+    #[tokio::test]
+    async fn requests_are_answered_over_the_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("control.sock");
+        let (server, mut requests) = ControlServer::bind(&path).await.unwrap();
+        assert!(matches!(
+            ControlServer::bind(&path).await,
+            Err(Error::DaemonRunning(_))
+        ));
+        tokio::spawn(async move {
+            while let Some((request, responder)) = requests.recv().await {
+                assert_eq!(request, Request::Status);
+                responder
+                    .send(Response::Status { workspaces: vec![] })
+                    .unwrap();
+            }
+        });
+        let response = request(&path, &Request::Status).await.unwrap();
+        assert_eq!(response, Response::Status { workspaces: vec![] });
+
+        drop(server);
+        assert!(!path.exists());
+        assert!(matches!(
+            request(&path, &Request::Status).await,
+            Err(Error::DaemonUnreachable { .. })
+        ));
+    }
+
+    #[test]
+    fn requests_are_encoded_as_tagged_json() {
+        let pause = Request::Pause {
+            workspace: Some("project".to_string()),
+            syncer: None,
+        };
+        assert_eq!(
+            serde_json::to_string(&pause).unwrap(),
+            r#"{"request":"pause","workspace":"project","syncer":null}"#
+        );
+        // Omitted targets apply to everything
+        assert_eq!(
+            serde_json::from_str::<Request>(r#"{"request":"resync"}"#).unwrap(),
+            Request::Resync { workspace: None }
+        );
+        assert_eq!(
+            serde_json::to_string(&Response::Ok).unwrap(),
+            r#"{"response":"ok"}"#
+        );
+    }
+}
@@ -0,0 +1,512 @@
+use notify::event::ModifyKind;
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::time::Instant;
+
+use crate::config::{Config, Workspace};
+use crate::control::{ControlServer, Request, Response};
+use crate::error::{Error, Result};
+use crate::executor::SyncExecutor;
+use crate::fstree::FsTree;
+use crate::ignore_files;
+use crate::notify_tokio::TokioEventHandler;
+use crate::runner::{RequestedPauses, WorkspaceRunner};
+use crate::status::SyncState;
+use crate::syncers::Changes;
+use tracing::{debug, error, info, info_span, warn};
+
+// Watches the selected workspaces and syncs them as files change, until it receives SIGINT, SIGTERM
+// or SIGHUP, or is asked to shut down. Requests to the daemon are served on the control socket for
+// as long as it runs, and the config file is reloaded whenever it changes
+pub async fn run(
+    config: Config,
+    config_path: &str,
+    selected: &[String],
+    socket_path: &Path,
+) -> Result {
+    let workspaces = config.select_workspaces(selected)?;
+    let mut interrupt = listen(SignalKind::interrupt())?;
+    let mut terminate = listen(SignalKind::terminate())?;
+    let mut hangup = listen(SignalKind::hangup())?;
+    let (control_server, mut control_requests) = ControlServer::bind(socket_path).await?;
+    let (handler, mut receiver) = TokioEventHandler::unbounded();
+    let watcher = notify::recommended_watcher(handler).map_err(Error::Watcher)?;
+    // The config file is matched against event paths, which are always absolute. It may also be a
+    // symlink, in which case it's the file it links to that gets edited
+    let config_file = std::fs::canonicalize(config_path).map_err(Error::io(format!(
+        "failed to resolve config file {}",
+        config_path
+    )))?;
+    let mut daemon = Daemon {
+        config_path: String::from(config_path),
+        config_file,
+        selected: selected.to_vec(),
+        watcher,
+        workspace_tree: FsTree::new(),
+        shutdown_timeout: config.shutdown_timeout(),
+        global_excludes: ignore_files::global_excludes_path(),
+    };
+    daemon.watch_config()?;
+    daemon.watch_global_excludes();
+    for workspace in workspaces {
+        daemon.start_workspace(workspace, None)?;
+    }
+
+    // Editors often write the config file in several steps, so it is only reloaded once it has
+    // stopped changing for a moment
+    let mut reload_at: Option<Instant> = None;
+    loop {
+        tokio::select! {
+            event = receiver.recv() => match event {
+                Some(Ok(event)) => {
+                    debug!(kind = ?event.kind, paths = ?event.paths, "Received event");
+                    if daemon.is_config_change(&event) {
+                        reload_at = Some(Instant::now() + CONFIG_SETTLE_DELAY);
+                    }
+                    if daemon.is_global_excludes_change(&event) {
+                        for runner in &daemon.workspace_tree {
+                            runner.ignore_files_changed(event.paths[0].clone(), event.kind.clone());
+                        }
+                    }
+                    for path in event.paths {
+                        if let Some(runner) = daemon.workspace_tree.get_closest(&path) {
+                            runner.handle_change(path, event.kind.clone());
+                        }
+                    }
+                }
+                Some(Err(e)) => warn!("Received error event: {:?}", e),
+                None => break,
+            },
+            _ = tokio::time::sleep_until(reload_at.unwrap_or_else(Instant::now)), if reload_at.is_some() => {
+                reload_at = None;
+                if let Err(e) = daemon.reload().await {
+                    error!("Error reloading config: {}", e.report());
+                }
+            }
+            Some((request, responder)) = control_requests.recv() => {
+                let shutdown = request == Request::Shutdown;
+                let response = daemon.handle_request(request).await;
+                // The client may have gone away in the meantime, which is fine
+                let _ = responder.send(response);
+                if shutdown {
+                    break;
+                }
+            }
+            _ = interrupt.recv() => {
+                info!(signal = "SIGINT", "Received signal");
+                break;
+            }
+            _ = terminate.recv() => {
+                info!(signal = "SIGTERM", "Received signal");
+                break;
+            }
+            _ = hangup.recv() => {
+                info!(signal = "SIGHUP", "Received signal");
+                break;
+            }
+        }
+    }
+    control_server.close(control_requests).await;
+    daemon.shutdown().await;
+    info!("Exiting...");
+    Ok(())
+}
+
+fn listen(kind: SignalKind) -> Result<Signal> {
+    signal(kind).map_err(Error::io("failed to listen for signals"))
+}
+
+const CONFIG_SETTLE_DELAY: Duration = Duration::from_millis(200);
+
+struct Daemon {
+    config_path: String,
+    config_file: PathBuf,
+    // Names or source directories of the workspaces to run, all of them if empty
+    selected: Vec<String>,
+    watcher: RecommendedWatcher,
+    workspace_tree: FsTree<WorkspaceRunner>,
+    shutdown_timeout: Duration,
+    // The global git excludes file, which applies to every workspace respecting ignore files
+    global_excludes: Option<PathBuf>,
+}
+
+impl Daemon {
+    // The directory of the config file is watched rather than the file itself, as editors tend to
+    // replace the file when saving it
+    fn watch_config(&mut self) -> Result {
+        let config_dir = self.config_file.parent().unwrap_or(Path::new("/"));
+        self.watcher
+            .watch(config_dir, RecursiveMode::NonRecursive)
+            .map_err(|source| Error::Watch {
+                path: config_dir.to_string_lossy().into_owned(),
+                source,
+            })
+    }
+
+    fn is_config_change(&self, event: &Event) -> bool {
+        changes_file(event, &self.config_file)
+    }
+
+    // Like the config file, it's the directory that gets watched. It may well not exist, in which
+    // case there is nothing to watch
+    fn watch_global_excludes(&mut self) {
+        let excludes_dir = self.global_excludes.as_deref().and_then(Path::parent);
+        if let Some(excludes_dir) = excludes_dir {
+            if let Err(e) = self
+                .watcher
+                .watch(excludes_dir, RecursiveMode::NonRecursive)
+            {
+                debug!(
+                    "Not watching global git excludes in {:?}: {:?}",
+                    excludes_dir, e
+                );
+            }
+        }
+    }
+
+    fn is_global_excludes_change(&self, event: &Event) -> bool {
+        self.global_excludes
+            .as_ref()
+            .is_some_and(|global_excludes| changes_file(event, global_excludes))
+    }
+
+    // Pauses requested before the workspace was restarted are applied before it syncs anything
+    fn start_workspace(&mut self, workspace: Workspace, pauses: Option<RequestedPauses>) -> Result {
+        let path = PathBuf::from(&workspace.path);
+        info!(
+            workspace = %workspace.name,
+            path = %workspace.path,
+            "Monitoring workspace"
+        );
+        let initial_sync = workspace.initial_sync;
+        let runner = WorkspaceRunner::start(workspace);
+        if let Some(pauses) = pauses {
+            runner.restore_pauses(&pauses);
+        }
+        self.workspace_tree.insert(&path, runner);
+        if let Err(source) = self.watcher.watch(&path, RecursiveMode::Recursive) {
+            self.workspace_tree.remove(&path);
+            return Err(Error::Watch {
+                path: path.to_string_lossy().into_owned(),
+                source,
+            });
+        }
+        // Only done once the workspace is watched, so no change can slip in between the two
+        if initial_sync {
+            if let Some(runner) = self.workspace_tree.get_closest(&path) {
+                runner.sync_all();
+            }
+        }
+        Ok(())
+    }
+
+    // Stops watching the workspace and hands back its runner, which is left for the caller to stop
+    fn stop_workspace(&mut self, path: &Path) -> Option<WorkspaceRunner> {
+        let runner = self.workspace_tree.remove(path)?;
+        info!(workspace = %runner.workspace.name, "Stopping workspace");
+        if let Err(e) = self.watcher.unwatch(path) {
+            warn!("Error unwatching {}: {:?}", runner.workspace.path, e);
+        }
+        self.rewatch_inside(path);
+        Some(runner)
+    }
+
+    // Unwatching a directory also drops the watches on the directories inside it, which the config
+    // file, the global git excludes or other workspaces may be in
+    fn rewatch_inside(&mut self, path: &Path) {
+        if self
+            .config_file
+            .parent()
+            .is_some_and(|config_dir| config_dir.starts_with(path))
+        {
+            if let Err(e) = self.watch_config() {
+                error!("Error watching the config file again: {}", e.report());
+            }
+        }
+        if self
+            .global_excludes
+            .as_deref()
+            .and_then(Path::parent)
+            .is_some_and(|excludes_dir| excludes_dir.starts_with(path))
+        {
+            self.watch_global_excludes();
+        }
+        let nested: Vec<PathBuf> = self
+            .workspace_tree
+            .into_iter()
+            .map(|runner| PathBuf::from(&runner.workspace.path))
+            .filter(|workspace_path| workspace_path.starts_with(path))
+            .collect();
+        for workspace_path in nested {
+            if let Err(e) = self
+                .watcher
+                .watch(&workspace_path, RecursiveMode::Recursive)
+            {
+                error!("Error watching {:?} again: {:?}", workspace_path, e);
+            }
+        }
+    }
+
+    // Stops watching for changes, then waits for every workspace to sync what was collected so
+    // far, giving up after the shutdown timeout
+    async fn shutdown(mut self) {
+        drop(self.watcher);
+        info!(timeout = ?self.shutdown_timeout, "Waiting for syncs to finish");
+        let deadline = Instant::now() + self.shutdown_timeout;
+        let paths: Vec<PathBuf> = self
+            .workspace_tree
+            .into_iter()
+            .map(|runner| PathBuf::from(&runner.workspace.path))
+            .collect();
+        let stops: Vec<_> = paths
+            .iter()
+            .filter_map(|path| self.workspace_tree.remove(path))
+            .map(|runner| tokio::spawn(runner.stop(deadline)))
+            .collect();
+        for stop in stops {
+            let _ = stop.await;
+        }
+    }
+
+    // Only workspaces that were added, removed or changed are restarted, the others carry on
+    // untouched. The config is only applied once every workspace has been built from it, so an
+    // invalid config leaves the running workspaces as they are. A workspace failing to start
+    // doesn't keep the others from starting
+    async fn reload(&mut self) -> Result {
+        info!(config = %self.config_path, "Reloading config");
+        let config = Config::parse(&self.config_path).await?;
+        let workspaces = config.select_workspaces(&self.selected)?;
+        self.shutdown_timeout = config.shutdown_timeout();
+        let stale: Vec<PathBuf> = self
+            .workspace_tree
+            .into_iter()
+            .filter(|runner| {
+                !workspaces
+                    .iter()
+                    .any(|ws| ws.same_config(&runner.workspace))
+            })
+            .map(|runner| PathBuf::from(&runner.workspace.path))
+            .collect();
+        let deadline = Instant::now() + self.shutdown_timeout;
+        let mut pauses = HashMap::new();
+        let mut stops = Vec::new();
+        for path in stale {
+            if let Some(runner) = self.stop_workspace(&path) {
+                pauses.insert(path, runner.requested_pauses());
+                stops.push(tokio::spawn(runner.stop(deadline)));
+            }
+        }
+        // The old syncers are done before their replacements start, so that the two never sync
+        // to the same place at once
+        for stop in stops {
+            let _ = stop.await;
+        }
+        let running: Vec<String> = self
+            .workspace_tree
+            .into_iter()
+            .map(|runner| runner.workspace.path.clone())
+            .collect();
+        let mut result = Ok(());
+        for workspace in workspaces {
+            if !running.contains(&workspace.path) {
+                let name = workspace.name.clone();
+                let requested = pauses.remove(Path::new(&workspace.path));
+                if let Err(e) = self.start_workspace(workspace, requested) {
+                    error!(workspace = %name, "Error starting workspace: {}", e.report());
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+
+    async fn handle_request(&mut self, request: Request) -> Response {
+        let result = match request {
+            Request::Status => {
+                return Response::Status {
+                    workspaces: self
+                        .workspace_tree
+                        .into_iter()
+                        .map(|runner| runner.status())
+                        .collect(),
+                }
+            }
+            Request::Resync { workspace } => self
+                .runners(workspace.as_deref())
+                .map(|runners| runners.iter().for_each(|runner| runner.sync_all())),
+            Request::Pause { workspace, syncer } => {
+                self.for_each_syncer(workspace.as_deref(), syncer.as_deref(), |runner, syncer| {
+                    runner.pause(syncer)
+                })
+            }
+            Request::Resume { workspace, syncer } => {
+                self.for_each_syncer(workspace.as_deref(), syncer.as_deref(), |runner, syncer| {
+                    runner.resume(syncer)
+                })
+            }
+            Request::Reload => self.reload().await.map_err(|e| e.report()),
+            Request::Shutdown => {
+                info!("Shutdown requested");
+                Ok(())
+            }
+        };
+        match result {
+            Ok(_) => Response::Ok,
+            Err(message) => Response::Error { message },
+        }
+    }
+
+    // The runner of the workspace with the given name or source directory, or every runner
+    fn runners(
+        &self,
+        workspace: Option<&str>,
+    ) -> std::result::Result<Vec<&WorkspaceRunner>, String> {
+        let runners: Vec<&WorkspaceRunner> = self
+            .workspace_tree
+            .into_iter()
+            .filter(|runner| workspace.is_none_or(|name| runner.workspace.is_named(name)))
+            .collect();
+        match (workspace, runners.is_empty()) {
+            (Some(name), true) => Err(format!("Unknown workspace: {}", name)),
+            _ => Ok(runners),
+        }
+    }
+
+    fn for_each_syncer(
+        &self,
+        workspace: Option<&str>,
+        syncer: Option<&str>,
+        action: impl Fn(&WorkspaceRunner, Option<&str>) -> bool,
+    ) -> std::result::Result<(), String> {
+        let mut found = false;
+        for runner in self.runners(workspace)? {
+            found |= action(runner, syncer);
+        }
+        match (syncer, found) {
+            (Some(name), false) => Err(format!("Unknown syncer: {}", name)),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn changes_file(event: &Event, file: &Path) -> bool {
+    let changed = matches!(
+        event.kind,
+        EventKind::Create(_) | EventKind::Remove(_) | EventKind::Modify(_)
+    ) && !matches!(event.kind, EventKind::Modify(ModifyKind::Metadata(_)));
+    changed && event.paths.iter().any(|path| path == file)
+}
+
+// Syncs every workspace in full through each of its syncers, retrying the way the daemon does.
+// Returns whether all syncs succeeded
+pub async fn sync_once(workspaces: Vec<Workspace>) -> bool {
+    let mut executors = Vec::new();
+    for workspace in &workspaces {
+        let span = info_span!("workspace", workspace = %workspace.name);
+        for configured in &workspace.syncers {
+            let executor = span.in_scope(|| {
+                SyncExecutor::new(&workspace.path, configured.syncer.clone(), configured.retry)
+            });
+            executor.submit(Changes::full());
+            executors.push(executor);
+        }
+    }
+    let mut success = true;
+    for executor in &executors {
+        executor.wait_idle().await;
+        success &= executor.status().state != SyncState::Failed;
+    }
+    for configured in workspaces.iter().flat_map(|workspace| &workspace.syncers) {
+        configured.syncer.close().await;
+    }
+    success
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Config, ConfiguredSyncer};
+    use crate::daemon::Daemon;
+    use crate::fstree::FsTree;
+    use crate::notify_tokio::TokioEventHandler;
+    use crate::retry::RetryPolicy;
+    use crate::syncers::{Changes, Result as SyncerResult, SyncStats, Syncer};
+    use async_trait::async_trait;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    struct SlowSyncer {
+        finished: Arc<AtomicBool>,
+    }
+
+    #[async_trait]
+    impl Syncer for SlowSyncer {
+        fn name(&self) -> String {
+            String::from("slow")
+        }
+
+        async fn sync(&self, _workspace_path: &Path, _changes: &Changes) -> SyncerResult {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            self.finished.store(true, Ordering::SeqCst);
+            Ok(SyncStats::default())
+        }
+    }
+
+    fn write_config(config_path: &Path, workspace: &Path, dst_dir: &Path) {
+        let config = format!(
+            "workspaces:\n  - src_dir: {}\n    syncers:\n      - type: local\n        dst_dir: {}\n",
+            workspace.display(),
+            dst_dir.display()
+        );
+        std::fs::write(config_path, config).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reloading_waits_for_the_syncs_of_changed_workspaces() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let workspace_path = root.join("workspace");
+        std::fs::create_dir(&workspace_path).unwrap();
+        std::fs::write(workspace_path.join("file1"), "one").unwrap();
+        let config_path = root.join("config.yaml");
+        write_config(&config_path, &workspace_path, &root.join("old"));
+        let config = Config::parse(config_path.to_str().unwrap()).await.unwrap();
+        let mut workspace = config.workspaces().unwrap().remove(0);
+        let finished = Arc::new(AtomicBool::new(false));
+        workspace.syncers = vec![ConfiguredSyncer {
+            syncer: Arc::new(Box::pin(SlowSyncer {
+                finished: finished.clone(),
+            })),
+            retry: RetryPolicy::default(),
+        }];
+        let (handler, _events) = TokioEventHandler::unbounded();
+        let mut daemon = Daemon {
+            config_path: config_path.to_str().unwrap().to_string(),
+            config_file: config_path.clone(),
+            selected: vec![],
+            watcher: notify::recommended_watcher(handler).unwrap(),
+            workspace_tree: FsTree::new(),
+            shutdown_timeout: Duration::from_secs(5),
+            global_excludes: None,
+        };
+        // The initial sync is still running when the workspace gets a new destination
+        daemon.start_workspace(workspace, None).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        write_config(&config_path, &workspace_path, &root.join("new"));
+        daemon.reload().await.unwrap();
+        assert!(finished.load(Ordering::SeqCst));
+
+        let synced = root.join("new/file1");
+        for _ in 0..50 {
+            if synced.exists() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(std::fs::read_to_string(synced).unwrap(), "one");
+    }
+}
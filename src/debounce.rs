@@ -0,0 +1,190 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use notify::EventKind;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::time::Instant;
+use tracing::error;
+
+use crate::syncers::Changes;
+
+const DEFAULT_QUIET_PERIOD_MS: u64 = 200;
+const DEFAULT_MAX_LATENCY_MS: u64 = 2000;
+
+#[derive(Debug, PartialEq, Clone, Default, Deserialize, Serialize)]
+pub struct DebounceProperties {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quiet_period_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_latency_ms: Option<u64>,
+}
+
+impl DebounceProperties {
+    pub fn merge(&self, overrides: Option<&DebounceProperties>) -> Self {
+        DebounceProperties {
+            quiet_period_ms: overrides
+                .and_then(|o| o.quiet_period_ms)
+                .or(self.quiet_period_ms),
+            max_latency_ms: overrides
+                .and_then(|o| o.max_latency_ms)
+                .or(self.max_latency_ms),
+        }
+    }
+
+    pub fn as_settings(&self) -> DebounceSettings {
+        DebounceSettings {
+            quiet_period: Duration::from_millis(
+                self.quiet_period_ms.unwrap_or(DEFAULT_QUIET_PERIOD_MS),
+            ),
+            max_latency: Duration::from_millis(
+                self.max_latency_ms.unwrap_or(DEFAULT_MAX_LATENCY_MS),
+            ),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct DebounceSettings {
+    // How long no new events must arrive before a batch is flushed
+    pub quiet_period: Duration,
+    // Upper bound on how long a batch can be held back, even if events keep arriving
+    pub max_latency: Duration,
+}
+
+impl Default for DebounceSettings {
+    fn default() -> Self {
+        DebounceProperties::default().as_settings()
+    }
+}
+
+// Collects individual file events and hands them out as coalesced batches once the workspace
+// has gone quiet. Dropping the Debouncer flushes whatever is pending and closes the batch receiver
+pub struct Debouncer(UnboundedSender<(PathBuf, EventKind)>);
+
+impl Debouncer {
+    pub fn spawn(settings: DebounceSettings) -> (Self, UnboundedReceiver<Changes>) {
+        let (event_sender, event_receiver) = tokio::sync::mpsc::unbounded_channel();
+        let (batch_sender, batch_receiver) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(run(settings, event_receiver, batch_sender));
+        (Debouncer(event_sender), batch_receiver)
+    }
+
+    pub fn push(&self, path: PathBuf, kind: EventKind) {
+        if let Err(e) = self.0.send((path, kind)) {
+            error!("Error queueing event for debouncing: {:?}", e);
+        }
+    }
+}
+
+async fn run(
+    settings: DebounceSettings,
+    mut events: UnboundedReceiver<(PathBuf, EventKind)>,
+    batches: UnboundedSender<Changes>,
+) {
+    let mut closed = false;
+    while !closed {
+        let (path, kind) = match events.recv().await {
+            Some(event) => event,
+            None => break,
+        };
+        let mut changes = Changes::new();
+        changes.insert(path, kind);
+        let max_deadline = Instant::now() + settings.max_latency;
+        loop {
+            let deadline = std::cmp::min(Instant::now() + settings.quiet_period, max_deadline);
+            match tokio::time::timeout_at(deadline, events.recv()).await {
+                Ok(Some((path, kind))) => changes.insert(path, kind),
+                Ok(None) => {
+                    closed = true;
+                    break;
+                }
+                Err(_elapsed) => break,
+            }
+        }
+        if batches.send(changes).is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::debounce::{DebounceProperties, DebounceSettings, Debouncer};
+    use notify::event::ModifyKind;
+    use notify::EventKind;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    fn settings(quiet_period_ms: u64, max_latency_ms: u64) -> DebounceSettings {
+        DebounceSettings {
+            quiet_period: Duration::from_millis(quiet_period_ms),
+            max_latency: Duration::from_millis(max_latency_ms),
+        }
+    }
+
+    #[test]
+    fn test_debounceproperties_merge() {
+        let global = DebounceProperties {
+            quiet_period_ms: Some(100),
+            max_latency_ms: Some(1000),
+        };
+        let workspace = DebounceProperties {
+            quiet_period_ms: None,
+            max_latency_ms: Some(5000),
+        };
+        let merged = global.merge(Some(&workspace)).as_settings();
+        assert_eq!(merged, settings(100, 5000));
+        assert_eq!(
+            DebounceProperties::default().merge(None).as_settings(),
+            DebounceSettings::default()
+        );
+    }
+
+    #[tokio::test]
+    async fn bursts_are_coalesced_into_one_batch() {
+        let (debouncer, mut batches) = Debouncer::spawn(settings(50, 1000));
+        for i in 0..10 {
+            debouncer.push(
+                PathBuf::from(format!("/ws/file{}", i % 3)),
+                EventKind::Modify(ModifyKind::Any),
+            );
+        }
+        let batch = batches.recv().await.unwrap();
+        assert_eq!(batch.len(), 3);
+        drop(debouncer);
+        assert!(batches.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn max_latency_caps_how_long_a_batch_is_held() {
+        let (debouncer, mut batches) = Debouncer::spawn(settings(100, 150));
+        for i in 0..10 {
+            debouncer.push(
+                PathBuf::from(format!("/ws/file{}", i)),
+                EventKind::Modify(ModifyKind::Any),
+            );
+            tokio::time::sleep(Duration::from_millis(40)).await;
+        }
+        let first = batches.recv().await.unwrap();
+        assert!(first.len() < 10, "first batch should be flushed early");
+        drop(debouncer);
+        let mut total = first.len();
+        while let Some(batch) = batches.recv().await {
+            total += batch.len();
+        }
+        assert_eq!(total, 10);
+    }
+
+    #[tokio::test]
+    async fn pending_batch_is_flushed_when_dropped() {
+        let (debouncer, mut batches) = Debouncer::spawn(settings(10_000, 10_000));
+        debouncer.push(
+            PathBuf::from("/ws/file1"),
+            EventKind::Modify(ModifyKind::Any),
+        );
+        drop(debouncer);
+        assert_eq!(batches.recv().await.unwrap().len(), 1);
+        assert!(batches.recv().await.is_none());
+    }
+}
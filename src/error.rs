@@ -0,0 +1,168 @@
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+use crate::rsync::exit_code::RsyncExitCode;
+
+pub type Result<T = ()> = std::result::Result<T, Error>;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("failed to run {program}")]
+    Spawn {
+        program: String,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("rsync failed: {code}{}", stderr_suffix(.stderr))]
+    Rsync { code: RsyncExitCode, stderr: String },
+    #[error("ssh connection to {host} failed{}", stderr_suffix(.stderr))]
+    Ssh { host: String, stderr: String },
+    #[error("command on {host} failed with {status}{}", stderr_suffix(.stderr))]
+    Remote {
+        host: String,
+        status: std::process::ExitStatus,
+        stderr: String,
+    },
+    #[error("{0}")]
+    Config(String),
+    #[error("invalid config file {path}")]
+    ConfigFile {
+        path: String,
+        #[source]
+        source: serde_yaml::Error,
+    },
+    #[error("invalid glob '{pattern}'")]
+    Glob {
+        pattern: String,
+        #[source]
+        source: globset::Error,
+    },
+    #[error("path is not valid UTF-8: {0:?}")]
+    PathEncoding(PathBuf),
+    #[error("failed to start file watcher")]
+    Watcher(#[source] notify::Error),
+    #[error("failed to watch {path}")]
+    Watch {
+        path: String,
+        #[source]
+        source: notify::Error,
+    },
+    #[error("a daemon is already listening on {0:?}")]
+    DaemonRunning(PathBuf),
+    #[error("unable to reach the daemon at {path:?}, is it running?")]
+    DaemonUnreachable {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("invalid control message")]
+    ControlMessage(#[source] serde_json::Error),
+    #[error("invalid sync manifest {path:?}")]
+    Manifest {
+        path: PathBuf,
+        #[source]
+        source: serde_json::Error,
+    },
+    #[error("invalid snapshot {name}")]
+    Snapshot {
+        name: String,
+        #[source]
+        source: serde_json::Error,
+    },
+    #[error("{context}")]
+    S3 {
+        context: String,
+        transient: bool,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    #[error("{context}")]
+    Sftp {
+        context: String,
+        transient: bool,
+        #[source]
+        source: ssh2::Error,
+    },
+    #[error("{context}")]
+    Io {
+        context: String,
+        #[source]
+        source: std::io::Error,
+    },
+}
+
+impl Error {
+    pub fn io(context: impl Into<String>) -> impl FnOnce(std::io::Error) -> Error {
+        let context = context.into();
+        move |source| Error::Io { context, source }
+    }
+
+    // Whether the error is likely to go away by itself, e.g. the remote host was briefly
+    // unreachable, so that the same sync is worth trying again
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::Ssh { .. } => true,
+            Error::S3 { transient, .. } | Error::Sftp { transient, .. } => *transient,
+            Error::Rsync { code, .. } => matches!(
+                code,
+                RsyncExitCode::SocketIo
+                    | RsyncExitCode::StreamIo
+                    | RsyncExitCode::Timeout
+                    | RsyncExitCode::ConnectionTimeout
+                    | RsyncExitCode::RemoteShell
+            ),
+            _ => false,
+        }
+    }
+
+    // The error along with every error that caused it, on a single line
+    pub fn report(&self) -> String {
+        let mut report = self.to_string();
+        let mut previous = report.clone();
+        let mut source = std::error::Error::source(self);
+        while let Some(cause) = source {
+            // Some errors (serde_yaml's for one) repeat themselves as their own source
+            let message = cause.to_string();
+            if message != previous {
+                report.push_str(": ");
+                report.push_str(&message);
+            }
+            previous = message;
+            source = cause.source();
+        }
+        report
+    }
+}
+
+fn stderr_suffix(stderr: &str) -> String {
+    let stderr = stderr.trim();
+    if stderr.is_empty() {
+        String::new()
+    } else {
+        format!("\n{}", stderr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::rsync::exit_code::RsyncExitCode;
+
+    #[test]
+    fn report_includes_the_source_chain() {
+        let error = Error::Spawn {
+            program: "rsync".to_string(),
+            source: std::io::Error::new(std::io::ErrorKind::NotFound, "not found"),
+        };
+        assert_eq!(error.report(), "failed to run rsync: not found");
+        let error = Error::Rsync {
+            code: RsyncExitCode::Partial,
+            stderr: "rsync: some files vanished\n".to_string(),
+        };
+        assert_eq!(
+            error.report(),
+            "rsync failed: partial transfer due to error (exit code 23)\nrsync: some files vanished"
+        );
+    }
+}
@@ -0,0 +1,416 @@
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use tokio::sync::watch;
+use tracing::{error, info, info_span, warn, Instrument, Span};
+
+use crate::retry::RetryPolicy;
+use crate::status::SyncerStatus;
+use crate::syncers::{Changes, Syncer};
+
+// Syncers are paused either on request or by the pause marker of their workspace, and only resume
+// once neither holds them back anymore
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PauseReason {
+    Requested,
+    Marker,
+}
+
+struct ExecutorState {
+    running: bool,
+    paused_on_request: bool,
+    paused_by_marker: bool,
+    pending: Option<Changes>,
+    status: SyncerStatus,
+}
+
+impl ExecutorState {
+    fn is_paused(&self) -> bool {
+        self.paused_on_request || self.paused_by_marker
+    }
+
+    fn paused_by(&mut self, reason: PauseReason) -> &mut bool {
+        match reason {
+            PauseReason::Requested => &mut self.paused_on_request,
+            PauseReason::Marker => &mut self.paused_by_marker,
+        }
+    }
+}
+
+// Runs a single syncer for a single workspace, making sure at most one sync is in flight at a
+// time. Changes submitted while a sync is running are merged together and synced in exactly one
+// follow-up run once the current one finishes. Syncs failing with a transient error are retried
+// according to the retry policy. While paused, changes keep being collected and are synced in a
+// single run once resumed
+pub struct SyncExecutor {
+    workspace_path: String,
+    syncer: Arc<Pin<Box<dyn Syncer>>>,
+    retry: RetryPolicy,
+    state: Mutex<ExecutorState>,
+    // Mirrors whether syncs are running, for whoever needs to wait for them to be done
+    running: watch::Sender<bool>,
+    running_updates: watch::Receiver<bool>,
+    span: Span,
+}
+
+impl SyncExecutor {
+    pub fn new(
+        workspace_path: &str,
+        syncer: Arc<Pin<Box<dyn Syncer>>>,
+        retry: RetryPolicy,
+    ) -> Arc<Self> {
+        let span = info_span!("syncer", syncer = %syncer.name());
+        let state = ExecutorState {
+            running: false,
+            paused_on_request: false,
+            paused_by_marker: false,
+            pending: None,
+            status: SyncerStatus::new(syncer.name()),
+        };
+        let (running, running_updates) = watch::channel(false);
+        Arc::new(SyncExecutor {
+            workspace_path: String::from(workspace_path),
+            syncer,
+            retry,
+            state: Mutex::new(state),
+            running,
+            running_updates,
+            span,
+        })
+    }
+
+    pub fn name(&self) -> String {
+        self.syncer.name()
+    }
+
+    pub fn status(&self) -> SyncerStatus {
+        let state = self.state.lock().unwrap();
+        let mut status = state.status.clone();
+        status.paused = state.is_paused();
+        status
+    }
+
+    pub fn submit(self: &Arc<Self>, changes: Changes) {
+        let mut state = self.state.lock().unwrap();
+        match state.pending.as_mut() {
+            Some(pending) => pending.merge(changes),
+            None => state.pending = Some(changes),
+        }
+        self.start_draining(&mut state);
+    }
+
+    // Whether there are changes that haven't been handed to the syncer yet, e.g. because it is paused
+    pub fn has_pending(&self) -> bool {
+        self.state.lock().unwrap().pending.is_some()
+    }
+
+    // Waits until no sync is running and none is about to start. Changes held back while paused
+    // aren't waited for
+    pub async fn wait_idle(&self) {
+        let mut running = self.running_updates.clone();
+        while *running.borrow() {
+            if running.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    pub fn is_paused_on_request(&self) -> bool {
+        self.state.lock().unwrap().paused_on_request
+    }
+
+    // A sync that is already running is left to finish
+    pub fn pause(&self, reason: PauseReason) {
+        self.span.in_scope(|| info!(?reason, "Pausing syncer"));
+        *self.state.lock().unwrap().paused_by(reason) = true;
+    }
+
+    // Only lifts the pause for the given reason, the syncer stays paused if there is another one
+    pub fn resume(self: &Arc<Self>, reason: PauseReason) {
+        let mut state = self.state.lock().unwrap();
+        *state.paused_by(reason) = false;
+        self.span.in_scope(|| match state.is_paused() {
+            true => info!(?reason, "Syncer is still paused for another reason"),
+            false => info!("Resuming syncer"),
+        });
+        self.start_draining(&mut state);
+    }
+
+    fn start_draining(self: &Arc<Self>, state: &mut ExecutorState) {
+        if !state.running && !state.is_paused() && state.pending.is_some() {
+            state.running = true;
+            let _ = self.running.send(true);
+            tokio::spawn(self.clone().drain().instrument(self.span.clone()));
+        }
+    }
+
+    async fn drain(self: Arc<Self>) {
+        loop {
+            let changes = {
+                let mut state = self.state.lock().unwrap();
+                let pending = match state.is_paused() {
+                    true => None,
+                    false => state.pending.take(),
+                };
+                match pending {
+                    Some(changes) => changes,
+                    None => {
+                        state.running = false;
+                        let _ = self.running.send(false);
+                        return;
+                    }
+                }
+            };
+            let span = info_span!("sync", changes = changes.len(), full = changes.is_full());
+            self.sync_with_retries(changes).instrument(span).await;
+        }
+    }
+
+    async fn sync_with_retries(&self, mut changes: Changes) {
+        let mut attempt = 1;
+        let start = Instant::now();
+        self.state.lock().unwrap().status.started();
+        loop {
+            let e = match self
+                .syncer
+                .sync(self.workspace_path.as_ref(), &changes)
+                .await
+            {
+                Ok(stats) => {
+                    info!(elapsed = ?start.elapsed(), bytes_transferred = ?stats.bytes_transferred, "Sync finished");
+                    let mut state = self.state.lock().unwrap();
+                    state.status.succeeded(&stats, start.elapsed());
+                    return;
+                }
+                Err(e) => e,
+            };
+            let delay = match self.retry.next_delay(&e, attempt) {
+                Some(delay) => delay,
+                None => {
+                    error!("Error during sync: {}", e.report());
+                    let mut state = self.state.lock().unwrap();
+                    state.status.failed(&e, start.elapsed());
+                    return;
+                }
+            };
+            warn!(
+                attempt,
+                max_attempts = self.retry.max_attempts,
+                ?delay,
+                "Error during sync, retrying: {}",
+                e.report()
+            );
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+            // The failed batch is kept, and whatever was submitted in the meantime is synced
+            // along with it rather than in a separate run
+            if let Some(pending) = self.state.lock().unwrap().pending.take() {
+                changes.merge(pending);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::executor::{PauseReason, SyncExecutor};
+    use crate::retry::RetryPolicy;
+    use crate::rsync::exit_code::RsyncExitCode;
+    use crate::status::SyncState;
+    use crate::syncers::{Changes, Result as SyncerResult, SyncStats, Syncer};
+    use async_trait::async_trait;
+    use notify::event::ModifyKind;
+    use notify::EventKind;
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::mpsc::UnboundedSender;
+
+    struct SlowSyncer {
+        in_flight: AtomicUsize,
+        max_in_flight: Arc<AtomicUsize>,
+        finished: UnboundedSender<usize>,
+    }
+
+    #[async_trait]
+    impl Syncer for SlowSyncer {
+        fn name(&self) -> String {
+            String::from("slow")
+        }
+
+        async fn sync(&self, _workspace_path: &Path, changes: &Changes) -> SyncerResult {
+            let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            self.finished.send(changes.len()).unwrap();
+            Ok(SyncStats::default())
+        }
+    }
+
+    // Fails with a transient error until it has been called the given number of times
+    struct FlakySyncer {
+        failures: usize,
+        calls: Arc<AtomicUsize>,
+        finished: UnboundedSender<usize>,
+    }
+
+    #[async_trait]
+    impl Syncer for FlakySyncer {
+        fn name(&self) -> String {
+            String::from("flaky")
+        }
+
+        async fn sync(&self, _workspace_path: &Path, changes: &Changes) -> SyncerResult {
+            if self.calls.fetch_add(1, Ordering::SeqCst) < self.failures {
+                return Err(Error::Rsync {
+                    code: RsyncExitCode::SocketIo,
+                    stderr: String::new(),
+                });
+            }
+            self.finished.send(changes.len()).unwrap();
+            Ok(SyncStats::default())
+        }
+    }
+
+    fn retry_policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            base_delay: Duration::from_millis(50),
+            max_delay: Duration::from_millis(50),
+            jitter: 0.0,
+        }
+    }
+
+    fn change(name: &str) -> Changes {
+        let mut changes = Changes::new();
+        changes.insert(
+            PathBuf::from("/ws").join(name),
+            EventKind::Modify(ModifyKind::Any),
+        );
+        changes
+    }
+
+    #[tokio::test]
+    async fn submissions_during_a_sync_are_merged_into_one_follow_up() {
+        let (sender, mut finished) = tokio::sync::mpsc::unbounded_channel();
+        let max_in_flight = Arc::new(AtomicUsize::new(0));
+        let syncer = SlowSyncer {
+            in_flight: AtomicUsize::new(0),
+            max_in_flight: max_in_flight.clone(),
+            finished: sender,
+        };
+        let executor = SyncExecutor::new("/ws", Arc::new(Box::pin(syncer)), RetryPolicy::default());
+        executor.submit(change("file1"));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        executor.submit(change("file2"));
+        executor.submit(change("file3"));
+        executor.submit(change("file2"));
+
+        assert_eq!(finished.recv().await, Some(1));
+        assert_eq!(finished.recv().await, Some(2));
+        let no_more_runs = tokio::time::timeout(Duration::from_millis(150), finished.recv()).await;
+        assert!(
+            no_more_runs.is_err(),
+            "there should be exactly one follow-up run"
+        );
+        assert_eq!(max_in_flight.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn transient_failures_are_retried_with_the_pending_batch() {
+        let (sender, mut finished) = tokio::sync::mpsc::unbounded_channel();
+        let syncer = FlakySyncer {
+            failures: 2,
+            calls: Arc::new(AtomicUsize::new(0)),
+            finished: sender,
+        };
+        let executor = SyncExecutor::new("/ws", Arc::new(Box::pin(syncer)), retry_policy(3));
+        executor.submit(change("file1"));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        executor.submit(change("file2"));
+
+        // The change submitted while retrying is synced along with the failed batch
+        assert_eq!(finished.recv().await, Some(2));
+        let no_more_runs = tokio::time::timeout(Duration::from_millis(150), finished.recv()).await;
+        assert!(no_more_runs.is_err());
+    }
+
+    #[tokio::test]
+    async fn retries_stop_after_max_attempts() {
+        let (sender, mut finished) = tokio::sync::mpsc::unbounded_channel();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let syncer = FlakySyncer {
+            failures: 2,
+            calls: calls.clone(),
+            finished: sender,
+        };
+        let executor = SyncExecutor::new("/ws", Arc::new(Box::pin(syncer)), retry_policy(2));
+        executor.submit(change("file1"));
+        let gave_up = tokio::time::timeout(Duration::from_millis(200), finished.recv()).await;
+        assert!(gave_up.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        let status = executor.status();
+        assert_eq!(status.state, SyncState::Failed);
+        assert!(status.last_error.is_some());
+
+        // The next batch goes through now that the syncer has recovered
+        executor.submit(change("file2"));
+        assert_eq!(finished.recv().await, Some(1));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let status = executor.status();
+        assert_eq!(status.state, SyncState::Idle);
+        assert!(status.last_success.is_some());
+    }
+
+    #[tokio::test]
+    async fn waiting_for_idle_covers_the_follow_up_run() {
+        let (sender, mut finished) = tokio::sync::mpsc::unbounded_channel();
+        let syncer = SlowSyncer {
+            in_flight: AtomicUsize::new(0),
+            max_in_flight: Arc::new(AtomicUsize::new(0)),
+            finished: sender,
+        };
+        let executor = SyncExecutor::new("/ws", Arc::new(Box::pin(syncer)), RetryPolicy::default());
+        executor.wait_idle().await;
+        executor.submit(change("file1"));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        executor.submit(change("file2"));
+        executor.wait_idle().await;
+        assert_eq!(finished.try_recv(), Ok(1));
+        assert_eq!(finished.try_recv(), Ok(1));
+        assert!(!executor.has_pending());
+    }
+
+    #[tokio::test]
+    async fn changes_are_held_while_paused_and_synced_once_resumed() {
+        let (sender, mut finished) = tokio::sync::mpsc::unbounded_channel();
+        let syncer = FlakySyncer {
+            failures: 0,
+            calls: Arc::new(AtomicUsize::new(0)),
+            finished: sender,
+        };
+        let executor = SyncExecutor::new("/ws", Arc::new(Box::pin(syncer)), retry_policy(1));
+        executor.pause(PauseReason::Requested);
+        executor.submit(change("file1"));
+        executor.submit(change("file2"));
+        let held = tokio::time::timeout(Duration::from_millis(100), finished.recv()).await;
+        assert!(held.is_err());
+        assert!(executor.status().paused);
+        executor.wait_idle().await;
+        assert!(executor.has_pending());
+
+        // Lifting a pause that was never made leaves the requested one in place
+        executor.resume(PauseReason::Marker);
+        let held = tokio::time::timeout(Duration::from_millis(100), finished.recv()).await;
+        assert!(held.is_err());
+        assert!(executor.status().paused);
+
+        executor.resume(PauseReason::Requested);
+        assert_eq!(finished.recv().await, Some(2));
+        assert!(!executor.status().paused);
+    }
+}
@@ -0,0 +1,336 @@
+use std::path::{Path, PathBuf};
+
+use globset::{Glob, GlobMatcher};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::error::{Error, Result};
+use crate::ignore_files::IgnoreFiles;
+use crate::rsync::cli::RsyncOption;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterAction {
+    Include,
+    Exclude,
+}
+
+// A filter rule as declared in the config file, e.g. `- exclude: build` or `- include: build/config.json`
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterRuleProperties {
+    Include(String),
+    Exclude(String),
+}
+
+impl FilterRuleProperties {
+    pub fn as_rule(&self) -> (FilterAction, &str) {
+        match self {
+            FilterRuleProperties::Include(pattern) => (FilterAction::Include, pattern),
+            FilterRuleProperties::Exclude(pattern) => (FilterAction::Exclude, pattern),
+        }
+    }
+}
+
+struct FilterRule {
+    action: FilterAction,
+    matcher: GlobMatcher,
+    // The same pattern in rsync syntax. Empty if it can't be expressed relative to the workspace
+    rsync_patterns: Vec<String>,
+}
+
+// Decides which files of a workspace get synced. Rules are ordered from lowest to highest
+// precedence and the last rule matching a path decides whether it is synced. The rules of ignore
+// files come before all others, and paths no rule matches are synced. Just like with rsync, nothing
+// below an excluded directory is synced, whatever the rules say about it
+pub struct Filter {
+    root: PathBuf,
+    rules: Vec<FilterRule>,
+    ignore_files: Option<IgnoreFiles>,
+}
+
+impl Filter {
+    // Patterns are globs matched against absolute paths. Relative ones are relative to the root
+    pub fn new<'a>(
+        root: &Path,
+        rules: impl IntoIterator<Item = (FilterAction, &'a str)>,
+        ignore_files: Option<IgnoreFiles>,
+    ) -> Result<Self> {
+        let root_str = root
+            .to_str()
+            .ok_or_else(|| Error::PathEncoding(root.to_path_buf()))?;
+        let rules = rules
+            .into_iter()
+            .map(|(action, pattern)| {
+                let glob = match Path::new(pattern).is_absolute() {
+                    true => String::from(pattern),
+                    false => format!("{}/{}", root_str.trim_end_matches('/'), pattern),
+                };
+                let matcher = Glob::new(&glob)
+                    .map_err(|source| Error::Glob {
+                        pattern: glob.clone(),
+                        source,
+                    })?
+                    .compile_matcher();
+                let rsync_patterns = rsync_patterns(root_str, &glob);
+                if rsync_patterns.is_empty() {
+                    warn!(%pattern, "Pattern is outside of the workspace, it only applies to file events");
+                }
+                Ok(FilterRule {
+                    action,
+                    matcher,
+                    rsync_patterns,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Filter {
+            root: root.to_path_buf(),
+            rules,
+            ignore_files,
+        })
+    }
+
+    pub fn should_sync(&self, path: &Path) -> bool {
+        let relative = match path.strip_prefix(&self.root) {
+            Ok(relative) => relative,
+            Err(_) => return !self.excludes(path, None, false),
+        };
+        let components: Vec<_> = relative.components().collect();
+        let mut ancestor = self.root.clone();
+        for (i, component) in components.iter().enumerate() {
+            ancestor.push(component);
+            let is_dir = i + 1 < components.len() || path.is_dir();
+            let relative = ancestor.strip_prefix(&self.root).ok();
+            if self.excludes(&ancestor, relative, is_dir) {
+                return false;
+            }
+        }
+        true
+    }
+
+    // Ignore files only apply within the workspace
+    fn excludes(&self, path: &Path, relative: Option<&Path>, is_dir: bool) -> bool {
+        match self
+            .rules
+            .iter()
+            .rev()
+            .find(|rule| rule.matcher.is_match(path))
+        {
+            Some(rule) => rule.action == FilterAction::Exclude,
+            None => self
+                .ignore_files
+                .as_ref()
+                .zip(relative)
+                .and_then(|(ignore_files, relative)| ignore_files.last_match(relative, is_dir))
+                .unwrap_or(false),
+        }
+    }
+
+    // Whether the ignore files need to be reloaded after the given file changed
+    pub fn is_ignore_file(&self, path: &Path) -> bool {
+        self.ignore_files
+            .as_ref()
+            .is_some_and(|ignore_files| ignore_files.is_ignore_file(path))
+    }
+
+    // Has the ignore files reloaded with the next batch of changes, rather than on every change.
+    // Returns false if the filter doesn't use ignore files
+    pub fn mark_ignore_files_stale(&self) -> bool {
+        match &self.ignore_files {
+            Some(ignore_files) => {
+                ignore_files.mark_stale();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn has_stale_ignore_files(&self) -> bool {
+        self.ignore_files
+            .as_ref()
+            .is_some_and(|ignore_files| ignore_files.is_stale())
+    }
+
+    // Returns false if the filter doesn't use ignore files
+    pub fn reload_ignore_files(&self) -> bool {
+        match &self.ignore_files {
+            Some(ignore_files) => {
+                ignore_files.reload();
+                true
+            }
+            None => false,
+        }
+    }
+
+    // The rules as rsync --include and --exclude options. rsync goes by the first matching rule
+    // rather than the last, so they are listed from highest to lowest precedence
+    pub fn rsync_options(&self) -> Vec<RsyncOption> {
+        self.rules
+            .iter()
+            .rev()
+            .flat_map(|rule| {
+                rule.rsync_patterns
+                    .iter()
+                    .map(move |pattern| match rule.action {
+                        FilterAction::Include => RsyncOption::Include(pattern.clone()),
+                        FilterAction::Exclude => RsyncOption::Exclude(pattern.clone()),
+                    })
+            })
+            .chain(
+                self.ignore_files
+                    .iter()
+                    .flat_map(|ignore_files| ignore_files.rsync_options()),
+            )
+            .collect()
+    }
+
+    // The rules as rsync options for a transfer rooted at a directory inside the workspace, given
+    // relative to it, rather than at the workspace itself
+    pub fn rsync_options_below(&self, dir: &Path) -> Vec<RsyncOption> {
+        let dir = dir.to_string_lossy();
+        let dir = dir.trim_matches('/');
+        if dir.is_empty() {
+            return self.rsync_options();
+        }
+        self.rsync_options()
+            .into_iter()
+            .flat_map(|option| match option {
+                RsyncOption::Include(pattern) => rebase(&pattern, dir)
+                    .into_iter()
+                    .map(RsyncOption::Include)
+                    .collect(),
+                RsyncOption::Exclude(pattern) => rebase(&pattern, dir)
+                    .into_iter()
+                    .map(RsyncOption::Exclude)
+                    .collect(),
+                _ => Vec::new(),
+            })
+            .collect()
+    }
+}
+
+// Whether a syncer with the given filter syncs path. Syncers without one sync everything
+pub fn allows(filter: Option<&Filter>, path: &Path) -> bool {
+    filter.is_none_or(|filter| filter.should_sync(path))
+}
+
+// A pattern anchored to the workspace, as anchored to a directory inside it. Patterns starting with
+// ** also match inside the directory, any other anchored to somewhere else in the workspace doesn't
+fn rebase(pattern: &str, dir: &str) -> Vec<String> {
+    let anchored = match pattern.strip_prefix('/') {
+        Some(anchored) => anchored,
+        None => return vec![String::from(pattern)],
+    };
+    if let Some(rest) = anchored
+        .strip_prefix(dir)
+        .and_then(|rest| rest.strip_prefix('/'))
+        .filter(|rest| !rest.is_empty())
+    {
+        return vec![format!("/{}", rest)];
+    }
+    match anchored.strip_prefix("**") {
+        // Like with a leading **/ in the workspace, it may match no directory at all
+        Some(rest) if rest.starts_with('/') => vec![String::from(rest), String::from(pattern)],
+        Some(_) => vec![String::from(pattern)],
+        None => Vec::new(),
+    }
+}
+
+// rsync matches patterns starting with a slash against the root of the transfer, which is the
+// workspace. A * in a glob also matches across directories, which only ** does in rsync
+fn rsync_patterns(root: &str, glob: &str) -> Vec<String> {
+    let relative = match glob
+        .strip_prefix(root.trim_end_matches('/'))
+        .and_then(|rest| rest.strip_prefix('/'))
+    {
+        Some(relative) => relative,
+        None => return Vec::new(),
+    };
+    let mut pattern = String::new();
+    for c in relative.chars() {
+        match c {
+            '*' if pattern.ends_with("**") => {}
+            '*' => pattern.push_str("**"),
+            c => pattern.push(c),
+        }
+    }
+    // A leading **/ matches no directory at all in a glob, but at least one in rsync
+    match relative.starts_with("**/") {
+        true => vec![format!("/{}", &pattern[3..]), format!("/{}", pattern)],
+        false => vec![format!("/{}", pattern)],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::filter::{Filter, FilterAction};
+    use crate::rsync::cli::RsyncOption;
+    use std::path::Path;
+
+    #[test]
+    fn last_matching_rule_decides() {
+        let filter = Filter::new(
+            Path::new("/ws"),
+            [
+                (FilterAction::Exclude, "build/*"),
+                (FilterAction::Include, "build/config.json"),
+                (FilterAction::Exclude, "*.tmp"),
+                (FilterAction::Exclude, "logs"),
+            ],
+            None,
+        )
+        .unwrap();
+        assert!(!filter.should_sync(Path::new("/ws/build/app")));
+        assert!(filter.should_sync(Path::new("/ws/build/config.json")));
+        assert!(!filter.should_sync(Path::new("/ws/src/file.tmp")));
+        assert!(!filter.should_sync(Path::new("/ws/logs/today")));
+        assert!(filter.should_sync(Path::new("/ws/src/main.rs")));
+    }
+
+    #[test]
+    fn nothing_below_an_excluded_directory_is_synced() {
+        let filter = Filter::new(
+            Path::new("/ws"),
+            [
+                (FilterAction::Exclude, "build"),
+                (FilterAction::Include, "build/config.json"),
+            ],
+            None,
+        )
+        .unwrap();
+        assert!(!filter.should_sync(Path::new("/ws/build/config.json")));
+    }
+
+    #[test]
+    fn rules_are_translated_to_rsync_options() {
+        let filter = Filter::new(
+            Path::new("/ws"),
+            [
+                (FilterAction::Exclude, "*/ignore-1/*"),
+                (FilterAction::Include, "build/config.json"),
+                (FilterAction::Exclude, "**/node_modules"),
+                (FilterAction::Exclude, "/elsewhere/*"),
+            ],
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            filter.rsync_options(),
+            vec![
+                RsyncOption::Exclude("/node_modules".to_string()),
+                RsyncOption::Exclude("/**/node_modules".to_string()),
+                RsyncOption::Include("/build/config.json".to_string()),
+                RsyncOption::Exclude("/**/ignore-1/**".to_string()),
+            ]
+        );
+        assert_eq!(
+            filter.rsync_options_below(Path::new("build")),
+            vec![
+                RsyncOption::Exclude("/node_modules".to_string()),
+                RsyncOption::Exclude("/**/node_modules".to_string()),
+                RsyncOption::Include("/config.json".to_string()),
+                RsyncOption::Exclude("/ignore-1/**".to_string()),
+                RsyncOption::Exclude("/**/ignore-1/**".to_string()),
+            ]
+        );
+    }
+}
@@ -1,7 +1,7 @@
 use std::collections::HashMap;
 use std::ffi::{OsStr, OsString};
 use std::fmt::Debug;
-use std::path::PathBuf;
+use std::path::Path;
 
 #[derive(Debug)]
 struct Node<T> {
@@ -30,9 +30,9 @@ impl<T> FsTree<T> {
         }
     }
 
-    pub fn insert(&mut self, path: &PathBuf, data: T) {
+    pub fn insert(&mut self, path: &Path, data: T) {
         let mut path_vec: Vec<&OsStr> = path.iter().rev().collect();
-        if path_vec.first().map_or(false, |p| p.is_empty()) {
+        if path_vec.first().is_some_and(|p| p.is_empty()) {
             path_vec.remove(0);
         }
         if path_vec.last() != Some(&OsStr::new("/")) {
@@ -42,7 +42,7 @@ impl<T> FsTree<T> {
     }
 
     fn insert_with_path(node: &mut Node<T>, mut path: Vec<&OsStr>, data: T) {
-        if path.len() == 0 {
+        if path.is_empty() {
             node.data = Some(data);
             return;
         }
@@ -54,7 +54,16 @@ impl<T> FsTree<T> {
         FsTree::insert_with_path(child, path, data);
     }
 
-    pub fn get_closest(&self, path: &PathBuf) -> Option<&T> {
+    // Takes out the data stored at exactly the given path, if any
+    pub fn remove(&mut self, path: &Path) -> Option<T> {
+        let mut node = &mut self.root;
+        for component in path.iter() {
+            node = node.children.get_mut(component)?;
+        }
+        node.data.take()
+    }
+
+    pub fn get_closest(&self, path: &Path) -> Option<&T> {
         let path_vec: Vec<&OsStr> = path.iter().rev().collect();
         FsTree::get_with_path(&self.root, path_vec)
     }
@@ -107,11 +116,11 @@ impl<'a, T> Iterator for FsTreeIter<'a, T> {
         while data.is_none() && !self.to_check.is_empty() {
             let next_check = self.to_check.pop();
             data = next_check.and_then(|node| node.data.as_ref());
-            next_check.map(|node| {
+            if let Some(node) = next_check {
                 node.children.values().for_each(|child| {
                     self.to_check.push(child);
                 });
-            });
+            }
         }
         data
     }
@@ -186,6 +195,23 @@ mod tests {
         );
     }
 
+    #[test]
+    fn remove_only_takes_the_exact_path() {
+        let mut tree: FsTree<i8> = FsTree::new();
+        tree.insert(&PathBuf::from("/tmp/dir1"), 1);
+        tree.insert(&PathBuf::from("/tmp/dir1/subdir1"), 2);
+        assert_eq!(tree.remove(&PathBuf::from("/tmp/dir1/subdir2")), None);
+        assert_eq!(tree.remove(&PathBuf::from("/tmp")), None);
+        assert_eq!(tree.remove(&PathBuf::from("/tmp/dir1/")), Some(1));
+        assert_eq!(tree.remove(&PathBuf::from("/tmp/dir1")), None);
+        assert_eq!(
+            tree.get_closest(&PathBuf::from("/tmp/dir1/subdir1/file1")),
+            Some(&2)
+        );
+        assert_eq!(tree.get_closest(&PathBuf::from("/tmp/dir1/file1")), None);
+        assert_eq!(tree.into_iter().count(), 1);
+    }
+
     #[test]
     fn iterator_works() {
         let mut tree: FsTree<Item> = FsTree::new();
@@ -0,0 +1,361 @@
+use std::collections::VecDeque;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{OnceLock, RwLock};
+
+use globset::{GlobBuilder, GlobMatcher};
+use tracing::{debug, warn};
+
+use crate::rsync::cli::RsyncOption;
+
+const GITIGNORE: &str = ".gitignore";
+// Same syntax as .gitignore, for files that should not be synced but are tracked by git
+const RDSIGNORE: &str = ".rdsignore";
+const GIT_EXCLUDE: &str = ".git/info/exclude";
+
+// A single pattern from an ignore file, in .gitignore syntax
+#[derive(Debug)]
+struct IgnoreRule {
+    // Directory of the ignore file, relative to the workspace root. Empty for the root itself
+    base: String,
+    pattern: String,
+    // Patterns containing a slash only match relative to their base, others match at any depth
+    // below it
+    anchored: bool,
+    dir_only: bool,
+    negated: bool,
+    matcher: GlobMatcher,
+}
+
+impl IgnoreRule {
+    fn parse(base: &str, line: &str) -> Option<IgnoreRule> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let (negated, pattern) = match line.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, line),
+        };
+        // A leading backslash lets a pattern start with a literal # or !
+        let pattern = pattern
+            .strip_prefix('\\')
+            .filter(|rest| rest.starts_with('#') || rest.starts_with('!'))
+            .unwrap_or(pattern);
+        let (dir_only, pattern) = match pattern.strip_suffix('/') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern),
+        };
+        let anchored = pattern.contains('/');
+        let pattern = pattern.trim_start_matches('/');
+        if pattern.is_empty() {
+            return None;
+        }
+        let glob = match anchored {
+            true => join(base, pattern),
+            false => join(base, &format!("**/{}", pattern)),
+        };
+        let matcher = match GlobBuilder::new(&glob).literal_separator(true).build() {
+            Ok(glob) => glob.compile_matcher(),
+            Err(e) => {
+                warn!(pattern = %line, "Skipping invalid ignore pattern: {}", e);
+                return None;
+            }
+        };
+        Some(IgnoreRule {
+            base: String::from(base),
+            pattern: String::from(pattern),
+            anchored,
+            dir_only,
+            negated,
+            matcher,
+        })
+    }
+
+    fn is_match(&self, relative: &Path, is_dir: bool) -> bool {
+        (is_dir || !self.dir_only) && self.matcher.is_match(relative)
+    }
+
+    // Patterns are anchored to the root of the transfer, which is the workspace root. In rsync a
+    // "**" always matches at least one directory, so unanchored patterns below the root need two
+    // rules to also match directly inside their base
+    fn as_rsync_options(&self) -> Vec<RsyncOption> {
+        let suffix = if self.dir_only { "/" } else { "" };
+        let patterns = match (self.anchored, self.base.is_empty()) {
+            (false, true) => vec![self.pattern.clone()],
+            (true, _) => vec![format!("/{}", join(&self.base, &self.pattern))],
+            (false, false) => vec![
+                format!("/{}/{}", self.base, self.pattern),
+                format!("/{}/**/{}", self.base, self.pattern),
+            ],
+        };
+        patterns
+            .into_iter()
+            .map(|pattern| {
+                let pattern = format!("{}{}", pattern, suffix);
+                match self.negated {
+                    true => RsyncOption::Include(pattern),
+                    false => RsyncOption::Exclude(pattern),
+                }
+            })
+            .collect()
+    }
+}
+
+fn join(base: &str, pattern: &str) -> String {
+    match base.is_empty() {
+        true => String::from(pattern),
+        false => format!("{}/{}", base, pattern),
+    }
+}
+
+// The ignore rules of a workspace, read from the global git excludes file, .git/info/exclude,
+// every .gitignore and every .rdsignore in the workspace. Rules are kept from lowest to highest
+// precedence, and just like with git the last rule matching a path decides whether it is ignored
+pub struct IgnoreFiles {
+    root: PathBuf,
+    global_excludes: Option<PathBuf>,
+    rules: RwLock<Vec<IgnoreRule>>,
+    // Set when one of the files changed, until the rules are reloaded
+    stale: AtomicBool,
+}
+
+impl IgnoreFiles {
+    pub fn load(root: &Path) -> Self {
+        IgnoreFiles::with_global_excludes(root, global_excludes_path())
+    }
+
+    fn with_global_excludes(root: &Path, global_excludes: Option<PathBuf>) -> Self {
+        let ignore_files = IgnoreFiles {
+            root: root.to_path_buf(),
+            global_excludes,
+            rules: RwLock::new(Vec::new()),
+            stale: AtomicBool::new(false),
+        };
+        ignore_files.reload();
+        ignore_files
+    }
+
+    // Goes through the whole workspace, so it blocks for a while on large ones
+    pub fn reload(&self) {
+        self.stale.store(false, Ordering::SeqCst);
+        let rules = load_rules(&self.root, self.global_excludes.as_deref());
+        debug!(root = ?self.root, rules = rules.len(), "Loaded ignore files");
+        *self.rules.write().unwrap() = rules;
+    }
+
+    pub fn mark_stale(&self) {
+        self.stale.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_stale(&self) -> bool {
+        self.stale.load(Ordering::SeqCst)
+    }
+
+    // Whether the rules need to be reloaded after the given file changed
+    pub fn is_ignore_file(&self, path: &Path) -> bool {
+        let file_name = path.file_name().unwrap_or_default();
+        (file_name == GITIGNORE || file_name == RDSIGNORE)
+            || path == self.root.join(GIT_EXCLUDE)
+            || Some(path) == self.global_excludes.as_deref()
+    }
+
+    // Whether the last rule matching the path, relative to the workspace, ignores it. None if no
+    // rule matches it
+    pub fn last_match(&self, relative: &Path, is_dir: bool) -> Option<bool> {
+        last_match(self.rules.read().unwrap().iter(), relative, is_dir)
+    }
+
+    // The rules as rsync options. rsync goes by the first matching rule rather than the last, so
+    // they are listed in reverse
+    pub fn rsync_options(&self) -> Vec<RsyncOption> {
+        self.rules
+            .read()
+            .unwrap()
+            .iter()
+            .rev()
+            .flat_map(|rule| rule.as_rsync_options())
+            .collect()
+    }
+}
+
+fn last_match<'a>(
+    rules: impl DoubleEndedIterator<Item = &'a IgnoreRule>,
+    relative: &Path,
+    is_dir: bool,
+) -> Option<bool> {
+    rules
+        .rev()
+        .find(|rule| rule.is_match(relative, is_dir))
+        .map(|rule| !rule.negated)
+}
+
+fn load_rules(root: &Path, global_excludes: Option<&Path>) -> Vec<IgnoreRule> {
+    let mut rules = Vec::new();
+    if let Some(global_excludes) = global_excludes {
+        read_rules(global_excludes, "", &mut rules);
+    }
+    read_rules(&root.join(GIT_EXCLUDE), "", &mut rules);
+    // Kept apart so that .rdsignore rules take precedence over every .gitignore
+    let mut rds_rules = Vec::new();
+    // Going through the workspace breadth first keeps the rules of deeper directories, which take
+    // precedence, after those of their parents
+    let mut dirs = VecDeque::from([PathBuf::new()]);
+    while let Some(dir) = dirs.pop_front() {
+        let base = dir.to_string_lossy();
+        read_rules(&root.join(&dir).join(GITIGNORE), &base, &mut rules);
+        read_rules(&root.join(&dir).join(RDSIGNORE), &base, &mut rds_rules);
+        let entries = match std::fs::read_dir(root.join(&dir)) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!(
+                    "Error listing {:?} for ignore files: {:?}",
+                    root.join(&dir),
+                    e
+                );
+                continue;
+            }
+        };
+        for entry in entries.flatten() {
+            let is_dir = entry.file_type().is_ok_and(|file_type| file_type.is_dir());
+            if !is_dir || entry.file_name() == ".git" {
+                continue;
+            }
+            let child = dir.join(entry.file_name());
+            // Git doesn't look for ignore files in ignored directories either
+            if last_match(rules.iter().chain(rds_rules.iter()), &child, true) != Some(true) {
+                dirs.push_back(child);
+            }
+        }
+    }
+    rules.extend(rds_rules);
+    rules
+}
+
+fn read_rules(path: &Path, base: &str, rules: &mut Vec<IgnoreRule>) {
+    match std::fs::read_to_string(path) {
+        Ok(data) => rules.extend(
+            data.lines()
+                .filter_map(|line| IgnoreRule::parse(base, line)),
+        ),
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => warn!("Error reading ignore file {:?}: {:?}", path, e),
+    }
+}
+
+// Where git reads its global excludes from: core.excludesFile if set, $XDG_CONFIG_HOME/git/ignore
+// otherwise. Finding out runs git, so it's only done once, when the daemon starts, rather than on
+// every config reload
+pub fn global_excludes_path() -> Option<PathBuf> {
+    static GLOBAL_EXCLUDES: OnceLock<Option<PathBuf>> = OnceLock::new();
+    GLOBAL_EXCLUDES.get_or_init(resolve_global_excludes).clone()
+}
+
+fn resolve_global_excludes() -> Option<PathBuf> {
+    let configured = std::process::Command::new("git")
+        .args(["config", "--global", "--path", "--get", "core.excludesFile"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .filter(|path| !path.is_empty());
+    if let Some(path) = configured {
+        return Some(PathBuf::from(path));
+    }
+    std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| home::home_dir().map(|home| home.join(".config")))
+        .map(|config_dir| config_dir.join("git").join("ignore"))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::filter::Filter;
+    use crate::ignore_files::IgnoreFiles;
+    use crate::rsync::cli::RsyncOption;
+    use tempfile::TempDir;
+
+    fn workspace(files: &[(&str, &str)]) -> TempDir {
+        let root = tempfile::tempdir().unwrap();
+        for (path, contents) in files {
+            let path = root.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+        root
+    }
+
+    #[test]
+    fn ignore_files_follow_git_precedence() {
+        let workspace = workspace(&[
+            (".gitignore", "target/\n*.log\n!keep.log\n/root-only\n"),
+            (".git/info/exclude", "scratch\n"),
+            ("sub/.gitignore", "# generated\n!debug.log\nout/*.tmp\n"),
+            (".rdsignore", "secrets.txt\n"),
+            ("target/.gitignore", "!*\n"),
+        ]);
+        let root = workspace.path();
+        let global_excludes = root.join("global-excludes");
+        std::fs::write(&global_excludes, ".DS_Store\n").unwrap();
+        let ignore_files = IgnoreFiles::with_global_excludes(root, Some(global_excludes));
+        let filter = Filter::new(root, [], Some(ignore_files)).unwrap();
+        let ignored = |path: &str| !filter.should_sync(&root.join(path));
+
+        assert!(ignored("target/debug/app"));
+        assert!(ignored("target/.gitignore"));
+        assert!(ignored("app.log"));
+        assert!(ignored("sub/deep/app.log"));
+        assert!(!ignored("keep.log"));
+        assert!(!ignored("sub/debug.log"));
+        assert!(ignored("root-only"));
+        assert!(!ignored("sub/root-only"));
+        assert!(ignored("sub/out/file.tmp"));
+        assert!(!ignored("out/file.tmp"));
+        assert!(ignored("scratch"));
+        assert!(ignored("sub/.DS_Store"));
+        assert!(ignored("sub/secrets.txt"));
+        assert!(!ignored("src/main.rs"));
+        assert!(!ignored("/elsewhere/app.log"));
+
+        assert!(filter.is_ignore_file(&root.join("sub/.gitignore")));
+        assert!(filter.is_ignore_file(&root.join(".rdsignore")));
+        assert!(filter.is_ignore_file(&root.join(".git/info/exclude")));
+        assert!(!filter.is_ignore_file(&root.join("src/main.rs")));
+    }
+
+    #[test]
+    fn rules_are_reloaded() {
+        let workspace = workspace(&[(".gitignore", "*.log\n")]);
+        let root = workspace.path();
+        let ignore_files = IgnoreFiles::with_global_excludes(root, None);
+        let filter = Filter::new(root, [], Some(ignore_files)).unwrap();
+        assert!(!filter.should_sync(&root.join("app.log")));
+        std::fs::write(root.join(".gitignore"), "*.tmp\n").unwrap();
+        assert!(filter.reload_ignore_files());
+        assert!(filter.should_sync(&root.join("app.log")));
+        assert!(!filter.should_sync(&root.join("app.tmp")));
+    }
+
+    #[test]
+    fn rules_are_translated_to_rsync_options() {
+        let workspace = workspace(&[
+            (".gitignore", "target/\n*.log\n!keep.log\n/build/out\n"),
+            ("sub/.gitignore", "*.tmp\n"),
+        ]);
+        let root = workspace.path();
+        let ignore_files = IgnoreFiles::with_global_excludes(root, None);
+        assert_eq!(
+            ignore_files.rsync_options(),
+            vec![
+                RsyncOption::Exclude("/sub/*.tmp".to_string()),
+                RsyncOption::Exclude("/sub/**/*.tmp".to_string()),
+                RsyncOption::Exclude("/build/out".to_string()),
+                RsyncOption::Include("keep.log".to_string()),
+                RsyncOption::Exclude("*.log".to_string()),
+                RsyncOption::Exclude("target/".to_string()),
+            ]
+        );
+    }
+}
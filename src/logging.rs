@@ -0,0 +1,98 @@
+use std::io::IsTerminal;
+
+use clap::ArgEnum;
+use serde::{Deserialize, Serialize};
+use tracing_subscriber::EnvFilter;
+
+use crate::error::{Error, Result};
+
+const DEFAULT_LEVEL: &str = "info";
+
+#[derive(Debug, PartialEq, Clone, Copy, Deserialize, Serialize, ArgEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug, PartialEq, Clone, Default, Deserialize, Serialize)]
+pub struct LogProperties {
+    // Either a level (error, warn, info, debug, trace) or a list of directives in the format of
+    // RUST_LOG, e.g. "warn,rust_dev_sync=debug"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub level: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<LogFormat>,
+}
+
+impl LogProperties {
+    pub fn merge(&self, overrides: Option<&LogProperties>) -> Self {
+        LogProperties {
+            level: overrides
+                .and_then(|o| o.level.clone())
+                .or_else(|| self.level.clone()),
+            format: overrides.and_then(|o| o.format).or(self.format),
+        }
+    }
+
+    fn filter(&self) -> Result<EnvFilter> {
+        let level = self.level.as_deref().unwrap_or(DEFAULT_LEVEL);
+        // A plain level only applies to our own logs, dependencies are kept quiet unless asked for
+        let directives = if level.contains(['=', ',']) {
+            level.to_string()
+        } else {
+            format!("warn,{}={}", env!("CARGO_CRATE_NAME"), level)
+        };
+        EnvFilter::try_new(directives)
+            .map_err(|e| Error::Config(format!("Invalid log level '{}': {}", level, e)))
+    }
+
+    // Installs the global logger. Should only be called once
+    pub fn init(&self) -> Result {
+        let builder = tracing_subscriber::fmt()
+            .with_env_filter(self.filter()?)
+            .with_ansi(std::io::stdout().is_terminal());
+        match self.format.unwrap_or(LogFormat::Text) {
+            LogFormat::Text => builder.init(),
+            LogFormat::Json => builder.json().init(),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::logging::{LogFormat, LogProperties};
+
+    #[test]
+    fn test_logproperties_merge() {
+        let config = LogProperties {
+            level: Some("warn".to_string()),
+            format: Some(LogFormat::Json),
+        };
+        let cli = LogProperties {
+            level: Some("debug".to_string()),
+            format: None,
+        };
+        assert_eq!(
+            config.merge(Some(&cli)),
+            LogProperties {
+                level: Some("debug".to_string()),
+                format: Some(LogFormat::Json),
+            }
+        );
+    }
+
+    #[test]
+    fn levels_are_validated() {
+        let props = |level: &str| LogProperties {
+            level: Some(level.to_string()),
+            format: None,
+        };
+        assert!(props("debug").filter().is_ok());
+        assert!(props("warn,rust_dev_sync=trace").filter().is_ok());
+        assert!(props("rust_dev_sync=loud").filter().is_err());
+        assert!(props("loud").filter().is_err());
+        assert!(LogProperties::default().filter().is_ok());
+    }
+}
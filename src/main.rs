@@ -1,70 +1,327 @@
+mod cli;
+mod config;
+mod config_writer;
+mod control;
+mod daemon;
+mod debounce;
+mod error;
+mod executor;
+mod filter;
 mod fstree;
+mod ignore_files;
+mod logging;
 mod notify_tokio;
+mod retry;
 mod rsync;
+mod runner;
+mod status;
+mod suppress;
 mod syncers;
-mod config;
 
-use std::path::PathBuf;
-use notify::{RecursiveMode, Watcher};
+use std::path::{Path, PathBuf};
+
+use clap::Parser;
+use tracing::error;
 
-use crate::syncers::Syncer;
+use crate::cli::{Cli, Command, ConfigCommand};
+use crate::config::Config;
+use crate::control::{Request, Response};
+use crate::error::{Error, Result};
+use crate::syncers::snapshot;
 
 #[tokio::main]
 async fn main() {
-    let home_dir = home::home_dir().unwrap().into_os_string().into_string().unwrap();
-    let config_path = home_dir + "~/.config/rust-dev-sync-config.yaml";
-    println!("Using config at location: {}", config_path);
-    let config = config::Config::parse(&config_path).await.unwrap();
+    let cli = Cli::parse();
+    let config_path = cli.config_path();
+    let success = match &cli.command {
+        Command::Config(command) => run_config_command(command, &config_path).await,
+        Command::Status => show_status(&cli).await,
+        Command::Resync
+        | Command::Pause { .. }
+        | Command::Resume { .. }
+        | Command::Reload
+        | Command::Shutdown => run_control_command(&cli).await,
+        _ => match load_config(&cli, &config_path).await {
+            Err(e) => {
+                println!("Error loading config at {}: {}", config_path, e.report());
+                false
+            }
+            Ok(config) => run_command(&cli, &config_path, config).await,
+        },
+    };
+    if !success {
+        std::process::exit(1);
+    }
+}
+
+// Loads the config file and sets up logging as configured
+async fn load_config(cli: &Cli, config_path: &str) -> Result<Config> {
+    let config = Config::parse(config_path).await?;
+    config.log().merge(Some(&cli.log_overrides())).init()?;
+    Ok(config)
+}
+
+async fn run_command(cli: &Cli, config_path: &str, config: Config) -> bool {
+    if let Command::Sync = cli.command {
+        return match daemon::run(config, config_path, &cli.workspaces, &cli.socket_path()).await {
+            Ok(_) => true,
+            Err(e) => {
+                error!("Error running daemon: {}", e.report());
+                false
+            }
+        };
+    }
+    match &cli.command {
+        Command::Snapshots { workspace } => return show_snapshots(&config, workspace).await,
+        Command::Restore {
+            workspace,
+            at,
+            to,
+            delete,
+            dry_run,
+        } => {
+            let restored = restore_snapshot(
+                &config,
+                workspace,
+                at.as_deref(),
+                to.as_ref(),
+                *delete,
+                *dry_run,
+            );
+            return match restored.await {
+                Ok(_) => true,
+                Err(e) => {
+                    println!("Error restoring {}: {}", workspace, e.report());
+                    false
+                }
+            };
+        }
+        _ => {}
+    }
+    let workspaces = match config.select_workspaces(&cli.workspaces) {
+        Ok(workspaces) => workspaces,
+        Err(e) => {
+            println!("Error loading config at {}: {}", config_path, e.report());
+            return false;
+        }
+    };
+    match &cli.command {
+        Command::Once => daemon::sync_once(workspaces).await,
+        Command::Validate => {
+            println!("Config is valid ({} workspace(s))", workspaces.len());
+            true
+        }
+        _ => unreachable!("control and config commands don't load workspaces"),
+    }
+}
+
+async fn show_snapshots(config: &Config, workspace: &str) -> bool {
+    let times = match config.snapshot_store(workspace) {
+        Ok(store) => snapshot::list_snapshots(store.as_ref()).await,
+        Err(e) => Err(e),
+    };
+    match times {
+        Ok(times) => {
+            for created_ms in times {
+                println!("{}", snapshot::format_time(created_ms));
+            }
+            true
+        }
+        Err(e) => {
+            println!("Error listing snapshots of {}: {}", workspace, e.report());
+            false
+        }
+    }
+}
 
-    let mut workspace_tree = fstree::FsTree::new();
-    for workspace in config.workspaces() {
-        workspace_tree.insert(&PathBuf::from(&workspace.path), workspace);
+// Files of the workspace that aren't in the snapshot are kept unless deleting them is asked for
+async fn restore_snapshot(
+    config: &Config,
+    workspace: &str,
+    at: Option<&str>,
+    to: Option<&PathBuf>,
+    delete: bool,
+    dry_run: bool,
+) -> Result {
+    let store = config.snapshot_store(workspace)?;
+    let workspace = config
+        .select_workspaces(&[workspace.to_string()])?
+        .remove(0);
+    let at_ms = at
+        .map(|at| snapshot::parse_time(at, snapshot::now_ms()))
+        .transpose()?;
+    let snapshot = snapshot::load_snapshot(store.as_ref(), at_ms)
+        .await?
+        .ok_or_else(|| match at_ms {
+            Some(at_ms) => Error::Config(format!(
+                "no snapshot taken by {}",
+                snapshot::format_time(at_ms)
+            )),
+            None => Error::Config(String::from("no snapshot taken yet")),
+        })?;
+    let (dst, filter) = match to {
+        Some(to) => (to.clone(), None),
+        None => (
+            PathBuf::from(&workspace.path),
+            Some(workspace.filter.as_ref()),
+        ),
+    };
+    let extras = match filter {
+        Some(filter) => snapshot::find_extras(&snapshot, &dst, filter).await?,
+        None => Vec::new(),
+    };
+    if dry_run {
+        let (outdated, kept) = snapshot::find_outdated(&snapshot, &dst, delete).await;
+        for path in outdated {
+            println!("Would write {}", path.display());
+        }
+        for path in kept {
+            println!("Would keep the directory {}", path.display());
+        }
+        let verb = if delete { "delete" } else { "keep" };
+        for path in &extras {
+            println!("Would {} {}", verb, path.display());
+        }
+        return Ok(());
+    }
+    let filter = filter.filter(|_| delete);
+    let (written, kept) = snapshot::restore(store.as_ref(), &snapshot, &dst, filter).await?;
+    for path in &kept {
+        println!(
+            "Kept the directory {}, the snapshot has a file there",
+            path.display()
+        );
+    }
+    println!(
+        "Restored the snapshot taken at {} into {} ({} file(s) written)",
+        snapshot::format_time(snapshot.created_ms),
+        dst.display(),
+        written
+    );
+    if !delete && !extras.is_empty() {
+        println!(
+            "Kept {} path(s) that aren't in the snapshot, restore with --delete to delete them",
+            extras.len()
+        );
     }
+    Ok(())
+}
 
-    let (handler, mut receiver) = notify_tokio::TokioEventHandler::unbounded();
-    let mut watcher = notify::recommended_watcher(handler).unwrap();
+// Sends a request to the running daemon, reporting any error it answers with
+async fn send_request(cli: &Cli, request: &Request) -> Option<Response> {
+    match control::request(&cli.socket_path(), request).await {
+        Ok(Response::Error { message }) => {
+            println!("Error from daemon: {}", message);
+            None
+        }
+        Ok(response) => Some(response),
+        Err(e) => {
+            println!("Error contacting daemon: {}", e.report());
+            None
+        }
+    }
+}
 
-    for workspace in &workspace_tree {
-        println!("Monitoring workspace: {:?}", workspace.path);
-        watcher
-            .watch(workspace.path.as_ref(), RecursiveMode::Recursive)
-            .unwrap();
+// Sends the request for the command to the running daemon, once per selected workspace
+async fn run_control_command(cli: &Cli) -> bool {
+    let workspaces = match cli.workspaces.is_empty() {
+        true => vec![None],
+        false => cli.workspaces.iter().cloned().map(Some).collect(),
+    };
+    for workspace in workspaces {
+        let request = match &cli.command {
+            Command::Resync => Request::Resync { workspace },
+            Command::Pause { syncer } => Request::Pause {
+                workspace,
+                syncer: syncer.clone(),
+            },
+            Command::Resume { syncer } => Request::Resume {
+                workspace,
+                syncer: syncer.clone(),
+            },
+            Command::Reload => Request::Reload,
+            Command::Shutdown => Request::Shutdown,
+            _ => unreachable!("not a control command"),
+        };
+        if send_request(cli, &request).await.is_none() {
+            return false;
+        }
+        // Requests that don't apply to a single workspace are only sent once
+        if matches!(request, Request::Reload | Request::Shutdown) {
+            break;
+        }
     }
+    true
+}
 
-    tokio::spawn(async move {
-        if let Err(e) = tokio::signal::ctrl_c().await {
-            println!("Error awaiting control-c action: {:?}", e)
+// Asks the running daemon for the state of its workspaces
+async fn show_status(cli: &Cli) -> bool {
+    let workspaces = match send_request(cli, &Request::Status).await {
+        Some(Response::Status { workspaces }) => workspaces,
+        Some(response) => {
+            println!("Unexpected response from daemon: {:?}", response);
+            return false;
         }
-        // Needed to close the Sender which will signal to the receiver that there is nothing left
-        drop(watcher);
+        None => return false,
+    };
+    let selected = workspaces.iter().filter(|status| {
+        cli.workspaces.is_empty()
+            || cli
+                .workspaces
+                .iter()
+                .any(|name| &status.workspace == name || Path::new(&status.path) == Path::new(name))
     });
+    for status in selected {
+        println!("{}", status);
+    }
+    true
+}
 
-    while let Some(event) = receiver.recv().await {
-        match event {
-            Ok(event) => {
-                println!("Received event: {:?}", event);
-                let path = event.paths.get(0).unwrap();
-                if let Some(workspace) = workspace_tree.get_closest(path) {
-                    if workspace.should_sync(path.as_path()) {
-                        for syncer in &workspace.syncers {
-                            let thread_syncer = syncer.clone();
-                            let workspace_path = workspace.path.clone();
-                            let file_path = path.clone();
-                            let kind = event.kind.clone();
-                            tokio::spawn(async move {
-                                if let Err(e) = thread_syncer
-                                    .sync(workspace_path.as_ref(), file_path.as_path(), kind)
-                                    .await
-                                {
-                                    println!("Error during sync: {}", e);
-                                }
-                            });
-                        }
-                    }
-                }
-            }
-            Err(e) => println!("Received error event: {:?}", e),
+async fn run_config_command(command: &ConfigCommand, config_path: &str) -> bool {
+    if let ConfigCommand::Path = command {
+        println!("{}", config_path);
+        return true;
+    }
+    let mut config = match Config::parse(config_path).await {
+        Ok(config) => config,
+        Err(Error::Io { source, .. }) if source.kind() == std::io::ErrorKind::NotFound => {
+            Config::default()
+        }
+        Err(e) => {
+            println!("Error loading config at {}: {}", config_path, e.report());
+            return false;
+        }
+    };
+    let result = match command {
+        ConfigCommand::Path => Ok(()),
+        ConfigCommand::List => config
+            .describe()
+            .map(|description| println!("{}", description)),
+        ConfigCommand::AddWorkspace {
+            src_dir,
+            name,
+            ignore,
+        } => config.add_workspace(src_dir, name.as_deref(), ignore),
+        ConfigCommand::RemoveWorkspace { workspace } => config.remove_workspace(workspace),
+        ConfigCommand::AddSyncer {
+            workspace,
+            syncer_type,
+            properties,
+        } => config.add_syncer(workspace, syncer_type, properties),
+        ConfigCommand::SetGlobal { key, value } => config.set_global(key, value),
+    };
+    if let Err(e) = result {
+        println!("{}", e.report());
+        return false;
+    }
+    if let ConfigCommand::List = command {
+        return true;
+    }
+    match config.save(config_path).await {
+        Ok(_) => true,
+        Err(e) => {
+            println!("Error saving config at {}: {}", config_path, e.report());
+            false
         }
     }
-    println!("Exiting...");
 }
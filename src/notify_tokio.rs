@@ -1,5 +1,6 @@
 use notify::{Event, EventHandler};
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tracing::error;
 
 pub struct TokioEventHandler(UnboundedSender<notify::Result<Event>>);
 
@@ -16,9 +17,15 @@ impl TokioEventHandler {
 
 impl EventHandler for TokioEventHandler {
     fn handle_event(&mut self, event: notify::Result<Event>) {
+        // The watcher shuts down in the background, so events can still come in after the
+        // receiver is gone. They are dropped without logging, as logging could itself cause more
+        // events when the logs are written somewhere that is watched
+        if self.0.is_closed() {
+            return;
+        }
         match self.0.send(event) {
             Ok(_) => {}
-            Err(e) => println!("Error sending event: {:?}", e),
+            Err(e) => error!("Error sending event: {:?}", e),
         }
     }
 }
@@ -0,0 +1,170 @@
+use std::time::Duration;
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+const DEFAULT_BASE_DELAY_MS: u64 = 1000;
+const DEFAULT_MAX_DELAY_MS: u64 = 30_000;
+const DEFAULT_JITTER: f64 = 0.2;
+
+#[derive(Debug, PartialEq, Clone, Default, Deserialize, Serialize)]
+pub struct RetryProperties {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_attempts: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    base_delay_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_delay_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    jitter: Option<f64>,
+}
+
+impl RetryProperties {
+    pub fn merge(&self, overrides: Option<&RetryProperties>) -> Self {
+        RetryProperties {
+            max_attempts: overrides.and_then(|o| o.max_attempts).or(self.max_attempts),
+            base_delay_ms: overrides
+                .and_then(|o| o.base_delay_ms)
+                .or(self.base_delay_ms),
+            max_delay_ms: overrides.and_then(|o| o.max_delay_ms).or(self.max_delay_ms),
+            jitter: overrides.and_then(|o| o.jitter).or(self.jitter),
+        }
+    }
+
+    pub fn as_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: self.max_attempts.unwrap_or(DEFAULT_MAX_ATTEMPTS).max(1),
+            base_delay: Duration::from_millis(self.base_delay_ms.unwrap_or(DEFAULT_BASE_DELAY_MS)),
+            max_delay: Duration::from_millis(self.max_delay_ms.unwrap_or(DEFAULT_MAX_DELAY_MS)),
+            jitter: self.jitter.unwrap_or(DEFAULT_JITTER).clamp(0.0, 1.0),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct RetryPolicy {
+    // Total number of attempts, including the first one
+    pub max_attempts: u32,
+    // Delay before the first retry. Doubled for every retry after that
+    pub base_delay: Duration,
+    // Upper bound on the delay between two attempts
+    pub max_delay: Duration,
+    // Fraction of the delay that is randomized, so syncers failing together don't retry together
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryProperties::default().as_policy()
+    }
+}
+
+impl RetryPolicy {
+    // How long to wait before retrying after the given (1-based) attempt failed with the given
+    // error. None means the error should not be retried
+    pub fn next_delay(&self, error: &Error, attempt: u32) -> Option<Duration> {
+        if !error.is_transient() || attempt >= self.max_attempts {
+            return None;
+        }
+        let exponent = (attempt - 1).min(31);
+        let delay = self
+            .base_delay
+            .saturating_mul(1 << exponent)
+            .min(self.max_delay);
+        let jitter = rand::thread_rng().gen_range(0.0..=self.jitter);
+        Some(delay.mul_f64(1.0 - jitter))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::retry::{RetryPolicy, RetryProperties};
+    use crate::rsync::exit_code::RsyncExitCode;
+    use std::time::Duration;
+
+    fn transient() -> Error {
+        Error::Rsync {
+            code: RsyncExitCode::Timeout,
+            stderr: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_retryproperties_merge() {
+        let global = RetryProperties {
+            max_attempts: Some(3),
+            base_delay_ms: Some(100),
+            max_delay_ms: None,
+            jitter: Some(0.5),
+        };
+        let syncer = RetryProperties {
+            max_attempts: Some(10),
+            base_delay_ms: None,
+            max_delay_ms: None,
+            jitter: Some(0.0),
+        };
+        assert_eq!(
+            global.merge(Some(&syncer)).as_policy(),
+            RetryPolicy {
+                max_attempts: 10,
+                base_delay: Duration::from_millis(100),
+                max_delay: Duration::from_secs(30),
+                jitter: 0.0,
+            }
+        );
+        assert_eq!(
+            RetryProperties::default().merge(None).as_policy(),
+            RetryPolicy::default()
+        );
+    }
+
+    #[test]
+    fn delays_grow_exponentially_up_to_the_max() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(500),
+            jitter: 0.0,
+        };
+        let delays = (1..10)
+            .map(|attempt| policy.next_delay(&transient(), attempt))
+            .collect::<Vec<Option<Duration>>>();
+        assert_eq!(delays[0], Some(Duration::from_millis(100)));
+        assert_eq!(delays[1], Some(Duration::from_millis(200)));
+        assert_eq!(delays[2], Some(Duration::from_millis(400)));
+        assert_eq!(delays[3], Some(Duration::from_millis(500)));
+        assert_eq!(policy.next_delay(&transient(), 10), None);
+    }
+
+    #[test]
+    fn jitter_only_shortens_the_delay() {
+        let policy = RetryPolicy {
+            max_attempts: 2,
+            base_delay: Duration::from_millis(1000),
+            max_delay: Duration::from_millis(1000),
+            jitter: 0.5,
+        };
+        for _ in 0..100 {
+            let delay = policy.next_delay(&transient(), 1).unwrap();
+            assert!(delay >= Duration::from_millis(500) && delay <= Duration::from_millis(1000));
+        }
+    }
+
+    #[test]
+    fn permanent_errors_are_not_retried() {
+        let error = Error::Rsync {
+            code: RsyncExitCode::Syntax,
+            stderr: String::new(),
+        };
+        assert_eq!(RetryPolicy::default().next_delay(&error, 1), None);
+        let error = Error::Ssh {
+            host: "host".to_string(),
+            stderr: String::new(),
+        };
+        assert!(RetryPolicy::default().next_delay(&error, 1).is_some());
+    }
+}
@@ -9,6 +9,10 @@ pub enum RsyncFlag {
     Compress,
     Verbose,
     DeleteAfter,
+    From0,
+    DeleteMissingArgs,
+    Stats,
+    ListOnly,
 }
 
 impl RsyncFlag {
@@ -21,6 +25,10 @@ impl RsyncFlag {
             RsyncFlag::Compress => "-z",
             RsyncFlag::Verbose => "-v",
             RsyncFlag::DeleteAfter => "--delete-after",
+            RsyncFlag::From0 => "--from0",
+            RsyncFlag::DeleteMissingArgs => "--delete-missing-args",
+            RsyncFlag::Stats => "--stats",
+            RsyncFlag::ListOnly => "--list-only",
         }
     }
 }
@@ -28,12 +36,18 @@ impl RsyncFlag {
 #[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
 pub enum RsyncOption {
     Exclude(String),
+    FilesFrom(String),
+    Include(String),
+    OutFormat(String),
 }
 
 impl RsyncOption {
     pub fn as_cli_args(&self) -> Vec<String> {
         let (name, value) = match self {
             RsyncOption::Exclude(x) => (String::from("--exclude"), x.clone()),
+            RsyncOption::FilesFrom(x) => (String::from("--files-from"), x.clone()),
+            RsyncOption::Include(x) => (String::from("--include"), x.clone()),
+            RsyncOption::OutFormat(x) => (String::from("--out-format"), x.clone()),
         };
         vec![name, value]
     }
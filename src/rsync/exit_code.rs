@@ -0,0 +1,151 @@
+use std::fmt::{Display, Formatter};
+
+// Exit codes documented in the EXIT VALUES section of the rsync man page
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum RsyncExitCode {
+    Syntax,
+    Protocol,
+    FileSelect,
+    Unsupported,
+    StartClient,
+    LogFileAppend,
+    SocketIo,
+    FileIo,
+    StreamIo,
+    MessageIo,
+    Ipc,
+    Signal,
+    WaitChild,
+    Malloc,
+    Partial,
+    Vanished,
+    DelLimit,
+    Timeout,
+    ConnectionTimeout,
+    // rsync exits with 255 when the remote shell it was started through fails
+    RemoteShell,
+    // The process was killed by a signal and never got to exit with a code
+    Killed,
+    Unknown(i32),
+}
+
+impl RsyncExitCode {
+    pub fn from_code(code: Option<i32>) -> Self {
+        match code {
+            Some(1) => RsyncExitCode::Syntax,
+            Some(2) => RsyncExitCode::Protocol,
+            Some(3) => RsyncExitCode::FileSelect,
+            Some(4) => RsyncExitCode::Unsupported,
+            Some(5) => RsyncExitCode::StartClient,
+            Some(6) => RsyncExitCode::LogFileAppend,
+            Some(10) => RsyncExitCode::SocketIo,
+            Some(11) => RsyncExitCode::FileIo,
+            Some(12) => RsyncExitCode::StreamIo,
+            Some(13) => RsyncExitCode::MessageIo,
+            Some(14) => RsyncExitCode::Ipc,
+            Some(20) => RsyncExitCode::Signal,
+            Some(21) => RsyncExitCode::WaitChild,
+            Some(22) => RsyncExitCode::Malloc,
+            Some(23) => RsyncExitCode::Partial,
+            Some(24) => RsyncExitCode::Vanished,
+            Some(25) => RsyncExitCode::DelLimit,
+            Some(30) => RsyncExitCode::Timeout,
+            Some(35) => RsyncExitCode::ConnectionTimeout,
+            Some(255) => RsyncExitCode::RemoteShell,
+            Some(code) => RsyncExitCode::Unknown(code),
+            None => RsyncExitCode::Killed,
+        }
+    }
+
+    pub fn code(&self) -> Option<i32> {
+        let code = match self {
+            RsyncExitCode::Syntax => 1,
+            RsyncExitCode::Protocol => 2,
+            RsyncExitCode::FileSelect => 3,
+            RsyncExitCode::Unsupported => 4,
+            RsyncExitCode::StartClient => 5,
+            RsyncExitCode::LogFileAppend => 6,
+            RsyncExitCode::SocketIo => 10,
+            RsyncExitCode::FileIo => 11,
+            RsyncExitCode::StreamIo => 12,
+            RsyncExitCode::MessageIo => 13,
+            RsyncExitCode::Ipc => 14,
+            RsyncExitCode::Signal => 20,
+            RsyncExitCode::WaitChild => 21,
+            RsyncExitCode::Malloc => 22,
+            RsyncExitCode::Partial => 23,
+            RsyncExitCode::Vanished => 24,
+            RsyncExitCode::DelLimit => 25,
+            RsyncExitCode::Timeout => 30,
+            RsyncExitCode::ConnectionTimeout => 35,
+            RsyncExitCode::RemoteShell => 255,
+            RsyncExitCode::Unknown(code) => *code,
+            RsyncExitCode::Killed => return None,
+        };
+        Some(code)
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            RsyncExitCode::Syntax => "syntax or usage error",
+            RsyncExitCode::Protocol => "protocol incompatibility",
+            RsyncExitCode::FileSelect => "errors selecting input/output files, dirs",
+            RsyncExitCode::Unsupported => "requested action not supported",
+            RsyncExitCode::StartClient => "error starting client-server protocol",
+            RsyncExitCode::LogFileAppend => "daemon unable to append to log-file",
+            RsyncExitCode::SocketIo => "error in socket I/O",
+            RsyncExitCode::FileIo => "error in file I/O",
+            RsyncExitCode::StreamIo => "error in rsync protocol data stream",
+            RsyncExitCode::MessageIo => "errors with program diagnostics",
+            RsyncExitCode::Ipc => "error in IPC code",
+            RsyncExitCode::Signal => "received SIGUSR1 or SIGINT",
+            RsyncExitCode::WaitChild => "some error returned by waitpid()",
+            RsyncExitCode::Malloc => "error allocating core memory buffers",
+            RsyncExitCode::Partial => "partial transfer due to error",
+            RsyncExitCode::Vanished => "partial transfer due to vanished source files",
+            RsyncExitCode::DelLimit => "the --max-delete limit stopped deletions",
+            RsyncExitCode::Timeout => "timeout in data send/receive",
+            RsyncExitCode::ConnectionTimeout => "timeout waiting for daemon connection",
+            RsyncExitCode::RemoteShell => "remote shell failed",
+            RsyncExitCode::Killed => "killed by a signal",
+            RsyncExitCode::Unknown(_) => "unknown error",
+        }
+    }
+}
+
+impl Display for RsyncExitCode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.code() {
+            Some(code) => write!(f, "{} (exit code {})", self.description(), code),
+            None => write!(f, "{}", self.description()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rsync::exit_code::RsyncExitCode;
+
+    #[test]
+    fn exit_codes_map_to_documented_meanings() {
+        assert_eq!(RsyncExitCode::from_code(Some(23)), RsyncExitCode::Partial);
+        assert_eq!(
+            RsyncExitCode::from_code(Some(255)),
+            RsyncExitCode::RemoteShell
+        );
+        assert_eq!(
+            RsyncExitCode::from_code(Some(99)),
+            RsyncExitCode::Unknown(99)
+        );
+        assert_eq!(RsyncExitCode::from_code(None), RsyncExitCode::Killed);
+        for code in [
+            1, 2, 3, 4, 5, 6, 10, 11, 12, 13, 14, 20, 21, 22, 23, 24, 25, 30, 35, 255,
+        ] {
+            assert_eq!(RsyncExitCode::from_code(Some(code)).code(), Some(code));
+        }
+        assert_eq!(
+            RsyncExitCode::Timeout.to_string(),
+            "timeout in data send/receive (exit code 30)"
+        );
+    }
+}
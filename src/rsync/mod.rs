@@ -1,82 +1,257 @@
 pub mod cli;
+pub mod exit_code;
 pub mod shell;
 
+use crate::error::{Error, Result};
 use crate::rsync::cli::{RsyncFlag, RsyncOption};
+use crate::rsync::exit_code::RsyncExitCode;
 use crate::rsync::shell::ssh::SSHShell;
+use std::path::PathBuf;
 use tokio::process::Command;
+use tracing::{debug, warn};
 
-pub type Result = std::result::Result<(), String>;
+// Which way files go between the local machine and a host
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Push,
+    Pull,
+}
+
+// The host at one end of a transfer. Transfers without one are between local directories
+#[derive(Debug, Clone, Copy)]
+pub struct Remote<'a> {
+    pub host: &'a str,
+    pub user: Option<&'a str>,
+    pub direction: Direction,
+}
+
+impl<'a> Remote<'a> {
+    pub fn new(host: &'a str, direction: Direction) -> Self {
+        Remote {
+            host,
+            user: None,
+            direction,
+        }
+    }
+
+    fn location(&self, path: &str) -> String {
+        match self.user {
+            Some(user) => format!("{}@{}:{}", user, self.host, path),
+            None => format!("{}:{}", self.host, path),
+        }
+    }
+}
 
 fn command(
     src: &str,
     dst: &str,
-    dst_host: Option<&str>,
-    dst_host_user: Option<&str>,
+    remote: Option<Remote>,
     shell: Option<&SSHShell>,
     flags: &[RsyncFlag],
     options: &[RsyncOption],
 ) -> Command {
     let mut cmd = Command::new("rsync");
+    // A sync still running when the daemon exits is interrupted rather than left behind
+    cmd.kill_on_drop(true);
     // Add rsync options
     for flag in flags {
         cmd.arg(flag.as_cli_arg());
     }
-    if shell.is_some() {
+    if let Some(shell) = shell {
         cmd.arg("-e");
-        cmd.arg(shell.as_ref().unwrap().as_arg());
+        cmd.arg(shell.as_arg());
     }
     for opt in options {
         cmd.args(opt.as_cli_args());
     }
-    cmd.arg(src);
-    let host = vec![dst_host_user, dst_host]
-        .into_iter()
-        .flatten()
-        .collect::<Vec<&str>>()
-        .join("@");
-    if host.is_empty() {
-        cmd.arg(dst);
-    } else {
-        cmd.arg(format!("{}:{}", host, dst));
+    match remote {
+        Some(remote) if remote.direction == Direction::Pull => {
+            cmd.arg(remote.location(src));
+            cmd.arg(dst);
+        }
+        Some(remote) => {
+            cmd.arg(src);
+            cmd.arg(remote.location(dst));
+        }
+        None => {
+            cmd.arg(src);
+            cmd.arg(dst);
+        }
     }
     cmd
 }
 
-// TODO: the result here should not be a syncer result
 pub async fn rsync(
     src: &str,
     dst: &str,
-    dst_host: Option<&str>,
-    dst_host_usr: Option<&str>,
+    remote: Option<Remote<'_>>,
     shell: Option<&SSHShell>,
     flags: &[RsyncFlag],
     options: &[RsyncOption],
-) -> Result {
-    let mut cmd = command(src, dst, dst_host, dst_host_usr, shell, flags, options);
-    println!("Running: '{:?}'", cmd);
-    match cmd.output().await {
-        Err(e) => Err(format!("Error running command: {:?}", e)),
-        Ok(output) => {
-            if !output.status.success() {
-                let stderr = String::from_utf8(output.stderr)
-                    .or::<std::result::Result<String, ()>>(Ok("unable to decode stderr".to_owned()))
-                    .unwrap();
-                return Err(format!(
-                    "Error Status: {}, StdErr:\n{}",
-                    output.status, stderr
-                ));
-            }
-            if !output.stderr.is_empty() {
-                println!("---------- rsync stderr ----------");
-                print!("{}", String::from_utf8(output.stderr).unwrap());
-                println!("----------------------------------");
-            }
-            if !output.stdout.is_empty() {
-                println!("---------- rsync stdout ----------");
-                print!("{}", String::from_utf8(output.stdout).unwrap());
-                println!("----------------------------------");
-            }
-            Ok(())
+) -> Result<String> {
+    let mut cmd = command(src, dst, remote, shell, flags, options);
+    debug!("Running: '{:?}'", cmd);
+    let output = cmd.output().await.map_err(|source| Error::Spawn {
+        program: "rsync".to_string(),
+        source,
+    })?;
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    if !output.status.success() {
+        let code = RsyncExitCode::from_code(output.status.code());
+        return Err(match (code, remote) {
+            (RsyncExitCode::RemoteShell, Some(remote)) => Error::Ssh {
+                host: remote.host.to_string(),
+                stderr,
+            },
+            _ => Error::Rsync { code, stderr },
+        });
+    }
+    if !stderr.is_empty() {
+        warn!(stderr = %stderr.trim_end(), "rsync reported errors");
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    if !stdout.is_empty() {
+        debug!(stdout = %stdout.trim_end(), "rsync output");
+    }
+    Ok(stdout)
+}
+
+// Has rsync print a line for every file it creates, updates or deletes, deletions included
+pub const TRANSFERRED_FORMAT: &str = "transferred: %n";
+
+// Files (relative to the root of the transfer) reported by a run with --out-format set to
+// TRANSFERRED_FORMAT. Directories are listed with a trailing slash, the root itself as ./
+pub fn transferred_files(output: &str) -> Vec<PathBuf> {
+    output
+        .lines()
+        .filter_map(|line| line.strip_prefix("transferred: "))
+        .map(|name| name.trim_end_matches('/'))
+        .filter(|name| !name.is_empty() && *name != ".")
+        .map(PathBuf::from)
+        .collect()
+}
+
+// Total number of bytes sent and received over the wire, taken from the output of --stats
+pub fn bytes_transferred(output: &str) -> Option<u64> {
+    let mut total = None;
+    for line in output.lines() {
+        let value = match line
+            .strip_prefix("Total bytes sent:")
+            .or_else(|| line.strip_prefix("Total bytes received:"))
+        {
+            Some(value) => value,
+            None => continue,
+        };
+        // Newer versions of rsync group digits with commas and may add a unit
+        let digits = value
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .replace(',', "");
+        if let Ok(bytes) = digits.parse::<u64>() {
+            total = Some(total.unwrap_or(0) + bytes);
         }
     }
+    total
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rsync::cli::{RsyncFlag, RsyncOption};
+    use crate::rsync::{bytes_transferred, command, transferred_files, Direction, Remote};
+    use std::path::PathBuf;
+
+    #[test]
+    fn command_places_options_before_src_and_dst() {
+        let remote = Remote {
+            host: "host",
+            user: Some("user"),
+            direction: Direction::Push,
+        };
+        let cmd = command(
+            "/local/dir/",
+            "/remote/dir",
+            Some(remote),
+            None,
+            &[RsyncFlag::Recursive, RsyncFlag::From0],
+            &[
+                RsyncOption::Exclude("build".to_string()),
+                RsyncOption::FilesFrom("/tmp/file-list".to_string()),
+            ],
+        );
+        let args = cmd
+            .as_std()
+            .get_args()
+            .map(|arg| arg.to_str().unwrap())
+            .collect::<Vec<&str>>();
+        assert_eq!(
+            args,
+            vec![
+                "-r",
+                "--from0",
+                "--exclude",
+                "build",
+                "--files-from",
+                "/tmp/file-list",
+                "/local/dir/",
+                "user@host:/remote/dir"
+            ]
+        );
+    }
+
+    #[test]
+    fn command_pulls_from_the_host() {
+        let cmd = command(
+            "/remote/dir/",
+            "/local/dir/",
+            Some(Remote::new("host", Direction::Pull)),
+            None,
+            &[RsyncFlag::Recursive],
+            &[],
+        );
+        let args = cmd
+            .as_std()
+            .get_args()
+            .map(|arg| arg.to_str().unwrap())
+            .collect::<Vec<&str>>();
+        assert_eq!(args, vec!["-r", "host:/remote/dir/", "/local/dir/"]);
+    }
+
+    #[test]
+    fn bytes_transferred_is_parsed_from_stats() {
+        let output = "sending incremental file list
+file1
+
+Number of files: 3 (reg: 2, dir: 1)
+Total file size: 12,345 bytes
+Total transferred file size: 12,345 bytes
+Total bytes sent: 1,237
+Total bytes received: 57
+
+sent 1,237 bytes  received 57 bytes  2,588.00 bytes/sec
+";
+        assert_eq!(bytes_transferred(output), Some(1294));
+        assert_eq!(bytes_transferred("Total bytes sent: 10\n"), Some(10));
+        assert_eq!(bytes_transferred("file1\n"), None);
+    }
+
+    #[test]
+    fn transferred_files_are_parsed_from_the_output() {
+        let output = "receiving incremental file list
+transferred: ./
+transferred: gen/
+transferred: gen/code.rs
+transferred: old.txt
+
+Total bytes received: 57
+";
+        assert_eq!(
+            transferred_files(output),
+            vec![
+                PathBuf::from("gen"),
+                PathBuf::from("gen/code.rs"),
+                PathBuf::from("old.txt")
+            ]
+        );
+    }
 }
@@ -1,4 +1,8 @@
+use std::path::Path;
+
 use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+use tracing::{debug, warn};
 
 #[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
 pub enum SSHOption {
@@ -13,47 +17,43 @@ pub enum SSHOption {
 }
 
 impl SSHOption {
-    fn as_cli_opt(&self) -> String {
+    fn as_cli_args(&self) -> [String; 2] {
+        let option = |value| [String::from("-o"), value];
         match self {
-            SSHOption::PasswordAuthentication(v) => {
-                format!("-o PasswordAuthentication={}", v.to_string())
-            }
-            SSHOption::ServerAliveInterval(v) => {
-                format!("-o ServerAliveInterval={}", v.to_string())
-            }
-            SSHOption::ServerAliveCountMax(v) => {
-                format!("-o ServerAliveCountMax={}", v.to_string())
-            }
-            SSHOption::ConnectTimeout(v) => format!("-o ConnectTimeout={}", v.to_string()),
-            SSHOption::ControlMaster(v) => format!("-o ControlMaster={}", v.to_string()),
-            SSHOption::ControlPersist(v) => format!("-o ControlPersist={}", v.to_string()),
-            SSHOption::ControlPath(v) => format!("-o ControlPath={}", v.to_string()),
-            SSHOption::IdentityFile(v) => format!("-i {}", v.to_string()),
+            SSHOption::PasswordAuthentication(v) => option(format!("PasswordAuthentication={}", v)),
+            SSHOption::ServerAliveInterval(v) => option(format!("ServerAliveInterval={}", v)),
+            SSHOption::ServerAliveCountMax(v) => option(format!("ServerAliveCountMax={}", v)),
+            SSHOption::ConnectTimeout(v) => option(format!("ConnectTimeout={}", v)),
+            SSHOption::ControlMaster(v) => option(format!("ControlMaster={}", v)),
+            SSHOption::ControlPersist(v) => option(format!("ControlPersist={}", v)),
+            SSHOption::ControlPath(v) => option(format!("ControlPath={}", v)),
+            SSHOption::IdentityFile(v) => [String::from("-i"), v.clone()],
         }
     }
-}
 
-impl Drop for SSHOption {
-    fn drop(&mut self) {
-        // Not sure if performing I/O in the drop method is a great idea, but
-        // this is serving as a good way for me to test and experiment with drop
-        if let SSHOption::ControlPath(x) = self {
-            if let Err(e) = std::fs::remove_file(x) {
-                if e.kind() != std::io::ErrorKind::NotFound {
-                    println!("Error closing ssh session control path: {:?}", e);
-                }
-            }
-        }
+    fn as_cli_opt(&self) -> String {
+        self.as_cli_args().join(" ")
     }
 }
 
 pub struct SSHShell {
     pub options: Vec<SSHOption>,
+    // Whether the control path was generated for this shell alone, rather than given in the
+    // config and possibly shared with other ssh sessions
+    owns_control_path: bool,
 }
 
 impl SSHShell {
     pub fn new(options: Vec<SSHOption>) -> Self {
-        SSHShell { options }
+        SSHShell {
+            options,
+            owns_control_path: false,
+        }
+    }
+
+    pub fn with_generated_control_path(mut self) -> Self {
+        self.owns_control_path = true;
+        self
     }
 }
 
@@ -63,43 +63,142 @@ impl SSHShell {
             .options
             .iter()
             .map(|opt| opt.as_cli_opt())
+            .collect::<Vec<String>>();
+        ["ssh".to_string()]
+            .into_iter()
+            .chain(opts)
             .collect::<Vec<String>>()
-            .join(" -o ");
-        format!("ssh -o {}", opts)
+            .join(" ")
+    }
+
+    // Runs a command on the host through ssh, with the same options rsync would use. The command
+    // is run by the login shell of the remote user
+    pub fn command(&self, host: &str, remote_command: &str) -> Command {
+        let mut cmd = Command::new("ssh");
+        // A command still running when the daemon exits is interrupted rather than left behind
+        cmd.kill_on_drop(true);
+        cmd.args(self.options.iter().flat_map(SSHOption::as_cli_args));
+        cmd.arg(host);
+        cmd.arg(remote_command);
+        cmd
+    }
+
+    fn control_path(&self) -> Option<&str> {
+        self.options.iter().find_map(|opt| match opt {
+            SSHOption::ControlPath(path) => Some(path.as_str()),
+            _ => None,
+        })
+    }
+
+    // Tells the master connection shared through the control path, if any, to exit and removes
+    // the control path. Meant to be called once the shell won't be used anymore. Control paths
+    // given in the config are left alone, since other sessions may still be using their master
+    pub async fn close(&self, host: Option<&str>) {
+        let control_path = match self.control_path() {
+            Some(control_path) if self.owns_control_path => control_path,
+            _ => return,
+        };
+        if !Path::new(control_path).exists() {
+            return;
+        }
+        if let Some(host) = host {
+            let exit = Command::new("ssh")
+                .args(["-o", &format!("ControlPath={}", control_path)])
+                .args(["-O", "exit", host])
+                .output()
+                .await;
+            match exit {
+                Ok(output) if output.status.success() => {
+                    debug!(host, "Closed ssh master connection")
+                }
+                Ok(output) => debug!(
+                    host,
+                    stderr = %String::from_utf8_lossy(&output.stderr).trim_end(),
+                    "Unable to close ssh master connection"
+                ),
+                Err(e) => debug!(
+                    host,
+                    "Unable to run ssh to close master connection: {:?}", e
+                ),
+            }
+        }
+        // The master normally removes it when exiting, unless it was no longer running
+        if let Err(e) = std::fs::remove_file(control_path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                warn!("Error closing ssh session control path: {:?}", e);
+            }
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::rsync::shell::ssh::SSHOption;
-    use std::time::{SystemTime, UNIX_EPOCH};
+    use crate::rsync::shell::ssh::{SSHOption, SSHShell};
 
     #[test]
-    fn control_path_option_cleans_up_session_file_when_dropped() {
-        let epoch_millis = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
+    fn options_are_passed_to_ssh() {
+        let shell = SSHShell::new(vec![
+            SSHOption::ConnectTimeout(20),
+            SSHOption::IdentityFile(".ssh/secret-pem".to_string()),
+            SSHOption::ControlMaster("auto".to_string()),
+        ]);
+        assert_eq!(
+            shell.as_arg(),
+            "ssh -o ConnectTimeout=20 -i .ssh/secret-pem -o ControlMaster=auto"
+        );
+        assert_eq!(SSHShell::new(vec![]).as_arg(), "ssh");
+        let cmd = shell.command("host", "ls /tmp");
+        let args = cmd
+            .as_std()
+            .get_args()
+            .map(|arg| arg.to_str().unwrap())
+            .collect::<Vec<&str>>();
+        assert_eq!(
+            args,
+            vec![
+                "-o",
+                "ConnectTimeout=20",
+                "-i",
+                ".ssh/secret-pem",
+                "-o",
+                "ControlMaster=auto",
+                "host",
+                "ls /tmp"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn closing_the_shell_cleans_up_the_control_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir
+            .path()
+            .join("control-path")
+            .to_str()
             .unwrap()
-            .as_millis();
-        let path = format!("/tmp/rust-sync-control-path-test-{}", epoch_millis);
-        let path_clone_1 = path.clone();
-        let path_clone_2 = path.clone();
+            .to_string();
         if let Err(e) = std::fs::OpenOptions::new()
             .create(true)
+            .truncate(true)
             .write(true)
-            .open(path)
+            .open(&path)
         {
             panic!("Error creating path for test: {:?}", e);
         }
 
-        {
-            let cp = SSHOption::ControlPath(path_clone_1);
-            println!("CP: {:?}", cp);
-        }
+        let shell = SSHShell::new(vec![SSHOption::ControlPath(path.clone())]);
+        shell.close(None).await;
+        assert!(
+            std::fs::metadata(&path).is_ok(),
+            "Closing should leave a control path given in the config alone"
+        );
 
-        match std::fs::metadata(path_clone_2) {
+        let shell = shell.with_generated_control_path();
+        shell.close(None).await;
+        match std::fs::metadata(&path) {
             Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
             Err(e) => panic!("Error stating control path file: {:?}", e),
-            Ok(_) => panic!("File should not exist after SSHOption::ControlPath was dropped"),
+            Ok(_) => panic!("File should not exist after the shell was closed"),
         }
     }
 }
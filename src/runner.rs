@@ -0,0 +1,392 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use notify::EventKind;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::{info, info_span, warn, Instrument, Span};
+
+use crate::config::Workspace;
+use crate::debounce::Debouncer;
+use crate::executor::{PauseReason, SyncExecutor};
+use crate::status::WorkspaceStatus;
+use crate::syncers::Changes;
+
+// Creating this file in the root of a workspace pauses all of its syncers until it is removed
+const PAUSE_MARKER: &str = ".rust-dev-sync-pause";
+
+// The pauses requested for a workspace, carried over when it is restarted with a new config
+pub enum RequestedPauses {
+    // Also covers syncers the new config adds
+    All,
+    Syncers(Vec<String>),
+}
+
+// Owns the event pipeline for a single workspace: file events are filtered, debounced into
+// batches and each batch is then handed to the executor of every syncer configured for the workspace
+pub struct WorkspaceRunner {
+    pub workspace: Arc<Workspace>,
+    debouncer: Debouncer,
+    dispatch: JoinHandle<()>,
+    // Syncs the whole workspace periodically, for syncers asking for it
+    polls: Vec<JoinHandle<()>>,
+    executors: Vec<Arc<SyncExecutor>>,
+    pause_marker: PathBuf,
+    paused_by_marker: AtomicBool,
+    span: Span,
+}
+
+impl WorkspaceRunner {
+    pub fn start(workspace: Workspace) -> Self {
+        let workspace = Arc::new(workspace);
+        let span = info_span!("workspace", workspace = %workspace.name);
+        // Executors pick up the workspace span as the parent of their own
+        let executors = span.in_scope(|| {
+            workspace
+                .syncers
+                .iter()
+                .map(|configured| {
+                    SyncExecutor::new(&workspace.path, configured.syncer.clone(), configured.retry)
+                })
+                .collect::<Vec<Arc<SyncExecutor>>>()
+        });
+        let (debouncer, mut batches) = Debouncer::spawn(workspace.debounce);
+        let dispatch_executors = executors
+            .iter()
+            .zip(&workspace.syncers)
+            .filter(|(_, configured)| configured.syncer.syncs_changes())
+            .map(|(executor, _)| executor.clone())
+            .collect::<Vec<Arc<SyncExecutor>>>();
+        let suppressor = workspace.suppressor.clone();
+        let filter = workspace.filter.clone();
+        let dispatch = tokio::spawn(
+            async move {
+                while let Some(mut changes) = batches.recv().await {
+                    // Changes made by the syncers themselves are left out, which can only be
+                    // told once they are done writing
+                    suppressor.wait_for_writers(&changes).await;
+                    changes.retain(|path| !suppressor.was_written(path));
+                    // Files the previous rules ignored may have changed without being synced, so
+                    // the whole workspace is synced again with the new rules
+                    if filter.has_stale_ignore_files() {
+                        let reloaded = filter.clone();
+                        let _ = tokio::task::spawn_blocking(move || reloaded.reload_ignore_files())
+                            .await;
+                        info!("Reloaded ignore files");
+                        changes = Changes::full();
+                    }
+                    if changes.is_empty() && !changes.is_full() {
+                        continue;
+                    }
+                    info!(changes = changes.len(), "Syncing changes");
+                    for executor in &dispatch_executors {
+                        executor.submit(changes.clone());
+                    }
+                }
+            }
+            .instrument(span.clone()),
+        );
+        let polls = executors
+            .iter()
+            .zip(&workspace.syncers)
+            .filter_map(|(executor, configured)| {
+                let period = configured.syncer.poll_interval()?;
+                let executor = executor.clone();
+                Some(tokio::spawn(
+                    async move {
+                        // The first tick is immediate, the initial sync takes care of that
+                        let mut interval = tokio::time::interval(period);
+                        interval.tick().await;
+                        loop {
+                            interval.tick().await;
+                            executor.submit(Changes::full());
+                        }
+                    }
+                    .instrument(span.clone()),
+                ))
+            })
+            .collect();
+        let runner = WorkspaceRunner {
+            pause_marker: Path::new(&workspace.path).join(PAUSE_MARKER),
+            workspace,
+            debouncer,
+            dispatch,
+            polls,
+            executors,
+            paused_by_marker: AtomicBool::new(false),
+            span,
+        };
+        // Checked before anything is submitted, so that not even the initial sync goes through
+        runner.check_pause_marker();
+        runner
+    }
+
+    pub fn handle_change(&self, path: PathBuf, kind: EventKind) {
+        if path == self.pause_marker {
+            self.check_pause_marker();
+            return;
+        }
+        if !matches!(kind, EventKind::Access(_)) && self.workspace.filter.is_ignore_file(&path) {
+            self.ignore_files_changed(path, kind);
+            return;
+        }
+        if self.workspace.should_sync(path.as_path()) {
+            self.debouncer.push(path, kind);
+        }
+    }
+
+    pub fn status(&self) -> WorkspaceStatus {
+        WorkspaceStatus {
+            workspace: self.workspace.name.clone(),
+            path: self.workspace.path.clone(),
+            syncers: self
+                .executors
+                .iter()
+                .map(|executor| executor.status())
+                .collect(),
+        }
+    }
+
+    // Pauses every syncer of the workspace, or only the one with the given name. Returns false if
+    // the workspace has no syncer with that name
+    pub fn pause(&self, syncer: Option<&str>) -> bool {
+        let executors = self.executors_named(syncer);
+        executors
+            .iter()
+            .for_each(|executor| executor.pause(PauseReason::Requested));
+        !executors.is_empty()
+    }
+
+    pub fn resume(&self, syncer: Option<&str>) -> bool {
+        let executors = self.executors_named(syncer);
+        executors
+            .iter()
+            .for_each(|executor| executor.resume(PauseReason::Requested));
+        !executors.is_empty()
+    }
+
+    pub fn requested_pauses(&self) -> RequestedPauses {
+        let paused: Vec<String> = self
+            .executors
+            .iter()
+            .filter(|executor| executor.is_paused_on_request())
+            .map(|executor| executor.name())
+            .collect();
+        match !paused.is_empty() && paused.len() == self.executors.len() {
+            true => RequestedPauses::All,
+            false => RequestedPauses::Syncers(paused),
+        }
+    }
+
+    // Syncers the workspace no longer has are skipped
+    pub fn restore_pauses(&self, pauses: &RequestedPauses) {
+        match pauses {
+            RequestedPauses::All => {
+                self.pause(None);
+            }
+            RequestedPauses::Syncers(syncers) => {
+                for syncer in syncers {
+                    self.pause(Some(syncer));
+                }
+            }
+        }
+    }
+
+    // Flushes the changes still being debounced and waits, at most until the deadline, for them
+    // and any sync already running to be synced. The syncers are closed afterwards
+    pub async fn stop(self, deadline: Instant) {
+        let span = self.span.clone();
+        async move {
+            drop(self.debouncer);
+            self.polls.iter().for_each(|poll| poll.abort());
+            let executors = self.executors;
+            let dispatch = self.dispatch;
+            let finished = tokio::time::timeout_at(deadline, async {
+                let _ = dispatch.await;
+                for executor in &executors {
+                    executor.wait_idle().await;
+                }
+            })
+            .await;
+            match finished {
+                Ok(_) => info!("Stopped workspace"),
+                Err(_) => warn!("Timed out waiting for syncs to finish"),
+            }
+            for executor in executors.iter().filter(|executor| executor.has_pending()) {
+                warn!(syncer = %executor.name(), "Dropping changes that were never synced");
+            }
+            for configured in &self.workspace.syncers {
+                configured.syncer.close().await;
+            }
+        }
+        .instrument(span)
+        .await
+    }
+
+    // Whichever way the marker came or went (created, deleted, renamed), only its presence matters
+    fn check_pause_marker(&self) {
+        let present = self.pause_marker.exists();
+        if self.paused_by_marker.swap(present, Ordering::SeqCst) == present {
+            return;
+        }
+        self.span.in_scope(|| match present {
+            true => info!("Pause marker found"),
+            false => info!("Pause marker removed"),
+        });
+        // Kept apart from pauses made on request, which the marker going away doesn't lift
+        for executor in &self.executors {
+            match present {
+                true => executor.pause(PauseReason::Marker),
+                false => executor.resume(PauseReason::Marker),
+            }
+        }
+    }
+
+    fn executors_named(&self, syncer: Option<&str>) -> Vec<&Arc<SyncExecutor>> {
+        self.executors
+            .iter()
+            .filter(|executor| syncer.is_none_or(|name| executor.name() == name))
+            .collect()
+    }
+
+    // Syncs the whole workspace through every syncer, without waiting for file events
+    pub fn sync_all(&self) {
+        self.span.in_scope(|| info!("Syncing all of workspace"));
+        for executor in &self.executors {
+            executor.submit(Changes::full());
+        }
+    }
+
+    // The ignore files are reloaded along with the next batch of changes, so that many of them
+    // changing at once (e.g. on a git checkout) only go through the workspace once
+    pub fn ignore_files_changed(&self, path: PathBuf, kind: EventKind) {
+        if self.workspace.filter.mark_ignore_files_stale() {
+            self.debouncer.push(path, kind);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Config, ConfiguredSyncer, Workspace};
+    use crate::debounce::DebounceSettings;
+    use crate::retry::RetryPolicy;
+    use crate::runner::{RequestedPauses, WorkspaceRunner, PAUSE_MARKER};
+    use crate::syncers::{Changes, Result as SyncerResult, SyncStats, Syncer};
+    use async_trait::async_trait;
+    use notify::event::{CreateKind, ModifyKind, RemoveKind};
+    use notify::EventKind;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+
+    // Reports the changes each sync was given
+    struct CountingSyncer {
+        finished: UnboundedSender<Changes>,
+    }
+
+    #[async_trait]
+    impl Syncer for CountingSyncer {
+        fn name(&self) -> String {
+            String::from("counting")
+        }
+
+        async fn sync(&self, _workspace_path: &Path, changes: &Changes) -> SyncerResult {
+            self.finished.send(changes.clone()).unwrap();
+            Ok(SyncStats::default())
+        }
+    }
+
+    // A workspace of root syncing through a CountingSyncer, with a short debounce
+    async fn counting_workspace(
+        root: &Path,
+        settings: &str,
+    ) -> (Workspace, UnboundedReceiver<Changes>) {
+        let config_path = root.join("config.yaml");
+        std::fs::write(
+            &config_path,
+            format!(
+                "workspaces:\n  - src_dir: {}\n    syncers:\n      - type: local\n        dst_dir: /unused\n{}",
+                root.display(),
+                settings
+            ),
+        )
+        .unwrap();
+        let config = Config::parse(config_path.to_str().unwrap()).await.unwrap();
+        let mut workspace = config.workspaces().unwrap().remove(0);
+        let (sender, finished) = tokio::sync::mpsc::unbounded_channel();
+        workspace.syncers = vec![ConfiguredSyncer {
+            syncer: Arc::new(Box::pin(CountingSyncer { finished: sender })),
+            retry: RetryPolicy::default(),
+        }];
+        workspace.debounce = DebounceSettings {
+            quiet_period: Duration::from_millis(10),
+            max_latency: Duration::from_millis(50),
+        };
+        (workspace, finished)
+    }
+
+    #[tokio::test]
+    async fn changes_are_held_until_the_pause_marker_is_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let (workspace, mut finished) = counting_workspace(&root, "").await;
+        let marker = root.join(PAUSE_MARKER);
+        std::fs::write(&marker, "").unwrap();
+        let runner = WorkspaceRunner::start(workspace);
+        assert!(runner.status().syncers[0].paused);
+
+        for name in ["file1", "file2"] {
+            std::fs::write(root.join(name), name).unwrap();
+            runner.handle_change(root.join(name), EventKind::Modify(ModifyKind::Any));
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        // Resuming a pause that wasn't requested leaves the marker's in place
+        runner.resume(None);
+        let held = tokio::time::timeout(Duration::from_millis(100), finished.recv()).await;
+        assert!(held.is_err());
+        assert!(runner.status().syncers[0].paused);
+
+        std::fs::remove_file(&marker).unwrap();
+        runner.handle_change(marker.clone(), EventKind::Remove(RemoveKind::File));
+        assert_eq!(finished.recv().await.unwrap().len(), 2);
+        assert!(!runner.status().syncers[0].paused);
+
+        // Nor does the marker going away lift a pause that was requested
+        runner.pause(None);
+        std::fs::write(&marker, "").unwrap();
+        runner.handle_change(marker.clone(), EventKind::Create(CreateKind::File));
+        std::fs::remove_file(&marker).unwrap();
+        runner.handle_change(marker, EventKind::Remove(RemoveKind::File));
+        assert!(runner.status().syncers[0].paused);
+        assert!(matches!(runner.requested_pauses(), RequestedPauses::All));
+    }
+
+    #[tokio::test]
+    async fn ignore_files_are_reloaded_once_per_batch() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        std::fs::write(root.join(".gitignore"), "*.log\n").unwrap();
+        let (workspace, mut finished) =
+            counting_workspace(&root, "    respect_ignore_files: true\n").await;
+        let filter = workspace.filter.clone();
+        let runner = WorkspaceRunner::start(workspace);
+        assert!(!filter.should_sync(&root.join("app.log")));
+
+        for dir in ["", "sub1", "sub2"] {
+            std::fs::create_dir_all(root.join(dir)).unwrap();
+            std::fs::write(root.join(dir).join(".gitignore"), "*.tmp\n").unwrap();
+            let path = root.join(dir).join(".gitignore");
+            runner.handle_change(path, EventKind::Modify(ModifyKind::Any));
+        }
+        // Nothing is reloaded until the batch is dispatched
+        assert!(!filter.should_sync(&root.join("app.log")));
+        assert!(finished.recv().await.unwrap().is_full());
+        assert!(filter.should_sync(&root.join("app.log")));
+        assert!(!filter.should_sync(&root.join("sub1/app.tmp")));
+        let no_more_runs = tokio::time::timeout(Duration::from_millis(100), finished.recv()).await;
+        assert!(no_more_runs.is_err());
+    }
+}
@@ -0,0 +1,161 @@
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+use crate::syncers::SyncStats;
+
+#[derive(Debug, PartialEq, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncState {
+    Idle,
+    Syncing,
+    Failed,
+}
+
+// State of a single syncer of a workspace, as tracked by the daemon. Timestamps are in seconds
+// since the unix epoch
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+pub struct SyncerStatus {
+    pub syncer: String,
+    pub state: SyncState,
+    pub paused: bool,
+    pub last_success: Option<u64>,
+    pub last_error: Option<String>,
+    pub last_duration_ms: Option<u64>,
+    pub last_bytes_transferred: Option<u64>,
+}
+
+impl SyncerStatus {
+    pub fn new(syncer: String) -> Self {
+        SyncerStatus {
+            syncer,
+            state: SyncState::Idle,
+            paused: false,
+            last_success: None,
+            last_error: None,
+            last_duration_ms: None,
+            last_bytes_transferred: None,
+        }
+    }
+
+    pub fn started(&mut self) {
+        self.state = SyncState::Syncing;
+    }
+
+    pub fn succeeded(&mut self, stats: &SyncStats, duration: Duration) {
+        self.state = SyncState::Idle;
+        self.last_success = Some(unix_now());
+        self.last_error = None;
+        self.last_duration_ms = Some(duration.as_millis() as u64);
+        self.last_bytes_transferred = stats.bytes_transferred;
+    }
+
+    pub fn failed(&mut self, error: &Error, duration: Duration) {
+        self.state = SyncState::Failed;
+        self.last_error = Some(error.report());
+        self.last_duration_ms = Some(duration.as_millis() as u64);
+        self.last_bytes_transferred = None;
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+pub struct WorkspaceStatus {
+    pub workspace: String,
+    pub path: String,
+    pub syncers: Vec<SyncerStatus>,
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or_default()
+}
+
+impl Display for SyncState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let state = match self {
+            SyncState::Idle => "idle",
+            SyncState::Syncing => "syncing",
+            SyncState::Failed => "failed",
+        };
+        write!(f, "{}", state)
+    }
+}
+
+impl Display for WorkspaceStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.workspace, self.path)?;
+        for syncer in &self.syncers {
+            write!(f, "\n  - {}: {}", syncer.syncer, syncer.state)?;
+            if syncer.paused {
+                write!(f, " (paused)")?;
+            }
+            match syncer.last_success {
+                Some(last_success) => write!(
+                    f,
+                    ", last synced {}s ago",
+                    unix_now().saturating_sub(last_success)
+                )?,
+                None => write!(f, ", never synced")?,
+            }
+            if let Some(duration) = syncer.last_duration_ms {
+                write!(f, ", took {}ms", duration)?;
+            }
+            if let Some(bytes) = syncer.last_bytes_transferred {
+                write!(f, ", {} bytes transferred", bytes)?;
+            }
+            if let Some(error) = syncer.last_error.as_ref() {
+                write!(f, "\n    last error: {}", error)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::status::{SyncState, SyncerStatus, WorkspaceStatus};
+    use crate::syncers::SyncStats;
+    use std::time::Duration;
+
+    #[test]
+    fn status_tracks_the_last_sync() {
+        let mut status = SyncerStatus::new("rsync:/remote".to_string());
+        status.started();
+        assert_eq!(status.state, SyncState::Syncing);
+        status.failed(
+            &Error::Config("broken".to_string()),
+            Duration::from_millis(5),
+        );
+        assert_eq!(status.state, SyncState::Failed);
+        assert_eq!(status.last_error.as_deref(), Some("broken"));
+        assert_eq!(status.last_success, None);
+
+        status.started();
+        status.succeeded(
+            &SyncStats {
+                bytes_transferred: Some(1024),
+            },
+            Duration::from_millis(20),
+        );
+        assert_eq!(status.state, SyncState::Idle);
+        assert_eq!(status.last_error, None);
+        assert!(status.last_success.is_some());
+        assert_eq!(status.last_duration_ms, Some(20));
+        assert_eq!(status.last_bytes_transferred, Some(1024));
+
+        let workspace = WorkspaceStatus {
+            workspace: "project".to_string(),
+            path: "/tmp/project/".to_string(),
+            syncers: vec![status],
+        };
+        assert_eq!(
+            workspace.to_string(),
+            "project (/tmp/project/)\n  - rsync:/remote: idle, last synced 0s ago, took 20ms, 1024 bytes transferred"
+        );
+    }
+}
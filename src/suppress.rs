@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use tokio::sync::watch;
+
+use crate::syncers::Changes;
+
+// How long files written by a syncer are remembered. Their events are long delivered by then
+const WRITTEN_EXPIRY: Duration = Duration::from_secs(60);
+
+// What a file looked like right after a syncer wrote it
+#[derive(Debug, PartialEq)]
+enum Stamp {
+    Missing,
+    Dir,
+    File {
+        len: u64,
+        modified: Option<SystemTime>,
+    },
+}
+
+impl Stamp {
+    // Directory times change whenever their content does, so only their presence is compared
+    fn of(path: &Path) -> Self {
+        match std::fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.is_dir() => Stamp::Dir,
+            Ok(metadata) => Stamp::File {
+                len: metadata.len(),
+                modified: metadata.modified().ok(),
+            },
+            Err(_) => Stamp::Missing,
+        }
+    }
+}
+
+// rsync writes each file to a temporary `.<name>.XXXXXX` next to it before renaming it into place
+fn is_temp_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_prefix('.'))
+        .and_then(|name| name.rsplit_once('.'))
+        .is_some_and(|(name, suffix)| {
+            !name.is_empty()
+                && suffix.len() == 6
+                && suffix.chars().all(|c| c.is_ascii_alphanumeric())
+        })
+}
+
+#[derive(Default)]
+struct SuppressorState {
+    // The directories syncers are writing into, once for each of them
+    writing: Vec<PathBuf>,
+    written: HashMap<PathBuf, (Stamp, Instant)>,
+    // The directories syncers wrote into, which the temporary files they used were in
+    written_dirs: HashMap<PathBuf, Instant>,
+}
+
+impl SuppressorState {
+    fn is_written_to(&self, changes: &Changes) -> bool {
+        self.writing
+            .iter()
+            .any(|dir| changes.is_full() || changes.iter().any(|(path, _)| path.starts_with(dir)))
+    }
+}
+
+// Keeps the files syncers write into the workspace (e.g. when pulling from a remote directory)
+// from being picked up as local changes and synced right back. Written files are remembered
+// along with what they looked like, and their changes are suppressed for as long as they still
+// look that way. Which changes a syncer made is only known once it is done writing, so that is
+// waited for before telling them apart
+pub struct EventSuppressor {
+    state: Mutex<SuppressorState>,
+    // Signals syncers starting and finishing to write, for whoever needs to wait for them
+    writes: watch::Sender<()>,
+    writes_updates: watch::Receiver<()>,
+}
+
+impl EventSuppressor {
+    pub fn new() -> Self {
+        let (writes, writes_updates) = watch::channel(());
+        EventSuppressor {
+            state: Mutex::new(SuppressorState::default()),
+            writes,
+            writes_updates,
+        }
+    }
+
+    // Changes outside of the directory are never waited for
+    pub fn start_writing(&self, dir: &Path) -> Writing<'_> {
+        let mut state = self.state.lock().unwrap();
+        state.writing.push(dir.to_path_buf());
+        let _ = self.writes.send(());
+        Writing {
+            suppressor: self,
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    // Waits for the syncers writing where the changes were made to be done
+    pub async fn wait_for_writers(&self, changes: &Changes) {
+        let mut writes = self.writes_updates.clone();
+        loop {
+            writes.borrow_and_update();
+            if !self.state.lock().unwrap().is_written_to(changes) {
+                return;
+            }
+            if writes.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    // Whether the last change to the path was made by a syncer rather than anyone else
+    pub fn was_written(&self, path: &Path) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        state
+            .written
+            .retain(|_, (_, written_at)| now.duration_since(*written_at) < WRITTEN_EXPIRY);
+        state
+            .written_dirs
+            .retain(|_, written_at| now.duration_since(*written_at) < WRITTEN_EXPIRY);
+        let written = state
+            .written
+            .get(path)
+            .is_some_and(|(stamp, _)| *stamp == Stamp::of(path));
+        // Temporary files are gone by the time their events come through
+        written
+            || is_temp_file(path)
+                && Stamp::of(path) == Stamp::Missing
+                && state.written_dirs.keys().any(|dir| path.starts_with(dir))
+    }
+}
+
+// A syncer writing into the workspace. Changes to the files it wrote are suppressed once it
+// reports them, whatever it didn't report (e.g. because it failed) gets synced as usual
+pub struct Writing<'a> {
+    suppressor: &'a EventSuppressor,
+    dir: PathBuf,
+}
+
+impl Writing<'_> {
+    pub fn finish(self, written: impl IntoIterator<Item = PathBuf>) {
+        let now = Instant::now();
+        let mut state = self.suppressor.state.lock().unwrap();
+        for path in written {
+            let stamp = Stamp::of(&path);
+            state.written.insert(path, (stamp, now));
+        }
+    }
+}
+
+impl Drop for Writing<'_> {
+    fn drop(&mut self) {
+        let mut state = self.suppressor.state.lock().unwrap();
+        if let Some(i) = state.writing.iter().position(|dir| *dir == self.dir) {
+            state.writing.swap_remove(i);
+        }
+        state.written_dirs.insert(self.dir.clone(), Instant::now());
+        let _ = self.suppressor.writes.send(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::suppress::EventSuppressor;
+    use crate::syncers::Changes;
+    use notify::event::{CreateKind, ModifyKind};
+    use notify::EventKind;
+    use std::path::Path;
+    use std::time::Duration;
+
+    fn changes(paths: &[&Path]) -> Changes {
+        let mut changes = Changes::new();
+        for path in paths {
+            changes.insert(path.to_path_buf(), EventKind::Modify(ModifyKind::Any));
+        }
+        changes
+    }
+
+    #[tokio::test]
+    async fn changes_are_suppressed_until_someone_else_makes_them() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let (pulled, deleted, local) = (
+            root.join("pulled"),
+            root.join("deleted"),
+            root.join("local"),
+        );
+
+        let suppressor = EventSuppressor::new();
+        let writing = suppressor.start_writing(root);
+        std::fs::write(&pulled, "remote").unwrap();
+        std::fs::write(&local, "local").unwrap();
+        let batch = changes(&[&pulled, &local]);
+        let waited = tokio::time::timeout(
+            Duration::from_millis(10),
+            suppressor.wait_for_writers(&batch),
+        )
+        .await;
+        assert!(waited.is_err());
+        writing.finish([pulled.clone(), deleted.clone()]);
+        suppressor.wait_for_writers(&batch).await;
+
+        assert!(suppressor.was_written(&pulled));
+        assert!(suppressor.was_written(&deleted));
+        assert!(!suppressor.was_written(&local));
+        std::fs::write(&pulled, "edited locally").unwrap();
+        std::fs::write(&deleted, "created locally").unwrap();
+        assert!(!suppressor.was_written(&pulled));
+        assert!(!suppressor.was_written(&deleted));
+    }
+
+    #[tokio::test]
+    async fn only_changes_where_syncers_write_wait_for_them() {
+        let dir = tempfile::tempdir().unwrap();
+        let (pulled_dir, local) = (dir.path().join("outputs"), dir.path().join("src/main.rs"));
+        let temp_file = pulled_dir.join(".report.txt.Ab3xZ9");
+
+        let suppressor = EventSuppressor::new();
+        let writing = suppressor.start_writing(&pulled_dir);
+        suppressor.wait_for_writers(&changes(&[&local])).await;
+        let mut temp_file_created = Changes::new();
+        temp_file_created.insert(temp_file.clone(), EventKind::Create(CreateKind::File));
+        let waited = tokio::time::timeout(
+            Duration::from_millis(10),
+            suppressor.wait_for_writers(&temp_file_created),
+        )
+        .await;
+        assert!(waited.is_err());
+        drop(writing);
+        suppressor.wait_for_writers(&temp_file_created).await;
+
+        assert!(suppressor.was_written(&temp_file));
+        assert!(!suppressor.was_written(&pulled_dir.join("report.txt.Ab3xZ9")));
+        assert!(!suppressor.was_written(&local.with_file_name(".main.rs.Ab3xZ9")));
+    }
+}
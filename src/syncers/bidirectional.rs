@@ -0,0 +1,402 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::error::{Error, Result};
+use crate::rsync::Direction;
+
+// Which version of a file is kept when it changed on both sides since the last sync
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    LocalWins,
+    RemoteWins,
+    // The local version is renamed and synced alongside the remote one. A file deleted on one side
+    // and changed on the other is kept
+    KeepBoth,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BidirectionalProperties {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    conflict_policy: Option<ConflictPolicy>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    manifest_dir: Option<String>,
+}
+
+impl BidirectionalProperties {
+    pub fn as_settings(&self) -> Result<BidirectionalSettings> {
+        let manifest_dir = match &self.manifest_dir {
+            Some(dir) => PathBuf::from(dir),
+            None => default_manifest_dir()?,
+        };
+        Ok(BidirectionalSettings {
+            conflict_policy: self.conflict_policy.unwrap_or(ConflictPolicy::KeepBoth),
+            manifest_dir,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BidirectionalSettings {
+    pub conflict_policy: ConflictPolicy,
+    pub manifest_dir: PathBuf,
+}
+
+impl BidirectionalSettings {
+    // One manifest per workspace and syncer, named after both
+    pub fn manifest_path(&self, workspace_path: &str, syncer: &str) -> PathBuf {
+        let name: String = format!("{}-{}", workspace_path.trim_end_matches('/'), syncer)
+            .chars()
+            .map(
+                |c| match c.is_ascii_alphanumeric() || c == '-' || c == '.' {
+                    true => c,
+                    false => '_',
+                },
+            )
+            .collect();
+        self.manifest_dir.join(name + ".json")
+    }
+}
+
+// $XDG_STATE_HOME/rust-dev-sync/manifests, or ~/.local/state/rust-dev-sync/manifests
+fn default_manifest_dir() -> Result<PathBuf> {
+    let state_dir = std::env::var_os("XDG_STATE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| home::home_dir().map(|home| home.join(".local").join("state")))
+        .ok_or_else(|| {
+            Error::Config("unable to locate sync manifests: no home directory".to_string())
+        })?;
+    Ok(state_dir.join("rust-dev-sync").join("manifests"))
+}
+
+// Size and modification time of a file, as listed by rsync. Times are kept as rsync prints them,
+// which is only ever compared with other listings
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct FileState {
+    pub size: u64,
+    pub modified: String,
+}
+
+// Files by path relative to the root of the listing
+pub type Listing = BTreeMap<PathBuf, FileState>;
+
+// Parses the output of rsync --list-only. Only regular files and symlinks are kept, directories
+// are created and removed along with the files they contain
+pub fn parse_listing(output: &str) -> Listing {
+    let mut listing = Listing::new();
+    for line in output.lines() {
+        let parsed = (|| {
+            let (permissions, rest) = next_field(line)?;
+            let (size, rest) = next_field(rest)?;
+            let (date, rest) = next_field(rest)?;
+            let (time, rest) = next_field(rest)?;
+            let name = rest.strip_prefix(' ')?;
+            Some((permissions, size, date, time, name))
+        })();
+        let (permissions, size, date, time, name) = match parsed {
+            Some(parsed) => parsed,
+            None => continue,
+        };
+        let name = match permissions.chars().next() {
+            Some('-') => name,
+            Some('l') => name.split(" -> ").next().unwrap_or(name),
+            _ => continue,
+        };
+        // Newer versions of rsync group digits with commas
+        let size = match size.replace(',', "").parse::<u64>() {
+            Ok(size) if permissions.len() == 10 => size,
+            _ => continue,
+        };
+        listing.insert(
+            PathBuf::from(name),
+            FileState {
+                size,
+                modified: format!("{} {}", date, time),
+            },
+        );
+    }
+    listing
+}
+
+fn next_field(line: &str) -> Option<(&str, &str)> {
+    let line = line.trim_start();
+    let end = line.find(' ')?;
+    Some((&line[..end], &line[end..]))
+}
+
+// The state both sides were left in by the last sync
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct Manifest {
+    files: Listing,
+}
+
+impl Manifest {
+    // No manifest means the two sides were never synced
+    pub async fn load(path: &Path) -> Result<Self> {
+        match tokio::fs::read(path).await {
+            Ok(data) => serde_json::from_slice(&data).map_err(|source| Error::Manifest {
+                path: path.to_path_buf(),
+                source,
+            }),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Manifest::default()),
+            Err(e) => Err(Error::io(format!(
+                "failed to read sync manifest {}",
+                path.display()
+            ))(e)),
+        }
+    }
+
+    // Written to a temporary file first, so that an interrupted write never leaves a truncated
+    // manifest behind
+    pub async fn save(&self, path: &Path) -> Result {
+        let context = || format!("failed to write sync manifest {}", path.display());
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir)
+                .await
+                .map_err(Error::io(context()))?;
+        }
+        let data = serde_json::to_vec(self).map_err(|source| Error::Manifest {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        let tmp_path = path.with_extension("json.tmp");
+        tokio::fs::write(&tmp_path, data)
+            .await
+            .map_err(Error::io(context()))?;
+        tokio::fs::rename(&tmp_path, path)
+            .await
+            .map_err(Error::io(context()))
+    }
+}
+
+// What a sync has to do to bring both sides in line, and the manifest to store once it's done
+#[derive(Debug, Default, PartialEq)]
+pub struct Plan {
+    pub push: Vec<PathBuf>,
+    pub pull: Vec<PathBuf>,
+    // Local files to move aside, to the second path, before the remote version is pulled
+    pub keep_both: Vec<(PathBuf, PathBuf)>,
+    pub conflicts: Vec<PathBuf>,
+    pub manifest: Manifest,
+}
+
+// A side changed a file if its state differs from the manifest, which covers files being created
+// and deleted too. Files only one side changed are transferred from that side, the conflict policy
+// decides for files both sides changed. Conflict copies are named with the given suffix. Files that
+// differ on both sides but were never synced, e.g. when turning on bidirectional syncing over an
+// existing copy, are pushed rather than treated as conflicts
+pub fn plan(
+    manifest: &Manifest,
+    local: &Listing,
+    remote: &Listing,
+    policy: ConflictPolicy,
+    conflict_suffix: &str,
+) -> Plan {
+    let mut plan = Plan::default();
+    let mut never_synced = 0;
+    let paths: BTreeSet<&PathBuf> = manifest
+        .files
+        .keys()
+        .chain(local.keys())
+        .chain(remote.keys())
+        .collect();
+    for path in paths {
+        let last = manifest.files.get(path);
+        let (local_state, remote_state) = (local.get(path), remote.get(path));
+        // Nothing to transfer, whichever side changed
+        if local_state == remote_state {
+            if let Some(state) = local_state {
+                plan.manifest.files.insert(path.clone(), state.clone());
+            }
+            continue;
+        }
+        let direction = match (local_state != last, remote_state != last) {
+            (true, false) => Direction::Push,
+            (false, true) => Direction::Pull,
+            _ if last.is_none() && local_state.is_some() && remote_state.is_some() => {
+                never_synced += 1;
+                Direction::Push
+            }
+            _ => {
+                plan.conflicts.push(path.clone());
+                match (policy, local_state, remote_state) {
+                    (ConflictPolicy::LocalWins, _, _) => Direction::Push,
+                    (ConflictPolicy::RemoteWins, _, _) => Direction::Pull,
+                    (ConflictPolicy::KeepBoth, Some(local_state), Some(_)) => {
+                        let copy = conflict_copy(path, conflict_suffix);
+                        plan.keep_both.push((path.clone(), copy.clone()));
+                        plan.push.push(copy.clone());
+                        plan.manifest.files.insert(copy, local_state.clone());
+                        Direction::Pull
+                    }
+                    (ConflictPolicy::KeepBoth, Some(_), None) => Direction::Push,
+                    (ConflictPolicy::KeepBoth, None, _) => Direction::Pull,
+                }
+            }
+        };
+        // Files missing from the side they are transferred from get deleted from the other
+        let (transfers, state) = match direction {
+            Direction::Push => (&mut plan.push, local_state),
+            Direction::Pull => (&mut plan.pull, remote_state),
+        };
+        transfers.push(path.clone());
+        if let Some(state) = state {
+            plan.manifest.files.insert(path.clone(), state.clone());
+        }
+    }
+    if never_synced > 0 {
+        warn!(
+            files = never_synced,
+            "Files differ on both sides but were never synced, keeping the local version"
+        );
+    }
+    plan
+}
+
+fn conflict_copy(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".conflict-{}", suffix));
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::syncers::bidirectional::{
+        parse_listing, plan, ConflictPolicy, FileState, Listing, Manifest,
+    };
+    use std::path::PathBuf;
+
+    fn listing(files: &[(&str, u64)]) -> Listing {
+        files
+            .iter()
+            .map(|(path, size)| {
+                let state = FileState {
+                    size: *size,
+                    modified: "2022/01/01 12:00:00".to_string(),
+                };
+                (PathBuf::from(path), state)
+            })
+            .collect()
+    }
+
+    fn paths(paths: &[&str]) -> Vec<PathBuf> {
+        paths.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn listings_are_parsed() {
+        let output = "receiving incremental file list
+drwxr-xr-x          4,096 2022/01/01 12:00:00 .
+-rw-r--r--          1,234 2022/01/01 12:00:01 dir/file with  spaces
+lrwxrwxrwx              4 2022/01/01 12:00:02 link -> file
+";
+        let listing = parse_listing(output);
+        assert_eq!(listing.len(), 2);
+        assert_eq!(
+            listing.get(&PathBuf::from("dir/file with  spaces")),
+            Some(&FileState {
+                size: 1234,
+                modified: "2022/01/01 12:00:01".to_string()
+            })
+        );
+        assert_eq!(listing.get(&PathBuf::from("link")).unwrap().size, 4);
+    }
+
+    #[test]
+    fn changes_are_transferred_from_the_side_they_were_made_on() {
+        let manifest = Manifest {
+            files: listing(&[
+                ("same", 1),
+                ("local-edit", 1),
+                ("remote-edit", 1),
+                ("gone", 1),
+            ]),
+        };
+        let local = listing(&[
+            ("same", 1),
+            ("local-edit", 2),
+            ("remote-edit", 1),
+            ("new", 1),
+        ]);
+        let remote = listing(&[
+            ("same", 1),
+            ("local-edit", 1),
+            ("remote-edit", 3),
+            ("gone", 1),
+        ]);
+        let plan = plan(&manifest, &local, &remote, ConflictPolicy::KeepBoth, "1");
+        assert_eq!(plan.push, paths(&["gone", "local-edit", "new"]));
+        assert_eq!(plan.pull, paths(&["remote-edit"]));
+        assert!(plan.conflicts.is_empty());
+        assert_eq!(
+            plan.manifest.files,
+            listing(&[
+                ("same", 1),
+                ("local-edit", 2),
+                ("remote-edit", 3),
+                ("new", 1)
+            ])
+        );
+    }
+
+    #[test]
+    fn conflicts_follow_the_policy() {
+        let manifest = Manifest {
+            files: listing(&[("both", 1), ("deleted", 1)]),
+        };
+        let local = listing(&[("both", 2)]);
+        let remote = listing(&[("both", 3), ("deleted", 4)]);
+
+        let local_wins = plan(&manifest, &local, &remote, ConflictPolicy::LocalWins, "1");
+        assert_eq!(local_wins.push, paths(&["both", "deleted"]));
+        assert!(local_wins.pull.is_empty());
+        assert_eq!(local_wins.conflicts, paths(&["both", "deleted"]));
+
+        let remote_wins = plan(&manifest, &local, &remote, ConflictPolicy::RemoteWins, "1");
+        assert!(remote_wins.push.is_empty());
+        assert_eq!(remote_wins.pull, paths(&["both", "deleted"]));
+
+        let keep_both = plan(&manifest, &local, &remote, ConflictPolicy::KeepBoth, "1");
+        assert_eq!(
+            keep_both.keep_both,
+            vec![(PathBuf::from("both"), PathBuf::from("both.conflict-1"))]
+        );
+        assert_eq!(keep_both.push, paths(&["both.conflict-1"]));
+        assert_eq!(keep_both.pull, paths(&["both", "deleted"]));
+        assert_eq!(
+            keep_both.manifest.files,
+            listing(&[("both", 3), ("both.conflict-1", 2), ("deleted", 4)])
+        );
+    }
+
+    #[test]
+    fn files_never_synced_are_pushed_without_conflicts() {
+        let local = listing(&[("differs", 1), ("same", 1), ("local-only", 1)]);
+        let remote = listing(&[("differs", 2), ("same", 1), ("remote-only", 1)]);
+        let plan = plan(
+            &Manifest::default(),
+            &local,
+            &remote,
+            ConflictPolicy::KeepBoth,
+            "1",
+        );
+        assert_eq!(plan.push, paths(&["differs", "local-only"]));
+        assert_eq!(plan.pull, paths(&["remote-only"]));
+        assert!(plan.conflicts.is_empty());
+        assert!(plan.keep_both.is_empty());
+        assert_eq!(
+            plan.manifest.files,
+            listing(&[
+                ("differs", 1),
+                ("local-only", 1),
+                ("remote-only", 1),
+                ("same", 1)
+            ])
+        );
+    }
+}
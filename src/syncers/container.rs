@@ -0,0 +1,60 @@
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use crate::syncers::ssh_tar::{TarSyncer, Transport};
+
+const DEFAULT_RUNTIME: &str = "docker";
+
+// Syncs into a running container rather than through a bind mount, which can be slow. Files are
+// streamed into it as a tar archive through `<runtime> exec`, the same way the ssh_tar syncer
+// does it through ssh, so the container only needs sh, tar and find
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ContainerProperties {
+    // Name or id of the container
+    container: String,
+    dst_dir: String,
+    // docker, podman, or any command taking the same exec arguments
+    #[serde(skip_serializing_if = "Option::is_none")]
+    runtime: Option<String>,
+    // Who the files are written as inside the container, in the form the runtime takes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<String>,
+}
+
+impl ContainerProperties {
+    pub fn as_syncer(&self) -> Result<TarSyncer> {
+        if self.container.is_empty() {
+            return Err(Error::Config(
+                "container syncers need the name of a container".to_string(),
+            ));
+        }
+        let transport = Transport::Exec {
+            runtime: self
+                .runtime
+                .clone()
+                .unwrap_or_else(|| String::from(DEFAULT_RUNTIME)),
+            container: self.container.clone(),
+            user: self.user.clone(),
+        };
+        Ok(TarSyncer::new(transport, &self.dst_dir))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::syncers::container::ContainerProperties;
+    use crate::syncers::Syncer;
+
+    #[test]
+    fn containers_are_named_like_their_destination() {
+        let props: ContainerProperties =
+            serde_yaml::from_str("{container: app, dst_dir: /workspace}").unwrap();
+        assert_eq!(
+            props.as_syncer().unwrap().name(),
+            "container:app:/workspace"
+        );
+        let props: ContainerProperties =
+            serde_yaml::from_str("{container: '', dst_dir: /workspace}").unwrap();
+        assert!(props.as_syncer().is_err());
+    }
+}
@@ -0,0 +1,396 @@
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs::{FileTimes, Metadata};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::error::{Error, Result};
+use crate::filter::{self, Filter};
+use crate::syncers::rsyncer::generate_session_id;
+use crate::syncers::{Changes, Result as SyncerResult, SyncStats, Syncer};
+
+// Mirrors the workspace into another local directory without going through rsync. Every changed
+// path is made the same on the destination: files are copied, directories are mirrored along
+// with their content and whatever no longer exists is deleted, which covers renames too.
+// Files are written to a temporary file next to the destination first and then renamed over it,
+// so that nothing ever sees them half written
+pub struct LocalSyncer {
+    dst_dir: PathBuf,
+    preserve_permissions: bool,
+    preserve_mod_times: bool,
+    preserve_links: bool,
+    filter: Option<Arc<Filter>>,
+}
+
+impl LocalSyncer {
+    pub fn new(
+        dst_dir: &str,
+        preserve_permissions: bool,
+        preserve_mod_times: bool,
+        preserve_links: bool,
+    ) -> Self {
+        LocalSyncer {
+            dst_dir: PathBuf::from(dst_dir),
+            preserve_permissions,
+            preserve_mod_times,
+            preserve_links,
+            filter: None,
+        }
+    }
+
+    // Files the workspace leaves out are neither copied nor deleted from the destination
+    pub fn with_filter(mut self, filter: Arc<Filter>) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    // Makes dst the same as src. The content of directories is only looked at when recursive.
+    // Returns the number of bytes copied
+    async fn mirror(&self, src: &Path, dst: &Path, recursive: bool) -> Result<u64> {
+        let mut bytes = 0;
+        let mut pending = vec![(src.to_path_buf(), dst.to_path_buf())];
+        // Copying files into a directory changes its modification time, so directories are
+        // only given theirs once everything in them has been copied
+        let mut dirs = Vec::new();
+        while let Some((src, dst)) = pending.pop() {
+            let metadata = match self.source_metadata(&src).await? {
+                Some(metadata) => metadata,
+                None => {
+                    remove(&dst).await?;
+                    continue;
+                }
+            };
+            if metadata.is_symlink() {
+                self.copy_link(&src, &dst).await?;
+            } else if metadata.is_dir() {
+                create_dir(&dst).await?;
+                if recursive {
+                    pending.extend(self.mirror_entries(&src, &dst).await?);
+                }
+                dirs.push((dst, metadata));
+            } else if metadata.is_file() {
+                bytes += self.copy_file(&src, &dst, &metadata).await?;
+            } else {
+                // Same as rsync, sockets, fifos and devices are left alone
+                debug!(path = ?src, "Skipping special file");
+            }
+        }
+        for (dst, metadata) in dirs.iter().rev() {
+            self.copy_metadata(dst, metadata)
+                .map_err(Error::io(format!("failed to update directory {:?}", dst)))?;
+        }
+        Ok(bytes)
+    }
+
+    // Deletes what no longer exists in the src directory from the dst one, and returns what is
+    // left to mirror
+    async fn mirror_entries(&self, src: &Path, dst: &Path) -> Result<Vec<(PathBuf, PathBuf)>> {
+        let names = list_dir(src).await?;
+        for name in list_dir(dst).await? {
+            if !names.contains(&name) && filter::allows(self.filter.as_deref(), &src.join(&name)) {
+                remove(&dst.join(&name)).await?;
+            }
+        }
+        Ok(names
+            .into_iter()
+            .map(|name| (src.join(&name), dst.join(&name)))
+            .filter(|(src, _)| filter::allows(self.filter.as_deref(), src))
+            .collect())
+    }
+
+    // Symlinks are followed unless they are preserved, and treated as missing if they lead nowhere
+    async fn source_metadata(&self, src: &Path) -> Result<Option<Metadata>> {
+        let metadata = match self.preserve_links {
+            true => tokio::fs::symlink_metadata(src).await,
+            false => tokio::fs::metadata(src).await,
+        };
+        match metadata {
+            Ok(metadata) => Ok(Some(metadata)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(Error::io(format!("failed to read {:?}", src))(e)),
+        }
+    }
+
+    async fn copy_file(&self, src: &Path, dst: &Path, metadata: &Metadata) -> Result<u64> {
+        let existing = tokio::fs::symlink_metadata(dst).await.ok();
+        if let Some(existing) = &existing {
+            if existing.is_file() && self.is_unchanged(metadata, existing) {
+                return Ok(0);
+            }
+        }
+        replace_with(dst, |temp| async move {
+            // New files get the permissions of the source either way, existing ones keep
+            // theirs unless permissions are preserved
+            let bytes = tokio::fs::copy(src, &temp).await?;
+            if let Some(existing) = existing.filter(|_| !self.preserve_permissions) {
+                tokio::fs::set_permissions(&temp, existing.permissions()).await?;
+            }
+            self.copy_metadata(&temp, metadata)?;
+            Ok(bytes)
+        })
+        .await
+        .map_err(Error::io(format!("failed to copy {:?} to {:?}", src, dst)))
+    }
+
+    // Without modification times to compare, a destination written after the source was last
+    // modified is taken to be up to date
+    fn is_unchanged(&self, src: &Metadata, dst: &Metadata) -> bool {
+        if src.len() != dst.len() {
+            return false;
+        }
+        match (src.modified(), dst.modified()) {
+            (Ok(src), Ok(dst)) if self.preserve_mod_times => src == dst,
+            (Ok(src), Ok(dst)) => dst >= src,
+            _ => false,
+        }
+    }
+
+    async fn copy_link(&self, src: &Path, dst: &Path) -> Result {
+        let target = tokio::fs::read_link(src)
+            .await
+            .map_err(Error::io(format!("failed to read symlink {:?}", src)))?;
+        if tokio::fs::read_link(dst).await.ok().as_ref() == Some(&target) {
+            return Ok(());
+        }
+        replace_with(dst, |temp| async move {
+            tokio::fs::symlink(&target, temp).await
+        })
+        .await
+        .map_err(Error::io(format!("failed to create symlink {:?}", dst)))
+    }
+
+    // Times go first, as the permissions may not allow opening the file anymore
+    fn copy_metadata(&self, dst: &Path, metadata: &Metadata) -> std::io::Result<()> {
+        if self.preserve_mod_times {
+            let times = FileTimes::new().set_modified(metadata.modified()?);
+            std::fs::File::open(dst)?.set_times(times)?;
+        }
+        if self.preserve_permissions {
+            std::fs::set_permissions(dst, metadata.permissions())?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Syncer for LocalSyncer {
+    fn name(&self) -> String {
+        format!("local:{}", self.dst_dir.display())
+    }
+
+    async fn sync(&self, workspace_path: &Path, changes: &Changes) -> SyncerResult {
+        let mut bytes = 0;
+        for (relative, recursive) in changes.roots(workspace_path) {
+            let path = workspace_path.join(&relative);
+            bytes += self
+                .mirror(&path, &self.dst_dir.join(&relative), recursive)
+                .await?;
+        }
+        Ok(SyncStats {
+            bytes_transferred: Some(bytes),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LocalProperties {
+    dst_dir: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    preserve_permissions: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    preserve_mod_times: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    preserve_links: Option<bool>,
+}
+
+impl LocalProperties {
+    pub fn as_syncer(&self) -> LocalSyncer {
+        LocalSyncer::new(
+            &self.dst_dir,
+            self.preserve_permissions.unwrap_or(false),
+            self.preserve_mod_times.unwrap_or(false),
+            self.preserve_links.unwrap_or(false),
+        )
+    }
+}
+
+async fn list_dir(dir: &Path) -> Result<HashSet<OsString>> {
+    let mut names = HashSet::new();
+    let mut entries = match tokio::fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(names),
+        Err(e) => return Err(Error::io(format!("failed to list {:?}", dir))(e)),
+    };
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .map_err(Error::io(format!("failed to list {:?}", dir)))?
+    {
+        names.insert(entry.file_name());
+    }
+    Ok(names)
+}
+
+pub async fn create_dir(dst: &Path) -> Result {
+    if let Ok(existing) = tokio::fs::symlink_metadata(dst).await {
+        if existing.is_dir() {
+            return Ok(());
+        }
+        remove(dst).await?;
+    }
+    tokio::fs::create_dir_all(dst)
+        .await
+        .map_err(Error::io(format!("failed to create directory {:?}", dst)))
+}
+
+pub async fn remove(dst: &Path) -> Result {
+    let removed = match tokio::fs::symlink_metadata(dst).await {
+        Ok(existing) if existing.is_dir() => tokio::fs::remove_dir_all(dst).await,
+        Ok(_) => tokio::fs::remove_file(dst).await,
+        Err(_) => return Ok(()),
+    };
+    match removed {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            Err(Error::io(format!("failed to delete {:?}", dst))(e))
+        }
+        _ => Ok(()),
+    }
+}
+
+// Has write create a temporary file next to dst, which then replaces dst in one go. The
+// temporary file is cleaned up if anything goes wrong
+pub async fn replace_with<T, F, Fut>(dst: &Path, write: F) -> std::io::Result<T>
+where
+    F: FnOnce(PathBuf) -> Fut,
+    Fut: std::future::Future<Output = std::io::Result<T>>,
+{
+    let parent = dst.parent().unwrap_or_else(|| Path::new("/"));
+    tokio::fs::create_dir_all(parent).await?;
+    if tokio::fs::symlink_metadata(dst)
+        .await
+        .is_ok_and(|existing| existing.is_dir())
+    {
+        tokio::fs::remove_dir_all(dst).await?;
+    }
+    let mut name = OsString::from(".");
+    name.push(dst.file_name().unwrap_or_default());
+    name.push(format!(".rust-dev-sync-{}", generate_session_id()));
+    let temp = parent.join(name);
+    let result = async {
+        let value = write(temp.clone()).await?;
+        tokio::fs::rename(&temp, dst).await?;
+        Ok(value)
+    }
+    .await;
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(&temp).await;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::filter::{Filter, FilterAction};
+    use crate::syncers::local::LocalSyncer;
+    use crate::syncers::{Changes, Syncer};
+    use notify::event::{CreateKind, ModifyKind, RemoveKind, RenameMode};
+    use notify::EventKind;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    // The source and destination directories, removed along with the returned TempDir
+    fn dirs() -> (TempDir, PathBuf, PathBuf) {
+        let root = tempfile::tempdir().unwrap();
+        let (src, dst) = (root.path().join("src"), root.path().join("dst"));
+        std::fs::create_dir_all(&src).unwrap();
+        (root, src, dst)
+    }
+
+    fn write(path: &Path, contents: &str) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+
+    fn read(path: &Path) -> String {
+        std::fs::read_to_string(path).unwrap()
+    }
+
+    #[tokio::test]
+    async fn workspace_is_mirrored() {
+        let (_root, src, dst) = dirs();
+        write(&src.join("a/file1"), "one");
+        write(&src.join("file2"), "two");
+        std::fs::set_permissions(src.join("file2"), PermissionsExt::from_mode(0o750)).unwrap();
+        std::os::unix::fs::symlink("file2", src.join("link")).unwrap();
+        write(&dst.join("stale/file"), "stale");
+        write(&dst.join("kept.log"), "excluded");
+        let filter = Filter::new(&src, [(FilterAction::Exclude, "*.log")], None).unwrap();
+        let syncer =
+            LocalSyncer::new(dst.to_str().unwrap(), true, true, true).with_filter(Arc::new(filter));
+
+        let stats = syncer.sync(&src, &Changes::full()).await.unwrap();
+        assert_eq!(stats.bytes_transferred, Some(6));
+        assert_eq!(read(&dst.join("a/file1")), "one");
+        assert_eq!(read(&dst.join("file2")), "two");
+        let copied = std::fs::metadata(dst.join("file2")).unwrap();
+        let original = std::fs::metadata(src.join("file2")).unwrap();
+        assert_eq!(copied.permissions().mode() & 0o777, 0o750);
+        assert_eq!(copied.modified().unwrap(), original.modified().unwrap());
+        assert_eq!(
+            std::fs::read_link(dst.join("link")).unwrap(),
+            PathBuf::from("file2")
+        );
+        assert!(!dst.join("stale").exists());
+        assert_eq!(read(&dst.join("kept.log")), "excluded");
+
+        // Nothing changed since, so nothing is copied
+        let stats = syncer.sync(&src, &Changes::full()).await.unwrap();
+        assert_eq!(stats.bytes_transferred, Some(0));
+    }
+
+    #[tokio::test]
+    async fn changes_are_mirrored() {
+        let (_root, src, dst) = dirs();
+        write(&src.join("a/file1"), "one");
+        write(&src.join("file2"), "two");
+        std::os::unix::fs::symlink("file2", src.join("link")).unwrap();
+        let syncer = LocalSyncer::new(dst.to_str().unwrap(), false, false, false);
+        syncer.sync(&src, &Changes::full()).await.unwrap();
+        // Symlinks that aren't preserved are copied as the file they point to
+        assert_eq!(read(&dst.join("link")), "two");
+
+        std::fs::rename(src.join("a"), src.join("b")).unwrap();
+        std::fs::remove_file(src.join("file2")).unwrap();
+        write(&src.join("file3"), "three");
+        let mut changes = Changes::new();
+        changes.insert(
+            src.join("a"),
+            EventKind::Modify(ModifyKind::Name(RenameMode::From)),
+        );
+        changes.insert(
+            src.join("b"),
+            EventKind::Modify(ModifyKind::Name(RenameMode::To)),
+        );
+        changes.insert(src.join("file2"), EventKind::Remove(RemoveKind::File));
+        changes.insert(src.join("file3"), EventKind::Create(CreateKind::File));
+        let stats = syncer.sync(&src, &changes).await.unwrap();
+        assert_eq!(stats.bytes_transferred, Some(8));
+        assert!(!dst.join("a").exists());
+        assert_eq!(read(&dst.join("b/file1")), "one");
+        assert!(!dst.join("file2").exists());
+        assert_eq!(read(&dst.join("file3")), "three");
+        let leftovers = std::fs::read_dir(&dst)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.starts_with('.'))
+            .collect::<Vec<String>>();
+        assert!(leftovers.is_empty());
+    }
+}
@@ -1,14 +1,184 @@
-use std::path::Path;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use async_trait::async_trait;
+use notify::event::ModifyKind;
 use notify::EventKind;
 
+pub mod bidirectional;
+pub mod container;
+pub mod local;
+pub mod puller;
 pub mod rsyncer;
+pub mod s3;
+pub mod sftp;
+pub mod snapshot;
+pub mod ssh_tar;
 
-pub type Result = std::result::Result<(), String>;
+pub type Result = crate::error::Result<SyncStats>;
+
+// What a sync did, as far as the syncer is able to tell
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SyncStats {
+    pub bytes_transferred: Option<u64>,
+}
+
+// A batch of file changes within a single workspace. Multiple events for the same path are
+// coalesced so that only the most recent kind of change is kept. A "full" batch asks syncers
+// to sync the entire workspace regardless of which paths are listed
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Changes {
+    full: bool,
+    paths: BTreeMap<PathBuf, EventKind>,
+}
+
+impl Changes {
+    pub fn new() -> Self {
+        Changes::default()
+    }
+
+    pub fn full() -> Self {
+        Changes {
+            full: true,
+            paths: BTreeMap::new(),
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.full
+    }
+
+    pub fn insert(&mut self, path: PathBuf, kind: EventKind) {
+        self.paths.insert(path, kind);
+    }
+
+    pub fn merge(&mut self, other: Changes) {
+        self.full |= other.full;
+        self.paths.extend(other.paths);
+    }
+
+    pub fn len(&self) -> usize {
+        self.paths.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty()
+    }
+
+    pub fn retain(&mut self, mut keep: impl FnMut(&Path) -> bool) {
+        self.paths.retain(|path, _| keep(path));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Path, &EventKind)> {
+        self.paths.iter().map(|(path, kind)| (path.as_path(), kind))
+    }
+
+    // The paths to sync relative to the workspace, each with whether the content of directories has
+    // to be looked at: a directory whose metadata changed still has the same content. A full batch
+    // is the whole workspace, and paths outside of the workspace are left out
+    pub fn roots(&self, workspace: &Path) -> Vec<(PathBuf, bool)> {
+        if self.full {
+            return vec![(PathBuf::new(), true)];
+        }
+        self.paths
+            .iter()
+            .filter_map(|(path, kind)| {
+                let relative = path.strip_prefix(workspace).ok()?;
+                let recursive = !matches!(
+                    kind,
+                    EventKind::Modify(ModifyKind::Metadata(_)) | EventKind::Access(_)
+                );
+                Some((relative.to_path_buf(), recursive))
+            })
+            .collect()
+    }
+}
 
 // Send/Sync is required to be able to move a syncer to a tokio thread context. I need to figure out why
 #[async_trait]
 pub trait Syncer: std::marker::Sync + std::marker::Send {
-    async fn sync(&self, workspace_path: &Path, file_path: &Path, kind: EventKind) -> Result;
+    // Short human readable description of where the syncer sends files to
+    fn name(&self) -> String;
+
+    async fn sync(&self, workspace_path: &Path, changes: &Changes) -> Result;
+
+    // Syncers that only bring files into the workspace have no use for local changes. They are
+    // only run to sync the whole workspace
+    fn syncs_changes(&self) -> bool {
+        true
+    }
+
+    // How often the whole workspace should be synced regardless of file events, for syncers
+    // picking up changes made elsewhere
+    fn poll_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    // Releases whatever the syncer keeps open between syncs, such as ssh connections. Called once
+    // no more syncs will run
+    async fn close(&self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::syncers::Changes;
+    use notify::event::{CreateKind, MetadataKind, ModifyKind, RemoveKind};
+    use notify::EventKind;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn changes_keep_latest_kind_per_path() {
+        let mut changes = Changes::new();
+        changes.insert(
+            PathBuf::from("/ws/file1"),
+            EventKind::Create(CreateKind::File),
+        );
+        changes.insert(
+            PathBuf::from("/ws/file2"),
+            EventKind::Modify(ModifyKind::Any),
+        );
+        changes.insert(
+            PathBuf::from("/ws/file1"),
+            EventKind::Remove(RemoveKind::File),
+        );
+        assert_eq!(changes.len(), 2);
+        assert_eq!(
+            changes.paths.get(&PathBuf::from("/ws/file1")),
+            Some(&EventKind::Remove(RemoveKind::File))
+        );
+        assert!(!changes.is_full());
+        changes.merge(Changes::full());
+        assert!(changes.is_full());
+        assert_eq!(changes.len(), 2);
+    }
+
+    #[test]
+    fn changes_roots_are_relative_to_the_workspace() {
+        let mut changes = Changes::new();
+        changes.insert(
+            PathBuf::from("/ws/dir1"),
+            EventKind::Create(CreateKind::Folder),
+        );
+        changes.insert(
+            PathBuf::from("/ws/dir2"),
+            EventKind::Modify(ModifyKind::Metadata(MetadataKind::Permissions)),
+        );
+        changes.insert(
+            PathBuf::from("/other/file1"),
+            EventKind::Modify(ModifyKind::Any),
+        );
+        assert_eq!(
+            changes.roots(Path::new("/ws")),
+            vec![
+                (PathBuf::from("dir1"), true),
+                (PathBuf::from("dir2"), false)
+            ]
+        );
+        changes.merge(Changes::full());
+        assert_eq!(
+            changes.roots(Path::new("/ws")),
+            vec![(PathBuf::new(), true)]
+        );
+    }
 }
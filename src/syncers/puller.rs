@@ -0,0 +1,146 @@
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use crate::filter::Filter;
+use crate::suppress::EventSuppressor;
+use crate::syncers::rsyncer::{RsyncGlobalProperties, RsyncProperties, Rsyncer};
+use crate::syncers::{Changes, Result as SyncerResult, Syncer};
+
+// Mirrors a remote directory into the workspace, e.g. to get hold of files built remotely. Runs
+// whenever the whole workspace is synced: when the daemon starts, on every poll and on demand.
+// Local changes are never sent anywhere
+pub struct RsyncPuller {
+    rsyncer: Rsyncer,
+    // Where in the workspace the remote directory goes, relative to its root
+    local_dir: PathBuf,
+    delete: bool,
+    poll_interval: Option<Duration>,
+    suppressor: Arc<EventSuppressor>,
+}
+
+#[async_trait]
+impl Syncer for RsyncPuller {
+    fn name(&self) -> String {
+        format!("rsync-pull:{}", self.rsyncer.destination())
+    }
+
+    async fn sync(&self, workspace_path: &Path, _changes: &Changes) -> SyncerResult {
+        let workspace = workspace_path
+            .to_str()
+            .ok_or_else(|| Error::PathEncoding(workspace_path.to_path_buf()))?;
+        // The files pulled in would otherwise be synced by the other syncers of the workspace
+        let writing = self
+            .suppressor
+            .start_writing(&workspace_path.join(&self.local_dir));
+        let (stats, files) = self
+            .rsyncer
+            .pull(workspace, &self.local_dir, self.delete)
+            .await?;
+        writing.finish(files.iter().map(|file| workspace_path.join(file)));
+        Ok(stats)
+    }
+
+    fn syncs_changes(&self) -> bool {
+        false
+    }
+
+    fn poll_interval(&self) -> Option<Duration> {
+        self.poll_interval
+    }
+
+    async fn close(&self) {
+        self.rsyncer.close().await;
+    }
+}
+
+// Takes the same properties as the rsync syncer, with the destination being pulled from instead
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RsyncPullProperties {
+    #[serde(flatten)]
+    rsync: RsyncProperties,
+    // Directory of the workspace to pull into, relative to its root. Defaults to the root itself
+    #[serde(skip_serializing_if = "Option::is_none")]
+    local_dir: Option<String>,
+    // Whether local files missing from the remote directory are deleted. The rsync DeleteAfter
+    // flag is ignored, so that global flags meant for pushing can't wipe the workspace
+    #[serde(skip_serializing_if = "Option::is_none")]
+    delete: Option<bool>,
+    // Without one, the destination is only pulled from when the whole workspace is synced
+    #[serde(skip_serializing_if = "Option::is_none")]
+    poll_interval_ms: Option<u64>,
+}
+
+impl RsyncPullProperties {
+    pub fn as_syncer(
+        &self,
+        global_props: Option<&RsyncGlobalProperties>,
+        filter: Arc<Filter>,
+        suppressor: Arc<EventSuppressor>,
+    ) -> Result<RsyncPuller> {
+        if self.rsync.is_bidirectional() {
+            return Err(Error::Config(
+                "rsync_pull syncers can't be bidirectional, use an rsync syncer instead"
+                    .to_string(),
+            ));
+        }
+        if self.poll_interval_ms == Some(0) {
+            return Err(Error::Config(
+                "poll_interval_ms must be greater than 0".to_string(),
+            ));
+        }
+        let local_dir = PathBuf::from(self.local_dir.as_deref().unwrap_or_default());
+        if !local_dir
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(Error::Config(format!(
+                "local_dir must be a directory inside the workspace, relative to it: {:?}",
+                local_dir
+            )));
+        }
+        Ok(RsyncPuller {
+            // Files the workspace leaves out are neither pulled nor deleted
+            rsyncer: self.rsync.as_syncer(global_props)?.with_filter(filter),
+            local_dir,
+            delete: self.delete.unwrap_or(false),
+            poll_interval: self.poll_interval_ms.map(Duration::from_millis),
+            suppressor,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Result;
+    use crate::filter::Filter;
+    use crate::suppress::EventSuppressor;
+    use crate::syncers::puller::{RsyncPullProperties, RsyncPuller};
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+
+    fn as_syncer(props: &str) -> Result<RsyncPuller> {
+        let props: RsyncPullProperties = serde_yaml::from_str(props).unwrap();
+        props.as_syncer(
+            None,
+            Arc::new(Filter::new(Path::new("/ws"), [], None).unwrap()),
+            Arc::new(EventSuppressor::new()),
+        )
+    }
+
+    #[test]
+    fn pulls_stay_inside_the_workspace_and_keep_local_files() {
+        let puller = as_syncer("{dst_dir: /remote, additional_flags: [DeleteAfter]}").unwrap();
+        assert_eq!(puller.local_dir, PathBuf::new());
+        assert!(!puller.delete);
+        let puller = as_syncer("{dst_dir: /remote, local_dir: out/logs, delete: true}").unwrap();
+        assert_eq!(puller.local_dir, PathBuf::from("out/logs"));
+        assert!(puller.delete);
+        assert!(as_syncer("{dst_dir: /remote, local_dir: ../elsewhere}").is_err());
+        assert!(as_syncer("{dst_dir: /remote, local_dir: /elsewhere}").is_err());
+    }
+}
@@ -1,18 +1,31 @@
 use std::fmt::Debug;
-use std::path::Path;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use async_trait::async_trait;
+use notify::event::{ModifyKind, RemoveKind};
 use notify::EventKind;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 use rand;
 use rand::distributions::Alphanumeric;
 use rand::Rng;
+use tracing::warn;
 
+use crate::error::{Error, Result};
+use crate::filter::{self, Filter};
 use crate::rsync;
 use crate::rsync::cli::{RsyncFlag, RsyncOption};
 use crate::rsync::shell::ssh::{SSHOption, SSHShell};
-use crate::syncers::{Result as SyncerResult, Syncer};
+use crate::rsync::{Direction, Remote};
+use crate::status;
+use crate::syncers::bidirectional::{
+    self, BidirectionalProperties, BidirectionalSettings, Listing, Manifest,
+};
+use crate::syncers::{Changes, Result as SyncerResult, SyncStats, Syncer};
+
+const DEFAULT_MAX_INCREMENTAL_FILES: usize = 1000;
 
 pub struct Rsyncer {
     dst_dir: String,
@@ -20,6 +33,9 @@ pub struct Rsyncer {
     flags: Vec<RsyncFlag>,
     options: Vec<RsyncOption>,
     shell: Option<SSHShell>,
+    max_incremental_files: usize,
+    filter: Option<Arc<Filter>>,
+    bidirectional: Option<BidirectionalSettings>,
 }
 
 impl Rsyncer {
@@ -29,35 +45,368 @@ impl Rsyncer {
         flags: &[RsyncFlag],
         options: &[RsyncOption],
         shell: Option<SSHShell>,
+        max_incremental_files: usize,
     ) -> Self {
         Rsyncer {
             dst_dir: String::from(dst_dir),
             dst_host: dst_host.map(String::from),
             flags: Vec::from(flags),
             options: Vec::from(options),
-            shell: shell,
+            shell,
+            max_incremental_files,
+            filter: None,
+            bidirectional: None,
+        }
+    }
+
+    // Has rsync apply the same filter as the workspace, so that nothing the watcher ignores is
+    // transferred by a full sync either
+    pub fn with_filter(mut self, filter: Arc<Filter>) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    // Changes made on the destination are synced back to the workspace too
+    pub fn with_bidirectional(mut self, settings: BidirectionalSettings) -> Self {
+        self.bidirectional = Some(settings);
+        self
+    }
+
+    // Where files are synced to, e.g. host:/remote/dir
+    pub fn destination(&self) -> String {
+        match self.dst_host.as_ref() {
+            Some(host) => format!("{}:{}", host, self.dst_dir),
+            None => self.dst_dir.clone(),
+        }
+    }
+
+    // Mirrors the destination into a directory of the workspace, given relative to it. Local
+    // files missing from the destination are only deleted when asked to, whatever the flags say.
+    // Also returns the files (relative to the workspace) that were written or deleted in the process
+    pub async fn pull(
+        &self,
+        workspace: &str,
+        local_dir: &Path,
+        delete: bool,
+    ) -> Result<(SyncStats, Vec<PathBuf>)> {
+        let local = Path::new(workspace).join(local_dir);
+        if !filter::allows(self.filter.as_deref(), &local) {
+            warn!(path = ?local, "Not pulling into a directory the workspace leaves out");
+            return Ok((SyncStats::default(), Vec::new()));
+        }
+        tokio::fs::create_dir_all(&local)
+            .await
+            .map_err(Error::io(format!("failed to create {:?}", local)))?;
+        let local_str = local
+            .to_str()
+            .ok_or_else(|| Error::PathEncoding(local.clone()))?;
+        let flags = self
+            .flags
+            .iter()
+            .filter(|flag| **flag != RsyncFlag::DeleteAfter)
+            .cloned()
+            .chain(delete.then_some(RsyncFlag::DeleteAfter))
+            .collect::<Vec<RsyncFlag>>();
+        let options = self
+            .options
+            .iter()
+            .cloned()
+            .chain([RsyncOption::OutFormat(String::from(
+                rsync::TRANSFERRED_FORMAT,
+            ))])
+            .chain(
+                self.filter
+                    .iter()
+                    .flat_map(|filter| filter.rsync_options_below(local_dir)),
+            )
+            .collect::<Vec<RsyncOption>>();
+        let output = self
+            .transfer(local_str, Direction::Pull, &flags, &options)
+            .await?;
+        let stats = SyncStats {
+            bytes_transferred: rsync::bytes_transferred(&output),
+        };
+        let files = rsync::transferred_files(&output)
+            .into_iter()
+            .map(|file| local_dir.join(file))
+            .collect();
+        Ok((stats, files))
+    }
+
+    async fn rsync(
+        &self,
+        workspace: &str,
+        direction: Direction,
+        flags: &[RsyncFlag],
+        options: &[RsyncOption],
+    ) -> SyncerResult {
+        let output = self
+            .transfer(
+                workspace,
+                direction,
+                flags,
+                &self.with_filter_options(options),
+            )
+            .await?;
+        Ok(SyncStats {
+            bytes_transferred: rsync::bytes_transferred(&output),
+        })
+    }
+
+    // Transfers between the given local directory and the destination. Filter rules are up to the
+    // caller, as they depend on where the transfer is rooted
+    async fn transfer(
+        &self,
+        local: &str,
+        direction: Direction,
+        flags: &[RsyncFlag],
+        options: &[RsyncOption],
+    ) -> Result<String> {
+        // Stats are always asked for so that the amount of data transferred can be reported
+        let flags = flags
+            .iter()
+            .cloned()
+            .chain([RsyncFlag::Stats])
+            .collect::<Vec<RsyncFlag>>();
+        let dst_dir = format!("{}/", self.dst_dir.trim_end_matches('/'));
+        let (src, dst) = match direction {
+            Direction::Push => (local, self.dst_dir.as_str()),
+            Direction::Pull => (dst_dir.as_str(), local),
+        };
+        let remote = self
+            .dst_host
+            .as_deref()
+            .map(|host| Remote::new(host, direction));
+        rsync::rsync(src, dst, remote, self.shell.as_ref(), &flags, options).await
+    }
+
+    // The rules are read at the time of the sync, as the ignore files they include may have
+    // changed since the syncer was created. The excludes of the syncer come first, so that they
+    // take precedence over the rules of the workspace
+    fn with_filter_options(&self, options: &[RsyncOption]) -> Vec<RsyncOption> {
+        self.options
+            .iter()
+            .chain(options)
+            .cloned()
+            .chain(self.filter.iter().flat_map(|filter| filter.rsync_options()))
+            .collect()
+    }
+
+    // Lists the files subject to syncing on the side a transfer in the given direction would
+    // start from: the workspace when pushing, the destination when pulling
+    async fn list(&self, workspace: &str, direction: Direction) -> Result<Listing> {
+        let flags = [RsyncFlag::ListOnly, RsyncFlag::Recursive]
+            .into_iter()
+            .chain(
+                self.flags
+                    .iter()
+                    .filter(|flag| **flag == RsyncFlag::IncludeLinks)
+                    .cloned(),
+            )
+            .collect::<Vec<RsyncFlag>>();
+        let options = self.with_filter_options(&[]);
+        let dst_dir = format!("{}/", self.dst_dir.trim_end_matches('/'));
+        // Nothing gets transferred with --list-only, the destination is only there to keep the
+        // host out of it when listing the workspace
+        let output = match direction {
+            Direction::Push => {
+                rsync::rsync(workspace, workspace, None, None, &flags, &options).await?
+            }
+            Direction::Pull => {
+                let remote = self
+                    .dst_host
+                    .as_deref()
+                    .map(|host| Remote::new(host, Direction::Pull));
+                rsync::rsync(
+                    &dst_dir,
+                    workspace,
+                    remote,
+                    self.shell.as_ref(),
+                    &flags,
+                    &options,
+                )
+                .await?
+            }
+        };
+        Ok(bidirectional::parse_listing(&output))
+    }
+
+    // Both sides are compared with the state the last sync left them in, so the whole workspace
+    // is looked at whichever files changed locally
+    async fn sync_both_ways(
+        &self,
+        workspace: &str,
+        settings: &BidirectionalSettings,
+    ) -> SyncerResult {
+        let manifest_path = settings.manifest_path(workspace, &self.name());
+        let manifest = Manifest::load(&manifest_path).await?;
+        let local = self.list(workspace, Direction::Push).await?;
+        let remote = self.list(workspace, Direction::Pull).await?;
+        let plan = bidirectional::plan(
+            &manifest,
+            &local,
+            &remote,
+            settings.conflict_policy,
+            &status::unix_now().to_string(),
+        );
+        for path in &plan.conflicts {
+            warn!(path = ?path, policy = ?settings.conflict_policy, "Both sides changed since the last sync");
+        }
+        for (path, copy) in &plan.keep_both {
+            let (path, copy) = (
+                Path::new(workspace).join(path),
+                Path::new(workspace).join(copy),
+            );
+            tokio::fs::rename(&path, &copy)
+                .await
+                .map_err(Error::io(format!("failed to keep a copy of {:?}", path)))?;
+        }
+        let mut stats = SyncStats::default();
+        for (direction, files) in [(Direction::Push, &plan.push), (Direction::Pull, &plan.pull)] {
+            if files.is_empty() {
+                continue;
+            }
+            let file_list = FileList::create(files).await?;
+            // Deletions are only ever made explicitly, and keeping modification times is what
+            // lets the next sync tell the transferred files haven't changed since
+            let flags = self
+                .flags
+                .iter()
+                .filter(|flag| **flag != RsyncFlag::DeleteAfter)
+                .cloned()
+                .chain([
+                    RsyncFlag::PreserveModTimes,
+                    RsyncFlag::From0,
+                    RsyncFlag::DeleteMissingArgs,
+                ])
+                .collect::<Vec<RsyncFlag>>();
+            let options = [RsyncOption::FilesFrom(file_list.path.clone())];
+            let transferred = self.rsync(workspace, direction, &flags, &options).await?;
+            if let Some(bytes) = transferred.bytes_transferred {
+                stats.bytes_transferred = Some(stats.bytes_transferred.unwrap_or(0) + bytes);
+            }
         }
+        plan.manifest.save(&manifest_path).await?;
+        Ok(stats)
     }
 }
 
 #[async_trait]
 impl Syncer for Rsyncer {
-    async fn sync(&self, workspace_path: &Path, _file_path: &Path, _kind: EventKind) -> SyncerResult {
-        rsync::rsync(
-            workspace_path.to_str().unwrap(),
-            self.dst_dir.as_ref(),
-            self.dst_host.as_deref(),
-            None,
-            self.shell.as_ref(),
-            self.flags.as_slice(),
-            self.options.as_slice(),
-        )
-        .await
+    fn name(&self) -> String {
+        format!("rsync:{}", self.destination())
+    }
+
+    async fn sync(&self, workspace_path: &Path, changes: &Changes) -> SyncerResult {
+        let src = workspace_path
+            .to_str()
+            .ok_or_else(|| Error::PathEncoding(workspace_path.to_path_buf()))?;
+        if let Some(settings) = &self.bidirectional {
+            return self.sync_both_ways(src, settings).await;
+        }
+        let files = match incremental_files(workspace_path, changes, self.max_incremental_files) {
+            Some(files) => files,
+            None => return self.rsync(src, Direction::Push, &self.flags, &[]).await,
+        };
+        if files.is_empty() {
+            return Ok(SyncStats::default());
+        }
+        let file_list = FileList::create(&files).await?;
+        // Files that no longer exist locally are passed along too, so that rsync deletes them
+        // from the destination instead of failing on them
+        let flags = self
+            .flags
+            .iter()
+            .cloned()
+            .chain([RsyncFlag::From0, RsyncFlag::DeleteMissingArgs])
+            .collect::<Vec<RsyncFlag>>();
+        let options = [RsyncOption::FilesFrom(file_list.path.clone())];
+        self.rsync(src, Direction::Push, &flags, &options).await
+    }
+
+    async fn close(&self) {
+        if let Some(shell) = self.shell.as_ref() {
+            shell.close(self.dst_host.as_deref()).await;
+        }
     }
 }
 
-#[derive(Debug, PartialEq, Clone, Deserialize)]
+// Works out which files (relative to the workspace) need to be transferred for a batch of
+// changes. None means the batch can't be reliably expressed as a list of files, and the whole
+// workspace should be synced instead
+fn incremental_files(
+    workspace_path: &Path,
+    changes: &Changes,
+    max_files: usize,
+) -> Option<Vec<PathBuf>> {
+    if changes.is_full() || changes.len() > max_files {
+        return None;
+    }
+    let mut files = Vec::new();
+    for (path, kind) in changes.iter() {
+        // A directory that was removed or renamed away can't be told apart from a file anymore,
+        // and --delete-missing-args leaves non-empty directories in place. FSEvents doesn't
+        // always say what was removed
+        let requires_full_sync = match kind {
+            EventKind::Remove(RemoveKind::Folder | RemoveKind::Any) => true,
+            EventKind::Modify(ModifyKind::Name(_)) => {
+                path.is_dir() || std::fs::symlink_metadata(path).is_err()
+            }
+            _ => false,
+        };
+        if requires_full_sync {
+            return None;
+        }
+        if let Ok(relative) = path.strip_prefix(workspace_path) {
+            if relative.as_os_str().is_empty() {
+                return None;
+            }
+            files.push(relative.to_path_buf());
+        }
+    }
+    Some(files)
+}
+
+// NUL separated list of files handed to rsync through --files-from. The file is removed when
+// the list is dropped
+struct FileList {
+    path: String,
+}
+
+impl FileList {
+    async fn create(files: &[PathBuf]) -> Result<Self> {
+        let mut path = std::env::temp_dir();
+        path.push("rust-dev-sync-files-".to_owned() + &generate_session_id());
+        let path = path
+            .into_os_string()
+            .into_string()
+            .map_err(|path| Error::PathEncoding(path.into()))?;
+        let mut data = Vec::new();
+        for file in files {
+            data.extend_from_slice(file.as_os_str().as_bytes());
+            data.push(b'\0');
+        }
+        tokio::fs::write(&path, data)
+            .await
+            .map_err(Error::io(format!(
+                "failed to write rsync file list {}",
+                path
+            )))?;
+        Ok(FileList { path })
+    }
+}
+
+impl Drop for FileList {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!("Error removing rsync file list: {:?}", e);
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
 pub struct SSHProperties {
+    #[serde(skip_serializing_if = "Option::is_none")]
     options: Option<Vec<SSHOption>>,
 }
 
@@ -80,74 +429,99 @@ impl SSHProperties {
         SSHProperties { options }
     }
 
-    fn as_shell(&self) -> SSHShell {
+    fn as_shell(&self) -> Result<SSHShell> {
+        let mut generated = false;
         let options = self
             .options
-            .as_ref()
-            .map(|opts| {
-                opts.iter()
-                    .map(|opt| match opt {
-                        SSHOption::ControlPath(ref path) if path == "GENERATE" => {
-                            let session_id = generate_session_id();
-                            let cp = generate_control_path(&session_id);
-                            SSHOption::ControlPath(cp)
-                        }
-                        _ => opt.clone(),
-                    })
-                    .collect()
+            .iter()
+            .flatten()
+            .map(|opt| match opt {
+                SSHOption::ControlPath(ref path) if path == "GENERATE" => {
+                    generated = true;
+                    let session_id = generate_session_id();
+                    generate_control_path(&session_id).map(SSHOption::ControlPath)
+                }
+                _ => Ok(opt.clone()),
             })
-            .unwrap_or(Vec::new());
-        SSHShell::new(options)
+            .collect::<Result<Vec<SSHOption>>>()?;
+        let shell = SSHShell::new(options);
+        Ok(if generated {
+            shell.with_generated_control_path()
+        } else {
+            shell
+        })
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct RsyncProperties {
+    #[serde(skip_serializing_if = "Option::is_none")]
     dst_host: Option<String>,
     dst_dir: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     additional_flags: Option<Vec<RsyncFlag>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     additional_excludes: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     ssh: Option<SSHAdditionalProperties>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_incremental_files: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bidirectional: Option<BidirectionalProperties>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct RsyncGlobalProperties {
+    #[serde(skip_serializing_if = "Option::is_none")]
     default_dst_host: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     excludes: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     flags: Option<Vec<RsyncFlag>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     ssh: Option<SSHProperties>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_incremental_files: Option<usize>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct SSHAdditionalProperties {
+    #[serde(skip_serializing_if = "Option::is_none")]
     additional_options: Option<Vec<SSHOption>>,
 }
 
+impl RsyncGlobalProperties {
+    pub fn excludes(&self) -> &[String] {
+        self.excludes.as_deref().unwrap_or_default()
+    }
+}
+
 impl RsyncProperties {
-    pub fn as_syncer(&self, global_props: Option<&RsyncGlobalProperties>) -> Rsyncer {
-        let excludes = self
+    pub fn is_bidirectional(&self) -> bool {
+        self.bidirectional.is_some()
+    }
+
+    // The global excludes are part of the workspace rules, these only apply to this syncer
+    pub fn as_syncer(&self, global_props: Option<&RsyncGlobalProperties>) -> Result<Rsyncer> {
+        // A pattern starting with ! is an exception to the patterns before it. rsync goes by the
+        // first matching rule, so the rules are listed from last to first
+        let mut excludes = self
             .additional_excludes
             .iter()
             .flatten()
-            .chain(
-                global_props
-                    .map(|prop| prop.excludes.as_ref())
-                    .flatten()
-                    .iter()
-                    .cloned()
-                    .flatten(),
-            )
-            .cloned()
-            .map(RsyncOption::Exclude)
+            .map(|pattern| match pattern.strip_prefix('!') {
+                Some(pattern) => RsyncOption::Include(String::from(pattern)),
+                None => RsyncOption::Exclude(pattern.clone()),
+            })
             .collect::<Vec<RsyncOption>>();
+        excludes.reverse();
         let flags = self
             .additional_flags
             .iter()
             .flatten()
             .chain(
                 global_props
-                    .map(|prop| prop.flags.as_ref())
-                    .flatten()
+                    .and_then(|prop| prop.flags.as_ref())
                     .iter()
                     .cloned()
                     .flatten(),
@@ -156,29 +530,60 @@ impl RsyncProperties {
             .collect::<Vec<RsyncFlag>>();
         let dst_host = self.dst_host.clone().or_else(|| {
             global_props
-                .map(|prop| prop.default_dst_host.as_ref())
-                .flatten()
+                .and_then(|prop| prop.default_dst_host.as_ref())
                 .cloned()
         });
-        let shell = global_props
-            .map(|props| props.ssh.as_ref())
-            .flatten()
-            .as_deref()
-            .or_else(|| self.ssh.as_ref().map(|_| &SSHProperties { options: None }))
-            .map(|props| props.merge(self.ssh.as_ref()))
-            .map(|props| props.as_shell());
-        Rsyncer::new(&self.dst_dir, dst_host.as_deref(), &flags, &excludes, shell)
+        let shell = ssh_shell(global_props, self.ssh.as_ref())?;
+        let max_incremental_files = self
+            .max_incremental_files
+            .or_else(|| global_props.and_then(|prop| prop.max_incremental_files))
+            .unwrap_or(DEFAULT_MAX_INCREMENTAL_FILES);
+        let rsyncer = Rsyncer::new(
+            &self.dst_dir,
+            dst_host.as_deref(),
+            &flags,
+            &excludes,
+            shell,
+            max_incremental_files,
+        );
+        match &self.bidirectional {
+            Some(props) => Ok(rsyncer.with_bidirectional(props.as_settings()?)),
+            None => Ok(rsyncer),
+        }
     }
 }
 
-fn generate_control_path(session_id: &str) -> String {
-    let mut control_path = home::home_dir().unwrap();
+// The ssh options of a syncer on top of the global ones. None when neither sets any
+pub fn ssh_shell(
+    global_props: Option<&RsyncGlobalProperties>,
+    ssh: Option<&SSHAdditionalProperties>,
+) -> Result<Option<SSHShell>> {
+    global_props
+        .and_then(|props| props.ssh.as_ref())
+        .or_else(|| ssh.map(|_| &SSHProperties { options: None }))
+        .map(|props| props.merge(ssh))
+        .map(|props| props.as_shell())
+        .transpose()
+}
+
+// Where a syncer reaching a host goes when it doesn't name one
+pub fn default_dst_host(global_props: Option<&RsyncGlobalProperties>) -> Option<&str> {
+    global_props.and_then(|props| props.default_dst_host.as_deref())
+}
+
+fn generate_control_path(session_id: &str) -> Result<String> {
+    let mut control_path = home::home_dir().ok_or_else(|| {
+        Error::Config("unable to generate an ssh ControlPath: no home directory".to_string())
+    })?;
     control_path.push(".ssh");
-    control_path.push("rust-dev-sync-".to_owned() + &session_id);
-    control_path.into_os_string().into_string().unwrap()
+    control_path.push("rust-dev-sync-".to_owned() + session_id);
+    control_path
+        .into_os_string()
+        .into_string()
+        .map_err(|path| Error::PathEncoding(path.into()))
 }
 
-fn generate_session_id() -> String {
+pub fn generate_session_id() -> String {
     rand::thread_rng()
         .sample_iter(&Alphanumeric)
         .take(7)
@@ -191,8 +596,13 @@ mod tests {
     use crate::rsync::cli::{RsyncFlag, RsyncOption};
     use crate::rsync::shell::ssh::SSHOption;
     use crate::syncers::rsyncer::{
-        RsyncGlobalProperties, RsyncProperties, SSHAdditionalProperties, SSHProperties,
+        incremental_files, RsyncGlobalProperties, RsyncProperties, SSHAdditionalProperties,
+        SSHProperties, DEFAULT_MAX_INCREMENTAL_FILES,
     };
+    use crate::syncers::{Changes, Syncer};
+    use notify::event::{CreateKind, ModifyKind, RemoveKind, RenameMode};
+    use notify::EventKind;
+    use std::path::{Path, PathBuf};
 
     #[test]
     fn test_sshproperties_merge() {
@@ -236,7 +646,7 @@ mod tests {
                 SSHOption::ControlPath("GENERATE".to_string()),
             ]),
         };
-        let shell = props.as_shell();
+        let shell = props.as_shell().unwrap();
         let cp = shell.options.iter().find(|opt| match opt {
             SSHOption::ControlPath(x) => x.contains(".ssh/rust-dev-sync-"),
             _ => false,
@@ -260,6 +670,8 @@ mod tests {
                     SSHOption::PasswordAuthentication(false),
                 ]),
             }),
+            max_incremental_files: None,
+            bidirectional: None,
         };
         let global_props = RsyncGlobalProperties {
             default_dst_host: Some("default-host".to_string()),
@@ -274,12 +686,15 @@ mod tests {
                     SSHOption::ServerAliveCountMax(12),
                 ]),
             }),
+            max_incremental_files: Some(50),
         };
-        let rsyncer = props.as_syncer(Some(&global_props));
-        let rsyncer_options = rsyncer.options;
-        assert!(rsyncer_options.contains(&RsyncOption::Exclude("additional-exclude-1".to_string())));
-        assert!(rsyncer_options.contains(&RsyncOption::Exclude("global-exclude-1".to_string())));
-        assert!(rsyncer_options.contains(&RsyncOption::Exclude("global-exclude-2".to_string())));
+        let rsyncer = props.as_syncer(Some(&global_props)).unwrap();
+        assert_eq!(rsyncer.name(), "rsync:override-host:/remote/dir");
+        // The global excludes are left to the workspace filter
+        assert_eq!(
+            rsyncer.options,
+            vec![RsyncOption::Exclude("additional-exclude-1".to_string())]
+        );
         let rsyncer_flags = rsyncer.flags;
         assert!(rsyncer_flags.contains(&RsyncFlag::Recursive));
         assert!(rsyncer_flags.contains(&RsyncFlag::DeleteAfter));
@@ -294,6 +709,7 @@ mod tests {
         assert!(rsyncer_ssh
             .options
             .contains(&SSHOption::ServerAliveCountMax(12)));
+        assert_eq!(rsyncer.max_incremental_files, 50);
     }
 
     #[test]
@@ -309,14 +725,17 @@ mod tests {
                     SSHOption::PasswordAuthentication(false),
                 ]),
             }),
+            max_incremental_files: Some(10),
+            bidirectional: None,
         };
         let global_props = RsyncGlobalProperties {
             default_dst_host: None,
             excludes: None,
             flags: None,
             ssh: None,
+            max_incremental_files: None,
         };
-        let rsyncer = props.as_syncer(Some(&global_props));
+        let rsyncer = props.as_syncer(Some(&global_props)).unwrap();
         let rsyncer_options = rsyncer.options;
         assert!(rsyncer_options.contains(&RsyncOption::Exclude("additional-exclude-1".to_string())));
         let rsyncer_flags = rsyncer.flags;
@@ -328,6 +747,7 @@ mod tests {
         assert!(rsyncer_ssh
             .options
             .contains(&SSHOption::PasswordAuthentication(false)));
+        assert_eq!(rsyncer.max_incremental_files, 10);
     }
 
     #[test]
@@ -338,6 +758,8 @@ mod tests {
             additional_flags: None,
             additional_excludes: None,
             ssh: None,
+            max_incremental_files: None,
+            bidirectional: None,
         };
         let global_props = RsyncGlobalProperties {
             default_dst_host: Some("default-host".to_string()),
@@ -352,11 +774,9 @@ mod tests {
                     SSHOption::ServerAliveCountMax(12),
                 ]),
             }),
+            max_incremental_files: None,
         };
-        let rsyncer = props.as_syncer(Some(&global_props));
-        let rsyncer_options = rsyncer.options;
-        assert!(rsyncer_options.contains(&RsyncOption::Exclude("global-exclude-1".to_string())));
-        assert!(rsyncer_options.contains(&RsyncOption::Exclude("global-exclude-2".to_string())));
+        let rsyncer = props.as_syncer(Some(&global_props)).unwrap();
         let rsyncer_flags = rsyncer.flags;
         assert!(rsyncer_flags.contains(&RsyncFlag::Recursive));
         assert!(rsyncer_flags.contains(&RsyncFlag::DeleteAfter));
@@ -367,5 +787,102 @@ mod tests {
         assert!(rsyncer_ssh
             .options
             .contains(&SSHOption::ServerAliveCountMax(12)));
+        assert_eq!(rsyncer.max_incremental_files, DEFAULT_MAX_INCREMENTAL_FILES);
+        assert!(rsyncer.options.is_empty());
+    }
+
+    #[test]
+    fn test_rsyncproperties_as_syncer_with_negated_excludes() {
+        let props = RsyncProperties {
+            dst_host: None,
+            dst_dir: "/remote/dir".to_string(),
+            additional_flags: None,
+            additional_excludes: Some(vec![
+                "build/*".to_string(),
+                "!build/config.json".to_string(),
+            ]),
+            ssh: None,
+            max_incremental_files: None,
+            bidirectional: None,
+        };
+        let rsyncer = props.as_syncer(None).unwrap();
+        assert_eq!(
+            rsyncer.options,
+            vec![
+                RsyncOption::Include("build/config.json".to_string()),
+                RsyncOption::Exclude("build/*".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_incremental_files_are_relative_to_workspace() {
+        let mut changes = Changes::new();
+        changes.insert(
+            PathBuf::from("/ws/dir1/file1"),
+            EventKind::Create(CreateKind::File),
+        );
+        changes.insert(
+            PathBuf::from("/ws/file2"),
+            EventKind::Remove(RemoveKind::File),
+        );
+        changes.insert(
+            PathBuf::from("/elsewhere/file3"),
+            EventKind::Modify(ModifyKind::Any),
+        );
+        let files = incremental_files(Path::new("/ws/"), &changes, 10).unwrap();
+        assert_eq!(
+            files,
+            vec![PathBuf::from("dir1/file1"), PathBuf::from("file2")]
+        );
+    }
+
+    #[test]
+    fn test_incremental_files_falls_back_to_full_sync() {
+        let mut changes = Changes::new();
+        changes.insert(
+            PathBuf::from("/ws/file1"),
+            EventKind::Modify(ModifyKind::Any),
+        );
+        changes.insert(
+            PathBuf::from("/ws/file2"),
+            EventKind::Modify(ModifyKind::Any),
+        );
+        assert!(incremental_files(Path::new("/ws/"), &changes, 1).is_none());
+        assert!(incremental_files(Path::new("/ws/"), &Changes::full(), 10).is_none());
+
+        let mut changes = Changes::new();
+        changes.insert(
+            PathBuf::from("/ws/dir1"),
+            EventKind::Remove(RemoveKind::Folder),
+        );
+        assert!(incremental_files(Path::new("/ws/"), &changes, 10).is_none());
+
+        let workspace = tempfile::tempdir().unwrap();
+        let renamed_dir = workspace.path().join("renamed");
+        std::fs::create_dir(&renamed_dir).unwrap();
+        let mut changes = Changes::new();
+        changes.insert(
+            renamed_dir.clone(),
+            EventKind::Modify(ModifyKind::Name(RenameMode::To)),
+        );
+        let files = incremental_files(workspace.path(), &changes, 10);
+        std::fs::remove_dir(&renamed_dir).unwrap();
+        assert!(files.is_none());
+
+        // Once renamed away, the directory is gone and could have been a file
+        let mut changes = Changes::new();
+        changes.insert(
+            renamed_dir,
+            EventKind::Modify(ModifyKind::Name(RenameMode::From)),
+        );
+        assert!(incremental_files(workspace.path(), &changes, 10).is_none());
+
+        let mut changes = Changes::new();
+        changes.insert(
+            PathBuf::from("/ws/dir1"),
+            EventKind::Remove(RemoveKind::Any),
+        );
+        assert!(incremental_files(Path::new("/ws/"), &changes, 10).is_none());
     }
 }
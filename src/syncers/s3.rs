@@ -0,0 +1,684 @@
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use aws_config::BehaviorVersion;
+use aws_sdk_s3::config::http::HttpResponse;
+use aws_sdk_s3::config::Region;
+use aws_sdk_s3::error::SdkError;
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart, Delete, ObjectIdentifier};
+use aws_sdk_s3::Client;
+use md5::{Digest, Md5};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncReadExt;
+use tokio::sync::OnceCell;
+use tracing::debug;
+
+use crate::error::{Error, Result};
+use crate::filter::{self, Filter};
+use crate::syncers::{Changes, Result as SyncerResult, SyncStats, Syncer};
+
+const MIB: u64 = 1024 * 1024;
+const DEFAULT_MULTIPART_THRESHOLD_MB: u64 = 8;
+const DEFAULT_MULTIPART_PART_SIZE_MB: u64 = 8;
+// S3 doesn't accept smaller parts, other than the last one
+const MIN_MULTIPART_PART_SIZE_MB: u64 = 5;
+// Most keys a single DeleteObjects request takes
+const MAX_DELETE_KEYS: usize = 1000;
+
+// An object as listed in the bucket
+#[derive(Debug, PartialEq)]
+pub struct RemoteObject {
+    pub etag: String,
+    pub size: u64,
+}
+
+// Where to find the bucket and how to sign in
+struct ClientSettings {
+    region: Option<String>,
+    endpoint: Option<String>,
+    profile: Option<String>,
+}
+
+// A bucket along with the client to reach it with, which is only set up once first needed
+pub struct S3Bucket {
+    pub name: String,
+    settings: ClientSettings,
+    client: OnceCell<Client>,
+}
+
+impl S3Bucket {
+    // Endpoints other than AWS (MinIO and the like) are addressed with the bucket in the path
+    // rather than in the host name, and default to the region MinIO uses rather than looking for
+    // one in the environment
+    pub async fn client(&self) -> &Client {
+        self.client
+            .get_or_init(|| async {
+                let settings = &self.settings;
+                let mut loader = aws_config::defaults(BehaviorVersion::latest());
+                if let Some(profile) = &settings.profile {
+                    loader = loader.profile_name(profile);
+                }
+                let region = match (&settings.region, &settings.endpoint) {
+                    (Some(region), _) => Some(region.clone()),
+                    (None, Some(_)) => Some(String::from("us-east-1")),
+                    (None, None) => None,
+                };
+                if let Some(region) = region {
+                    loader = loader.region(Region::new(region));
+                }
+                let sdk_config = loader.load().await;
+                let mut config = aws_sdk_s3::config::Builder::from(&sdk_config);
+                if let Some(endpoint) = &settings.endpoint {
+                    config = config.endpoint_url(endpoint).force_path_style(true);
+                }
+                Client::from_conf(config.build())
+            })
+            .await
+    }
+
+    pub async fn head(&self, key: &str) -> Result<Option<RemoteObject>> {
+        let result = self
+            .client()
+            .await
+            .head_object()
+            .bucket(&self.name)
+            .key(key)
+            .send()
+            .await;
+        match result {
+            Ok(output) => Ok(Some(RemoteObject {
+                etag: output
+                    .e_tag()
+                    .unwrap_or_default()
+                    .trim_matches('"')
+                    .to_string(),
+                size: output.content_length().unwrap_or_default() as u64,
+            })),
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_not_found()) => Ok(None),
+            Err(e) => Err(s3_error(format!(
+                "failed to look up s3://{}/{}",
+                self.name, key
+            ))(e)),
+        }
+    }
+
+    pub async fn list(&self, prefix: &str) -> Result<HashMap<String, RemoteObject>> {
+        let mut objects = HashMap::new();
+        let mut continuation_token = None;
+        loop {
+            let output = self
+                .client()
+                .await
+                .list_objects_v2()
+                .bucket(&self.name)
+                .prefix(prefix)
+                .set_continuation_token(continuation_token)
+                .send()
+                .await
+                .map_err(s3_error(format!(
+                    "failed to list objects in s3://{}/{}",
+                    self.name, prefix
+                )))?;
+            for object in output.contents() {
+                if let Some(key) = object.key() {
+                    objects.insert(
+                        key.to_string(),
+                        RemoteObject {
+                            etag: object
+                                .e_tag()
+                                .unwrap_or_default()
+                                .trim_matches('"')
+                                .to_string(),
+                            size: object.size().unwrap_or_default() as u64,
+                        },
+                    );
+                }
+            }
+            continuation_token = output.next_continuation_token().map(String::from);
+            if continuation_token.is_none() {
+                return Ok(objects);
+            }
+        }
+    }
+}
+
+// Mirrors the workspace into a bucket, under a prefix. Files are uploaded under their path
+// relative to the workspace and objects whose file no longer exists are deleted. Files are only
+// uploaded when their ETag differs from the one of the object, which is computed locally the
+// same way S3 does: the MD5 of the file, or for multipart uploads the MD5 of the MD5 of every part
+pub struct S3Syncer {
+    bucket: S3Bucket,
+    prefix: String,
+    multipart_threshold: u64,
+    part_size: u64,
+    filter: Option<Arc<Filter>>,
+}
+
+impl S3Syncer {
+    // Files the workspace leaves out are neither uploaded nor deleted from the bucket
+    pub fn with_filter(mut self, filter: Arc<Filter>) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    fn key(&self, relative: &Path) -> Result<String> {
+        let relative = relative
+            .to_str()
+            .ok_or_else(|| Error::PathEncoding(relative.to_path_buf()))?;
+        Ok(object_key(&self.prefix, relative))
+    }
+
+    // Makes the objects under the path's key the same as the path. The content of directories is
+    // only looked at when recursive. Returns the number of bytes uploaded
+    async fn mirror(&self, workspace: &Path, path: &Path, recursive: bool) -> Result<u64> {
+        let relative = match path.strip_prefix(workspace) {
+            Ok(relative) => relative,
+            Err(_) => return Ok(0),
+        };
+        let key = self.key(relative)?;
+        let dir_prefix = match key.is_empty() {
+            true => String::new(),
+            false => format!("{}/", key),
+        };
+        match tokio::fs::metadata(path).await {
+            Ok(metadata) if metadata.is_dir() => match recursive {
+                true => self.mirror_dir(workspace, path, &dir_prefix).await,
+                false => Ok(0),
+            },
+            Ok(_) => {
+                let remote = self.bucket.head(&key).await?;
+                self.upload_if_changed(path, &key, remote.as_ref()).await
+            }
+            // Whatever was there may have been a file or a directory
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                let stale = self
+                    .bucket
+                    .list(&dir_prefix)
+                    .await?
+                    .into_keys()
+                    .chain([key])
+                    .filter(|key| self.syncs_key(workspace, key))
+                    .collect::<Vec<_>>();
+                self.delete(stale).await?;
+                Ok(0)
+            }
+            Err(e) => Err(Error::io(format!("failed to read {:?}", path))(e)),
+        }
+    }
+
+    async fn mirror_dir(&self, workspace: &Path, dir: &Path, dir_prefix: &str) -> Result<u64> {
+        let mut remote = self.bucket.list(dir_prefix).await?;
+        let mut bytes = 0;
+        for file in self.local_files(dir).await? {
+            let relative = file.strip_prefix(workspace).unwrap_or(&file);
+            let key = self.key(relative)?;
+            let object = remote.remove(&key);
+            bytes += self.upload_if_changed(&file, &key, object.as_ref()).await?;
+        }
+        let stale = remote
+            .into_keys()
+            .filter(|key| self.syncs_key(workspace, key))
+            .collect::<Vec<_>>();
+        self.delete(stale).await?;
+        Ok(bytes)
+    }
+
+    // Objects of files the workspace leaves out are left alone
+    fn syncs_key(&self, workspace: &Path, key: &str) -> bool {
+        let relative = key
+            .strip_prefix(self.prefix.as_str())
+            .unwrap_or(key)
+            .trim_start_matches('/');
+        filter::allows(self.filter.as_deref(), &workspace.join(relative))
+    }
+
+    // Every file in the directory, following symlinks. Symlinks that lead nowhere are skipped
+    async fn local_files(&self, dir: &Path) -> Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        let mut pending = vec![dir.to_path_buf()];
+        while let Some(dir) = pending.pop() {
+            let mut entries = tokio::fs::read_dir(&dir)
+                .await
+                .map_err(Error::io(format!("failed to list {:?}", dir)))?;
+            while let Some(entry) = entries
+                .next_entry()
+                .await
+                .map_err(Error::io(format!("failed to list {:?}", dir)))?
+            {
+                let path = entry.path();
+                if !filter::allows(self.filter.as_deref(), &path) {
+                    continue;
+                }
+                match tokio::fs::metadata(&path).await {
+                    Ok(metadata) if metadata.is_dir() => pending.push(path),
+                    Ok(metadata) if metadata.is_file() => files.push(path),
+                    _ => debug!(path = ?path, "Skipping file that can't be uploaded"),
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    async fn upload_if_changed(
+        &self,
+        path: &Path,
+        key: &str,
+        remote: Option<&RemoteObject>,
+    ) -> Result<u64> {
+        let local = self.local_object(path).await?;
+        if remote == Some(&local) {
+            return Ok(0);
+        }
+        match local.size > self.multipart_threshold {
+            true => self.upload_multipart(path, key).await?,
+            false => self.upload(path, key).await?,
+        }
+        Ok(local.size)
+    }
+
+    // What the object would look like if the file was uploaded
+    async fn local_object(&self, path: &Path) -> Result<RemoteObject> {
+        let mut file = tokio::fs::File::open(path)
+            .await
+            .map_err(Error::io(format!("failed to open {:?}", path)))?;
+        let mut whole = Md5::new();
+        let mut parts = Vec::new();
+        let mut size = 0;
+        loop {
+            let chunk = read_part(&mut file, self.part_size)
+                .await
+                .map_err(Error::io(format!("failed to read {:?}", path)))?;
+            if chunk.is_empty() {
+                break;
+            }
+            size += chunk.len() as u64;
+            whole.update(&chunk);
+            parts.push(Md5::digest(&chunk));
+        }
+        let etag = match size > self.multipart_threshold {
+            true => multipart_etag(&parts),
+            false => hex::encode(whole.finalize()),
+        };
+        Ok(RemoteObject { etag, size })
+    }
+
+    async fn upload(&self, path: &Path, key: &str) -> Result {
+        let body = tokio::fs::read(path)
+            .await
+            .map_err(Error::io(format!("failed to read {:?}", path)))?;
+        self.bucket
+            .client()
+            .await
+            .put_object()
+            .bucket(&self.bucket.name)
+            .key(key)
+            .body(ByteStream::from(body))
+            .send()
+            .await
+            .map_err(s3_error(format!("failed to upload {:?}", path)))?;
+        Ok(())
+    }
+
+    // An upload that fails half way is aborted, so that the parts already uploaded don't linger
+    async fn upload_multipart(&self, path: &Path, key: &str) -> Result {
+        let client = self.bucket.client().await;
+        let upload = client
+            .create_multipart_upload()
+            .bucket(&self.bucket.name)
+            .key(key)
+            .send()
+            .await
+            .map_err(s3_error(format!("failed to start uploading {:?}", path)))?;
+        let upload_id = upload.upload_id().unwrap_or_default();
+        let result = self.upload_parts(path, key, upload_id).await;
+        if result.is_err() {
+            let _ = client
+                .abort_multipart_upload()
+                .bucket(&self.bucket.name)
+                .key(key)
+                .upload_id(upload_id)
+                .send()
+                .await;
+        }
+        result
+    }
+
+    async fn upload_parts(&self, path: &Path, key: &str, upload_id: &str) -> Result {
+        let client = self.bucket.client().await;
+        let mut file = tokio::fs::File::open(path)
+            .await
+            .map_err(Error::io(format!("failed to open {:?}", path)))?;
+        let mut parts = Vec::new();
+        loop {
+            let chunk = read_part(&mut file, self.part_size)
+                .await
+                .map_err(Error::io(format!("failed to read {:?}", path)))?;
+            if chunk.is_empty() {
+                break;
+            }
+            let part_number = parts.len() as i32 + 1;
+            let part = client
+                .upload_part()
+                .bucket(&self.bucket.name)
+                .key(key)
+                .upload_id(upload_id)
+                .part_number(part_number)
+                .body(ByteStream::from(chunk))
+                .send()
+                .await
+                .map_err(s3_error(format!(
+                    "failed to upload part {} of {:?}",
+                    part_number, path
+                )))?;
+            parts.push(
+                CompletedPart::builder()
+                    .set_e_tag(part.e_tag().map(String::from))
+                    .part_number(part_number)
+                    .build(),
+            );
+        }
+        client
+            .complete_multipart_upload()
+            .bucket(&self.bucket.name)
+            .key(key)
+            .upload_id(upload_id)
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(parts))
+                    .build(),
+            )
+            .send()
+            .await
+            .map_err(s3_error(format!("failed to finish uploading {:?}", path)))?;
+        Ok(())
+    }
+
+    async fn delete(&self, keys: Vec<String>) -> Result {
+        for keys in keys.chunks(MAX_DELETE_KEYS) {
+            let objects = keys
+                .iter()
+                .map(|key| ObjectIdentifier::builder().key(key).build())
+                .collect::<std::result::Result<Vec<_>, _>>()
+                .map_err(|e| Error::Config(e.to_string()))?;
+            let delete = Delete::builder()
+                .set_objects(Some(objects))
+                .quiet(true)
+                .build()
+                .map_err(|e| Error::Config(e.to_string()))?;
+            let output = self
+                .bucket
+                .client()
+                .await
+                .delete_objects()
+                .bucket(&self.bucket.name)
+                .delete(delete)
+                .send()
+                .await
+                .map_err(s3_error(format!(
+                    "failed to delete objects from s3://{}",
+                    self.bucket.name
+                )))?;
+            if let Some(error) = output.errors().first() {
+                return Err(Error::S3 {
+                    context: format!(
+                        "failed to delete s3://{}/{}",
+                        self.bucket.name,
+                        error.key().unwrap_or_default()
+                    ),
+                    transient: false,
+                    source: error.message().unwrap_or("unknown error").into(),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Syncer for S3Syncer {
+    fn name(&self) -> String {
+        match self.prefix.is_empty() {
+            true => format!("s3://{}", self.bucket.name),
+            false => format!("s3://{}/{}", self.bucket.name, self.prefix),
+        }
+    }
+
+    async fn sync(&self, workspace_path: &Path, changes: &Changes) -> SyncerResult {
+        let mut bytes = 0;
+        for (relative, recursive) in changes.roots(workspace_path) {
+            let path = workspace_path.join(relative);
+            bytes += self.mirror(workspace_path, &path, recursive).await?;
+        }
+        Ok(SyncStats {
+            bytes_transferred: Some(bytes),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct S3BucketProperties {
+    bucket: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    region: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    endpoint: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    profile: Option<String>,
+}
+
+impl S3BucketProperties {
+    pub fn as_bucket(&self) -> S3Bucket {
+        S3Bucket {
+            name: self.bucket.clone(),
+            settings: ClientSettings {
+                region: self.region.clone(),
+                endpoint: self.endpoint.clone(),
+                profile: self.profile.clone(),
+            },
+            client: OnceCell::new(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct S3Properties {
+    #[serde(flatten)]
+    bucket: S3BucketProperties,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prefix: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    multipart_threshold_mb: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    multipart_part_size_mb: Option<u64>,
+}
+
+impl S3Properties {
+    pub fn as_syncer(&self) -> Result<S3Syncer> {
+        let part_size_mb = self
+            .multipart_part_size_mb
+            .unwrap_or(DEFAULT_MULTIPART_PART_SIZE_MB);
+        if part_size_mb < MIN_MULTIPART_PART_SIZE_MB {
+            return Err(Error::Config(format!(
+                "multipart_part_size_mb must be at least {}",
+                MIN_MULTIPART_PART_SIZE_MB
+            )));
+        }
+        Ok(S3Syncer {
+            bucket: self.bucket.as_bucket(),
+            prefix: self
+                .prefix
+                .as_deref()
+                .unwrap_or_default()
+                .trim_matches('/')
+                .to_string(),
+            multipart_threshold: self
+                .multipart_threshold_mb
+                .unwrap_or(DEFAULT_MULTIPART_THRESHOLD_MB)
+                * MIB,
+            part_size: part_size_mb * MIB,
+            filter: None,
+        })
+    }
+}
+
+pub fn object_key(prefix: &str, relative: &str) -> String {
+    match (prefix.is_empty(), relative.is_empty()) {
+        (true, _) => relative.to_string(),
+        (false, true) => prefix.to_string(),
+        (false, false) => format!("{}/{}", prefix, relative),
+    }
+}
+
+fn multipart_etag<D: AsRef<[u8]>>(parts: &[D]) -> String {
+    let mut combined = Md5::new();
+    for part in parts {
+        combined.update(part);
+    }
+    format!("{}-{}", hex::encode(combined.finalize()), parts.len())
+}
+
+async fn read_part(file: &mut tokio::fs::File, part_size: u64) -> std::io::Result<Vec<u8>> {
+    let mut chunk = Vec::new();
+    file.take(part_size).read_to_end(&mut chunk).await?;
+    Ok(chunk)
+}
+
+// Requests that timed out, never made it to S3 or that S3 failed to handle are worth retrying
+pub fn s3_error<E>(context: String) -> impl FnOnce(SdkError<E, HttpResponse>) -> Error
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    move |e| {
+        let transient = match &e {
+            SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) => true,
+            SdkError::ServiceError(service) => {
+                let status = service.raw().status().as_u16();
+                status >= 500 || status == 429
+            }
+            _ => false,
+        };
+        Error::S3 {
+            context,
+            transient,
+            source: Box::new(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::filter::{Filter, FilterAction};
+    use crate::syncers::s3::{multipart_etag, object_key, S3Properties, MIB};
+    use crate::syncers::{Changes, Syncer};
+    use md5::{Digest, Md5};
+    use notify::event::RemoveKind;
+    use notify::EventKind;
+    use std::path::Path;
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    fn workspace() -> TempDir {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(root.path().join("sub")).unwrap();
+        root
+    }
+
+    fn properties(yaml: &str) -> S3Properties {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn keys_are_relative_to_the_prefix() {
+        assert_eq!(object_key("", "sub/file"), "sub/file");
+        assert_eq!(object_key("ws", "sub/file"), "ws/sub/file");
+        assert_eq!(object_key("ws", ""), "ws");
+        let syncer = properties("{bucket: dev, prefix: /ws/}")
+            .as_syncer()
+            .unwrap();
+        assert_eq!(syncer.name(), "s3://dev/ws");
+        assert!(properties("{bucket: dev, multipart_part_size_mb: 4}")
+            .as_syncer()
+            .is_err());
+    }
+
+    #[test]
+    fn objects_of_files_left_out_are_kept() {
+        let root = Path::new("/ws");
+        let filter = Filter::new(root, [(FilterAction::Exclude, "logs")], None).unwrap();
+        let syncer = properties("{bucket: dev, prefix: backup}")
+            .as_syncer()
+            .unwrap()
+            .with_filter(Arc::new(filter));
+        assert!(syncer.syncs_key(root, "backup/sub/file"));
+        assert!(!syncer.syncs_key(root, "backup/logs"));
+        assert!(!syncer.syncs_key(root, "backup/logs/today.log"));
+    }
+
+    #[tokio::test]
+    async fn etags_are_computed_like_s3_does() {
+        let workspace = workspace();
+        let root = workspace.path();
+        let syncer =
+            properties("{bucket: dev, multipart_threshold_mb: 6, multipart_part_size_mb: 5}")
+                .as_syncer()
+                .unwrap();
+        std::fs::write(root.join("small"), "hello\n").unwrap();
+        let small = syncer.local_object(&root.join("small")).await.unwrap();
+        assert_eq!(small.etag, "b1946ac92492d2347c6235b4d2611184");
+        assert_eq!(small.size, 6);
+
+        let data = vec![7u8; (12 * MIB) as usize];
+        std::fs::write(root.join("large"), &data).unwrap();
+        let large = syncer.local_object(&root.join("large")).await.unwrap();
+        let parts = data
+            .chunks((5 * MIB) as usize)
+            .map(Md5::digest)
+            .collect::<Vec<_>>();
+        assert_eq!(large.etag, multipart_etag(&parts));
+        assert!(large.etag.ends_with("-3"));
+    }
+
+    // Runs against an S3 compatible server such as MinIO, with credentials in AWS_ACCESS_KEY_ID and
+    // AWS_SECRET_ACCESS_KEY. Run with --ignored
+    #[tokio::test]
+    #[ignore = "needs an S3 compatible server in RUST_DEV_SYNC_TEST_S3_ENDPOINT and a bucket in RUST_DEV_SYNC_TEST_S3_BUCKET"]
+    async fn workspace_is_mirrored_to_the_bucket() {
+        let endpoint = std::env::var("RUST_DEV_SYNC_TEST_S3_ENDPOINT")
+            .expect("RUST_DEV_SYNC_TEST_S3_ENDPOINT should be set");
+        let bucket = std::env::var("RUST_DEV_SYNC_TEST_S3_BUCKET")
+            .expect("RUST_DEV_SYNC_TEST_S3_BUCKET should be set");
+        let workspace = workspace();
+        let root = workspace.path();
+        let prefix = root.file_name().unwrap().to_str().unwrap().to_string();
+        let syncer = properties(&format!(
+            "{{bucket: {}, prefix: {}, endpoint: \"{}\"}}",
+            bucket, prefix, endpoint
+        ))
+        .as_syncer()
+        .unwrap();
+        std::fs::write(root.join("file1"), "one").unwrap();
+        std::fs::write(root.join("sub/file2"), "two").unwrap();
+        let stats = syncer.sync(root, &Changes::full()).await.unwrap();
+        assert_eq!(stats.bytes_transferred, Some(6));
+        let stats = syncer.sync(root, &Changes::full()).await.unwrap();
+        assert_eq!(stats.bytes_transferred, Some(0));
+
+        std::fs::remove_dir_all(root.join("sub")).unwrap();
+        let mut changes = Changes::new();
+        changes.insert(root.join("sub"), EventKind::Remove(RemoveKind::Folder));
+        syncer.sync(root, &changes).await.unwrap();
+        let remaining = syncer.bucket.list(&format!("{}/", prefix)).await.unwrap();
+        assert_eq!(
+            remaining.into_keys().collect::<Vec<_>>(),
+            vec![format!("{}/file1", prefix)]
+        );
+
+        std::fs::remove_file(root.join("file1")).unwrap();
+        syncer.sync(root, &Changes::full()).await.unwrap();
+        assert!(syncer.bucket.list(&prefix).await.unwrap().is_empty());
+    }
+}
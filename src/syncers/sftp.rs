@@ -0,0 +1,824 @@
+use std::collections::HashMap;
+use std::fs::Metadata;
+use std::io::ErrorKind;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use ssh2::{
+    CheckResult, ErrorCode, FileStat, KnownHostFileKind, OpenFlags, OpenType, Session, Sftp,
+};
+use tracing::debug;
+
+use crate::error::{Error, Result};
+use crate::filter::{self, Filter};
+use crate::rsync::shell::ssh::SSHOption;
+use crate::syncers::rsyncer::{
+    default_dst_host, generate_session_id, ssh_shell, RsyncGlobalProperties,
+    SSHAdditionalProperties,
+};
+use crate::syncers::{Changes, Result as SyncerResult, SyncStats, Syncer};
+
+const DEFAULT_PORT: u16 = 22;
+const DEFAULT_POOL_SIZE: usize = 4;
+const DEFAULT_KEY_FILES: [&str; 3] = ["id_ed25519", "id_ecdsa", "id_rsa"];
+// SFTP status codes, see https://datatracker.ietf.org/doc/html/draft-ietf-secsh-filexfer-02
+const SFTP_NO_SUCH_FILE: i32 = 2;
+const SFTP_FAILURE: i32 = 4;
+const SFTP_NO_CONNECTION: i32 = 6;
+const SFTP_CONNECTION_LOST: i32 = 7;
+
+// How to reach the host and sign in. Taken from the ssh options the ssh command would get, as
+// far as they apply to a single connection
+#[derive(Debug, Clone, PartialEq)]
+struct ConnectSettings {
+    host: String,
+    port: u16,
+    user: String,
+    identity_files: Vec<PathBuf>,
+    connect_timeout: Option<Duration>,
+    // How often to check on the host, and how long it may go without answering
+    keepalive_interval: Option<u32>,
+    timeout: Option<Duration>,
+    // Hosts whose key isn't in there are turned down, unless unknown hosts are accepted
+    known_hosts: PathBuf,
+    accept_unknown_hosts: bool,
+}
+
+struct Connection {
+    session: Session,
+    sftp: Sftp,
+}
+
+impl ConnectSettings {
+    fn connect(&self) -> Result<Connection> {
+        let failed = |reason: String| Error::Ssh {
+            host: self.host.clone(),
+            stderr: reason,
+        };
+        let address = (self.host.as_str(), self.port)
+            .to_socket_addrs()
+            .map_err(|e| failed(e.to_string()))?
+            .next()
+            .ok_or_else(|| failed(String::from("host has no address")))?;
+        let stream = match self.connect_timeout {
+            Some(timeout) => TcpStream::connect_timeout(&address, timeout),
+            None => TcpStream::connect(address),
+        }
+        .map_err(|e| failed(e.to_string()))?;
+        let mut session = Session::new().map_err(|e| failed(e.to_string()))?;
+        session.set_tcp_stream(stream);
+        if let Some(timeout) = self.timeout {
+            session.set_timeout(timeout.as_millis() as u32);
+        }
+        session.handshake().map_err(|e| failed(e.to_string()))?;
+        self.check_host_key(&session)?;
+        self.authenticate(&session)?;
+        if let Some(interval) = self.keepalive_interval {
+            session.set_keepalive(true, interval);
+        }
+        let sftp = session.sftp().map_err(|e| failed(e.to_string()))?;
+        debug!(host = %self.host, "Opened sftp connection");
+        Ok(Connection { session, sftp })
+    }
+
+    fn check_host_key(&self, session: &Session) -> Result {
+        let failed = |reason: String| Error::Ssh {
+            host: self.host.clone(),
+            stderr: reason,
+        };
+        let (key, _) = session
+            .host_key()
+            .ok_or_else(|| failed(String::from("host sent no key")))?;
+        let mut known_hosts = session.known_hosts().map_err(|e| failed(e.to_string()))?;
+        if self.known_hosts.exists() {
+            known_hosts
+                .read_file(&self.known_hosts, KnownHostFileKind::OpenSSH)
+                .map_err(|e| failed(format!("unable to read {:?}: {}", self.known_hosts, e)))?;
+        }
+        match known_hosts.check_port(&self.host, self.port, key) {
+            CheckResult::Match => Ok(()),
+            CheckResult::NotFound if self.accept_unknown_hosts => Ok(()),
+            CheckResult::NotFound => Err(failed(format!(
+                "host key not found in {:?}, connect with ssh once to add it",
+                self.known_hosts
+            ))),
+            CheckResult::Mismatch => Err(failed(format!(
+                "host key doesn't match the one in {:?}",
+                self.known_hosts
+            ))),
+            CheckResult::Failure => Err(failed(String::from("unable to check the host key"))),
+        }
+    }
+
+    // Tries the identity files given, or the ssh agent followed by the usual key files when
+    // there are none
+    fn authenticate(&self, session: &Session) -> Result {
+        if self.identity_files.is_empty() {
+            let _ = session.userauth_agent(&self.user);
+        }
+        let default_key_files = || {
+            let ssh_dir = home::home_dir().unwrap_or_default().join(".ssh");
+            DEFAULT_KEY_FILES
+                .iter()
+                .map(|name| ssh_dir.join(name))
+                .filter(|path| path.exists())
+                .collect::<Vec<PathBuf>>()
+        };
+        let key_files = match self.identity_files.is_empty() {
+            true => default_key_files(),
+            false => self.identity_files.clone(),
+        };
+        for key_file in key_files {
+            if session.authenticated() {
+                break;
+            }
+            if let Err(e) = session.userauth_pubkey_file(&self.user, None, &key_file, None) {
+                debug!(key_file = ?key_file, "Key was turned down: {}", e);
+            }
+        }
+        match session.authenticated() {
+            true => Ok(()),
+            false => Err(Error::Ssh {
+                host: self.host.clone(),
+                stderr: format!("unable to sign in as {}", self.user),
+            }),
+        }
+    }
+}
+
+// Connections kept open between syncs, and used side by side to upload several files at once.
+// Idle connections are checked on before being handed out again
+struct ConnectionPool {
+    settings: ConnectSettings,
+    size: usize,
+    idle: Mutex<Vec<Connection>>,
+}
+
+impl ConnectionPool {
+    fn get(&self) -> Result<Connection> {
+        loop {
+            let connection = match self.idle.lock().unwrap().pop() {
+                Some(connection) => connection,
+                None => return self.settings.connect(),
+            };
+            if connection.session.keepalive_send().is_ok() {
+                return Ok(connection);
+            }
+        }
+    }
+
+    // Connections are only given back once they're known to work, the others are dropped
+    fn put(&self, connection: Connection) {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < self.size {
+            idle.push(connection);
+        }
+    }
+
+    fn close(&self) {
+        for connection in self.idle.lock().unwrap().drain(..) {
+            let _ = connection
+                .session
+                .disconnect(None, "closing connection", None);
+        }
+    }
+}
+
+// Mirrors the workspace into a directory on a host over SFTP, without running ssh or rsync.
+// Changed paths are made the same on the host as they are locally, the way the local syncer
+// does it. Files are skipped when their size and modification time match, uploaded to a
+// temporary file next to the destination and renamed over it, several at a time over the
+// connections of the pool
+pub struct SftpSyncer {
+    pool: Arc<ConnectionPool>,
+    dst_dir: PathBuf,
+    filter: Option<Arc<Filter>>,
+}
+
+impl SftpSyncer {
+    // Files the workspace leaves out are neither uploaded nor deleted from the host
+    pub fn with_filter(mut self, filter: Arc<Filter>) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    fn destination(&self) -> String {
+        let settings = &self.pool.settings;
+        match settings.port {
+            DEFAULT_PORT => format!("{}:{}", settings.host, self.dst_dir.display()),
+            port => format!("{}:{}:{}", settings.host, port, self.dst_dir.display()),
+        }
+    }
+}
+
+#[async_trait]
+impl Syncer for SftpSyncer {
+    fn name(&self) -> String {
+        format!("sftp:{}", self.destination())
+    }
+
+    async fn sync(&self, workspace_path: &Path, changes: &Changes) -> SyncerResult {
+        let roots = changes.roots(workspace_path);
+        let mirror = Mirror {
+            pool: self.pool.clone(),
+            workspace_path: workspace_path.to_path_buf(),
+            dst_dir: self.dst_dir.clone(),
+            filter: self.filter.clone(),
+        };
+        // libssh2 blocks, so the whole sync runs on a thread of its own
+        let bytes = tokio::task::spawn_blocking(move || mirror.run(&roots))
+            .await
+            .expect("sftp sync panicked")?;
+        Ok(SyncStats {
+            bytes_transferred: Some(bytes),
+        })
+    }
+
+    async fn close(&self) {
+        let pool = self.pool.clone();
+        let _ = tokio::task::spawn_blocking(move || pool.close()).await;
+    }
+}
+
+// A single sync, made of everything it needs to run away from the syncer
+struct Mirror {
+    pool: Arc<ConnectionPool>,
+    workspace_path: PathBuf,
+    dst_dir: PathBuf,
+    filter: Option<Arc<Filter>>,
+}
+
+impl Mirror {
+    // Mirrors every root (relative to the workspace) then uploads the files that changed.
+    // Returns the number of bytes uploaded
+    fn run(&self, roots: &[(PathBuf, bool)]) -> Result<u64> {
+        let connection = self.pool.get()?;
+        let mut uploads = Vec::new();
+        for (root, recursive) in roots {
+            self.mirror(&connection.sftp, root, *recursive, &mut uploads)?;
+        }
+        self.upload_all(connection, uploads)
+    }
+
+    // Makes the remote side of start the same as the local one, other than for files, which are
+    // added to uploads instead. The content of directories is only looked at when recursive
+    fn mirror(
+        &self,
+        sftp: &Sftp,
+        start: &Path,
+        recursive: bool,
+        uploads: &mut Vec<(PathBuf, Metadata)>,
+    ) -> Result {
+        let remote_stat = lstat(sftp, &self.dst_dir.join(start))?;
+        if remote_stat.is_none() {
+            // Parents come first, as with mkdir -p
+            match start.parent() {
+                Some(parent) => self.mirror(sftp, parent, false, uploads)?,
+                None => {
+                    if let Some(parent) = self.dst_dir.parent() {
+                        create_dir_all(sftp, parent)?;
+                    }
+                }
+            }
+        }
+        let mut pending = vec![(start.to_path_buf(), remote_stat)];
+        while let Some((relative, remote_stat)) = pending.pop() {
+            let (local, remote) = (
+                self.workspace_path.join(&relative),
+                self.dst_dir.join(&relative),
+            );
+            if !filter::allows(self.filter.as_deref(), &local) {
+                continue;
+            }
+            let metadata = match std::fs::symlink_metadata(&local) {
+                Ok(metadata) => metadata,
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    if let Some(remote_stat) = remote_stat {
+                        remove(sftp, &remote, &remote_stat)?;
+                    }
+                    continue;
+                }
+                Err(e) => return Err(Error::io(format!("failed to read {:?}", local))(e)),
+            };
+            let mode = metadata.permissions().mode() & 0o7777;
+            if metadata.is_symlink() {
+                let target = std::fs::read_link(&local)
+                    .map_err(Error::io(format!("failed to read symlink {:?}", local)))?;
+                if remote_stat
+                    .as_ref()
+                    .is_some_and(|stat| stat.file_type().is_symlink())
+                    && sftp.readlink(&remote).ok().as_ref() == Some(&target)
+                {
+                    continue;
+                }
+                if let Some(remote_stat) = &remote_stat {
+                    remove(sftp, &remote, remote_stat)?;
+                }
+                // Arguments go the way OpenSSH takes them, which is the other way around from
+                // what the SFTP draft says
+                sftp.symlink(&target, &remote)
+                    .map_err(sftp_error(format!("failed to create symlink {:?}", remote)))?;
+            } else if metadata.is_dir() {
+                let remote_children = match &remote_stat {
+                    Some(stat) if stat.is_dir() => {
+                        if stat.perm.map(|perm| perm & 0o7777) != Some(mode) {
+                            sftp.setstat(&remote, permissions(mode))
+                                .map_err(sftp_error(format!(
+                                    "failed to set permissions of {:?}",
+                                    remote
+                                )))?;
+                        }
+                        None
+                    }
+                    _ => {
+                        if let Some(remote_stat) = &remote_stat {
+                            remove(sftp, &remote, remote_stat)?;
+                        }
+                        sftp.mkdir(&remote, mode as i32)
+                            .map_err(sftp_error(format!("failed to create {:?}", remote)))?;
+                        Some(HashMap::new())
+                    }
+                };
+                if recursive || relative != start {
+                    let remote_children = match remote_children {
+                        Some(children) => children,
+                        None => list_remote(sftp, &remote)?,
+                    };
+                    pending.extend(self.mirror_entries(sftp, &relative, remote_children)?);
+                }
+            } else if metadata.is_file() {
+                let same_content = remote_stat.as_ref().filter(|stat| {
+                    stat.is_file()
+                        && stat.size == Some(metadata.len())
+                        && stat.mtime == Some(modified_secs(&metadata))
+                });
+                match same_content {
+                    // Only the permissions changed, there is nothing to upload
+                    Some(stat) if stat.perm.map(|perm| perm & 0o7777) != Some(mode) => sftp
+                        .setstat(&remote, permissions(mode))
+                        .map_err(sftp_error(format!(
+                            "failed to set permissions of {:?}",
+                            remote
+                        )))?,
+                    Some(_) => {}
+                    None => uploads.push((relative, metadata)),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Deletes what the remote directory has that the local one doesn't, and returns the local
+    // entries along with what they are on the remote side
+    fn mirror_entries(
+        &self,
+        sftp: &Sftp,
+        relative: &Path,
+        mut remote_children: HashMap<PathBuf, FileStat>,
+    ) -> Result<Vec<(PathBuf, Option<FileStat>)>> {
+        let local = self.workspace_path.join(relative);
+        let mut entries = Vec::new();
+        for entry in
+            std::fs::read_dir(&local).map_err(Error::io(format!("failed to list {:?}", local)))?
+        {
+            let entry = entry.map_err(Error::io(format!("failed to list {:?}", local)))?;
+            let name = PathBuf::from(entry.file_name());
+            let remote_stat = remote_children.remove(&name);
+            entries.push((relative.join(name), remote_stat));
+        }
+        for (name, remote_stat) in remote_children {
+            if filter::allows(self.filter.as_deref(), &local.join(&name)) {
+                remove(sftp, &self.dst_dir.join(relative).join(name), &remote_stat)?;
+            }
+        }
+        Ok(entries)
+    }
+
+    // Uploads the files, spread over as many connections as the pool allows
+    fn upload_all(&self, connection: Connection, uploads: Vec<(PathBuf, Metadata)>) -> Result<u64> {
+        let workers = uploads.len().clamp(1, self.pool.size);
+        let queue = Mutex::new(uploads.into_iter());
+        let mut first = Some(connection);
+        std::thread::scope(|scope| {
+            let handles = (0..workers)
+                .map(|_| {
+                    let connection = first.take();
+                    let queue = &queue;
+                    scope.spawn(move || {
+                        let connection = match connection {
+                            Some(connection) => connection,
+                            None => self.pool.get()?,
+                        };
+                        let mut bytes = 0;
+                        loop {
+                            let next = queue.lock().unwrap().next();
+                            match next {
+                                Some((relative, metadata)) => {
+                                    bytes += self.upload(&connection.sftp, &relative, &metadata)?
+                                }
+                                None => break,
+                            }
+                        }
+                        self.pool.put(connection);
+                        Ok(bytes)
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("sftp upload panicked"))
+                .sum()
+        })
+    }
+
+    fn upload(&self, sftp: &Sftp, relative: &Path, metadata: &Metadata) -> Result<u64> {
+        let (local, remote) = (
+            self.workspace_path.join(relative),
+            self.dst_dir.join(relative),
+        );
+        let mut src = match std::fs::File::open(&local) {
+            Ok(src) => src,
+            // Deleted since, which the next sync takes care of
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(Error::io(format!("failed to read {:?}", local))(e)),
+        };
+        let mode = metadata.permissions().mode() & 0o7777;
+        let mut name = std::ffi::OsString::from(".");
+        name.push(remote.file_name().unwrap_or_default());
+        name.push(format!(".rust-dev-sync-{}", generate_session_id()));
+        let temp = remote.with_file_name(name);
+        let result = (|| {
+            let mut dst = sftp
+                .open_mode(
+                    &temp,
+                    OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+                    mode as i32,
+                    OpenType::File,
+                )
+                .map_err(sftp_error(format!("failed to create {:?}", temp)))?;
+            let bytes = std::io::copy(&mut src, &mut dst).map_err(Error::io(format!(
+                "failed to upload {:?} to {:?}",
+                local, temp
+            )))?;
+            drop(dst);
+            let modified = modified_secs(metadata);
+            let stat = FileStat {
+                atime: Some(modified),
+                mtime: Some(modified),
+                ..permissions(mode)
+            };
+            sftp.setstat(&temp, stat)
+                .map_err(sftp_error(format!("failed to set the times of {:?}", temp)))?;
+            // Servers going by the SFTP draft OpenSSH implements don't rename over existing files,
+            // and only say so with a generic failure. Any other error leaves the remote file alone
+            if let Err(e) = sftp.rename(&temp, &remote, None) {
+                if e.code() != ErrorCode::SFTP(SFTP_FAILURE) || lstat(sftp, &remote)?.is_none() {
+                    return Err(sftp_error(format!("failed to replace {:?}", remote))(e));
+                }
+                sftp.unlink(&remote)
+                    .map_err(sftp_error(format!("failed to replace {:?}", remote)))?;
+                sftp.rename(&temp, &remote, None)
+                    .map_err(sftp_error(format!("failed to replace {:?}", remote)))?;
+            }
+            Ok(bytes)
+        })();
+        if result.is_err() {
+            let _ = sftp.unlink(&temp);
+        }
+        result
+    }
+}
+
+fn lstat(sftp: &Sftp, path: &Path) -> Result<Option<FileStat>> {
+    match sftp.lstat(path) {
+        Ok(stat) => Ok(Some(stat)),
+        Err(e) if e.code() == ErrorCode::SFTP(SFTP_NO_SUCH_FILE) => Ok(None),
+        Err(e) => Err(sftp_error(format!("failed to read {:?}", path))(e)),
+    }
+}
+
+// What is in a remote directory, by name
+fn list_remote(sftp: &Sftp, dir: &Path) -> Result<HashMap<PathBuf, FileStat>> {
+    Ok(sftp
+        .readdir(dir)
+        .map_err(sftp_error(format!("failed to list {:?}", dir)))?
+        .into_iter()
+        .filter_map(|(path, stat)| Some((PathBuf::from(path.file_name()?), stat)))
+        .collect())
+}
+
+fn create_dir_all(sftp: &Sftp, dir: &Path) -> Result {
+    if dir.as_os_str().is_empty() || lstat(sftp, dir)?.is_some() {
+        return Ok(());
+    }
+    if let Some(parent) = dir.parent() {
+        create_dir_all(sftp, parent)?;
+    }
+    sftp.mkdir(dir, 0o755)
+        .map_err(sftp_error(format!("failed to create {:?}", dir)))
+}
+
+fn remove(sftp: &Sftp, path: &Path, stat: &FileStat) -> Result {
+    if !stat.is_dir() {
+        return sftp
+            .unlink(path)
+            .map_err(sftp_error(format!("failed to delete {:?}", path)));
+    }
+    for (name, stat) in list_remote(sftp, path)? {
+        remove(sftp, &path.join(name), &stat)?;
+    }
+    sftp.rmdir(path)
+        .map_err(sftp_error(format!("failed to delete {:?}", path)))
+}
+
+fn permissions(mode: u32) -> FileStat {
+    FileStat {
+        size: None,
+        uid: None,
+        gid: None,
+        perm: Some(mode),
+        atime: None,
+        mtime: None,
+    }
+}
+
+fn modified_secs(metadata: &Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|since_epoch| since_epoch.as_secs())
+        .unwrap_or_default()
+}
+
+// Errors of the connection itself are worth trying again, unlike the ones the server answers with
+fn sftp_error(context: String) -> impl FnOnce(ssh2::Error) -> Error {
+    move |source| Error::Sftp {
+        context,
+        transient: matches!(
+            source.code(),
+            ErrorCode::Session(_)
+                | ErrorCode::SFTP(SFTP_NO_CONNECTION)
+                | ErrorCode::SFTP(SFTP_CONNECTION_LOST)
+        ),
+        source,
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SftpProperties {
+    // Either host or user@host, as for rsync
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dst_host: Option<String>,
+    dst_dir: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ssh: Option<SSHAdditionalProperties>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pool_size: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    known_hosts: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    accept_unknown_hosts: Option<bool>,
+}
+
+impl SftpProperties {
+    // Reaches hosts the same way the rsync syncers do, with their default host and ssh options.
+    // Options that only make sense for the ssh command (e.g. ControlMaster) are left out
+    fn as_settings(&self, global_props: Option<&RsyncGlobalProperties>) -> Result<ConnectSettings> {
+        let dst_host = self
+            .dst_host
+            .as_deref()
+            .or_else(|| default_dst_host(global_props))
+            .ok_or_else(|| {
+                Error::Config(
+                    "sftp syncers need a dst_host, or a global rsync.default_dst_host".to_string(),
+                )
+            })?;
+        let (user, host) = match dst_host.split_once('@') {
+            Some((user, host)) => (String::from(user), host),
+            None => (
+                std::env::var("USER").map_err(|_| {
+                    Error::Config(format!(
+                        "no user to sign in to {} as, use user@host as dst_host",
+                        dst_host
+                    ))
+                })?,
+                dst_host,
+            ),
+        };
+        let home = home::home_dir().unwrap_or_default();
+        let expand = |path: &str| match path.strip_prefix("~/") {
+            Some(path) => home.join(path),
+            None => PathBuf::from(path),
+        };
+        let options = ssh_shell(global_props, self.ssh.as_ref())?
+            .map(|shell| shell.options)
+            .unwrap_or_default();
+        let option = |find: fn(&SSHOption) -> Option<i32>| {
+            options
+                .iter()
+                .filter_map(find)
+                .next_back()
+                .filter(|value| *value > 0)
+        };
+        let keepalive_interval = option(|opt| match opt {
+            SSHOption::ServerAliveInterval(value) => Some(*value),
+            _ => None,
+        });
+        let keepalive_count = option(|opt| match opt {
+            SSHOption::ServerAliveCountMax(value) => Some(*value),
+            _ => None,
+        })
+        .unwrap_or(3);
+        Ok(ConnectSettings {
+            host: String::from(host),
+            port: self.port.unwrap_or(DEFAULT_PORT),
+            user,
+            identity_files: options
+                .iter()
+                .filter_map(|opt| match opt {
+                    SSHOption::IdentityFile(path) => Some(expand(path)),
+                    _ => None,
+                })
+                .collect(),
+            connect_timeout: option(|opt| match opt {
+                SSHOption::ConnectTimeout(value) => Some(*value),
+                _ => None,
+            })
+            .map(|secs| Duration::from_secs(secs as u64)),
+            keepalive_interval: keepalive_interval.map(|secs| secs as u32),
+            timeout: keepalive_interval
+                .map(|secs| Duration::from_secs((secs * keepalive_count) as u64)),
+            known_hosts: self
+                .known_hosts
+                .as_deref()
+                .map(expand)
+                .unwrap_or_else(|| home.join(".ssh/known_hosts")),
+            accept_unknown_hosts: self.accept_unknown_hosts.unwrap_or(false),
+        })
+    }
+
+    pub fn as_syncer(&self, global_props: Option<&RsyncGlobalProperties>) -> Result<SftpSyncer> {
+        let pool_size = self.pool_size.unwrap_or(DEFAULT_POOL_SIZE);
+        if pool_size == 0 {
+            return Err(Error::Config(
+                "pool_size must be greater than 0".to_string(),
+            ));
+        }
+        Ok(SftpSyncer {
+            pool: Arc::new(ConnectionPool {
+                settings: self.as_settings(global_props)?,
+                size: pool_size,
+                idle: Mutex::new(Vec::new()),
+            }),
+            dst_dir: PathBuf::from(&self.dst_dir),
+            filter: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::syncers::rsyncer::RsyncGlobalProperties;
+    use crate::syncers::sftp::{ConnectSettings, SftpProperties};
+    use crate::syncers::{Changes, Syncer};
+    use notify::event::{MetadataKind, ModifyKind, RemoveKind};
+    use notify::EventKind;
+    use std::io::Read;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    fn properties(yaml: &str) -> SftpProperties {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn settings_come_from_the_ssh_options() {
+        let global: RsyncGlobalProperties = serde_yaml::from_str(
+            "
+            default_dst_host: dev@host.com
+            ssh:
+              options:
+                - ServerAliveInterval: 10
+                - ServerAliveCountMax: 6
+                - ControlMaster: auto
+            ",
+        )
+        .unwrap();
+        let props = properties(
+            "
+            dst_dir: /remote/dir
+            port: 2222
+            known_hosts: /etc/known_hosts
+            ssh:
+              additional_options:
+                - IdentityFile: /keys/id_ed25519
+                - ConnectTimeout: 5
+            ",
+        );
+        assert_eq!(
+            props.as_settings(Some(&global)).unwrap(),
+            ConnectSettings {
+                host: String::from("host.com"),
+                port: 2222,
+                user: String::from("dev"),
+                identity_files: vec![PathBuf::from("/keys/id_ed25519")],
+                connect_timeout: Some(Duration::from_secs(5)),
+                keepalive_interval: Some(10),
+                timeout: Some(Duration::from_secs(60)),
+                known_hosts: PathBuf::from("/etc/known_hosts"),
+                accept_unknown_hosts: false,
+            }
+        );
+        let syncer = props.as_syncer(Some(&global)).unwrap();
+        assert_eq!(syncer.name(), "sftp:host.com:2222:/remote/dir");
+        assert!(properties("dst_dir: /remote/dir").as_syncer(None).is_err());
+        assert!(
+            properties("{dst_host: host.com, dst_dir: /remote/dir, pool_size: 0}")
+                .as_syncer(None)
+                .is_err()
+        );
+    }
+
+    // Signs in with the ssh agent or the usual key files. RUST_DEV_SYNC_TEST_SFTP_PORT picks
+    // another port than 22
+    #[tokio::test]
+    #[ignore = "needs an SSH server in RUST_DEV_SYNC_TEST_SFTP_HOST (user@host) and a directory in RUST_DEV_SYNC_TEST_SFTP_DIR"]
+    async fn workspace_is_mirrored_over_sftp() {
+        let host = std::env::var("RUST_DEV_SYNC_TEST_SFTP_HOST")
+            .expect("RUST_DEV_SYNC_TEST_SFTP_HOST should be set");
+        let dir = std::env::var("RUST_DEV_SYNC_TEST_SFTP_DIR")
+            .expect("RUST_DEV_SYNC_TEST_SFTP_DIR should be set");
+        let port = std::env::var("RUST_DEV_SYNC_TEST_SFTP_PORT").unwrap_or_else(|_| "22".into());
+        let workspace = tempfile::tempdir().unwrap();
+        let root = workspace.path();
+        let dst_dir = PathBuf::from(dir).join(root.file_name().unwrap());
+        std::fs::create_dir_all(root.join("sub/deep")).unwrap();
+        std::fs::write(root.join("file1"), "one").unwrap();
+        std::fs::write(root.join("sub/deep/file2"), "two").unwrap();
+        std::os::unix::fs::symlink("file1", root.join("link")).unwrap();
+        let syncer = properties(&format!(
+            "{{dst_host: {}, dst_dir: {:?}, port: {}, pool_size: 2, accept_unknown_hosts: true}}",
+            host, dst_dir, port
+        ))
+        .as_syncer(None)
+        .unwrap();
+        let stats = syncer.sync(root, &Changes::full()).await.unwrap();
+        assert_eq!(stats.bytes_transferred, Some(6));
+        let stats = syncer.sync(root, &Changes::full()).await.unwrap();
+        assert_eq!(stats.bytes_transferred, Some(0));
+
+        std::fs::remove_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("file1"), "changed").unwrap();
+        let mut changes = Changes::new();
+        changes.insert(root.join("sub"), EventKind::Remove(RemoveKind::Folder));
+        changes.insert(root.join("file1"), EventKind::Any);
+        syncer.sync(root, &changes).await.unwrap();
+
+        let connection = syncer.pool.get().unwrap();
+        let mut remote = connection
+            .sftp
+            .readdir(&dst_dir)
+            .unwrap()
+            .into_iter()
+            .map(|(path, _)| path.file_name().unwrap().to_owned())
+            .collect::<Vec<_>>();
+        remote.sort();
+        assert_eq!(remote, ["file1", "link"]);
+        let mut content = String::new();
+        let mut file = connection.sftp.open(dst_dir.join("file1")).unwrap();
+        file.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "changed");
+        assert_eq!(
+            connection.sftp.readlink(&dst_dir.join("link")).unwrap(),
+            PathBuf::from("file1")
+        );
+
+        // A file whose permissions changed gets them without being uploaded again
+        std::fs::set_permissions(root.join("file1"), PermissionsExt::from_mode(0o600)).unwrap();
+        let mut changes = Changes::new();
+        changes.insert(
+            root.join("file1"),
+            EventKind::Modify(ModifyKind::Metadata(MetadataKind::Permissions)),
+        );
+        let stats = syncer.sync(root, &changes).await.unwrap();
+        assert_eq!(stats.bytes_transferred, Some(0));
+        let stat = connection.sftp.stat(&dst_dir.join("file1")).unwrap();
+        assert_eq!(stat.perm.map(|perm| perm & 0o7777), Some(0o600));
+
+        std::fs::remove_dir_all(root).unwrap();
+        std::fs::create_dir(root).unwrap();
+        syncer.sync(root, &Changes::full()).await.unwrap();
+        assert!(connection.sftp.readdir(&dst_dir).unwrap().is_empty());
+        connection.sftp.rmdir(&dst_dir).unwrap();
+        syncer.close().await;
+    }
+}
@@ -0,0 +1,772 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{FileTimes, Permissions};
+use std::io::ErrorKind;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+use tokio::sync::Mutex;
+use tracing::debug;
+
+use crate::error::{Error, Result};
+use crate::filter::{self, Filter};
+use crate::syncers::local::{create_dir, remove, replace_with};
+use crate::syncers::s3::S3BucketProperties;
+use crate::syncers::{Changes, Result as SyncerResult, SyncStats, Syncer};
+
+pub mod store;
+
+use store::{LocalStore, S3Store, SnapshotStore};
+
+const BUFFER_SIZE: usize = 64 * 1024;
+const MILLIS_PER_DAY: i64 = 24 * 60 * 60 * 1000;
+
+// What a path in the workspace was when a snapshot was taken. Files refer to their content by
+// its SHA-256
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Entry {
+    Dir {
+        mode: u32,
+    },
+    File {
+        hash: String,
+        size: u64,
+        mode: u32,
+        modified_ns: u64,
+    },
+    Symlink {
+        target: PathBuf,
+    },
+}
+
+// Every path in the workspace at some point in time, relative to the workspace. Snapshots are
+// named after the time they were taken, in milliseconds since the unix epoch
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct Snapshot {
+    pub created_ms: u64,
+    pub files: BTreeMap<PathBuf, Entry>,
+}
+
+impl Snapshot {
+    fn hashes(&self) -> HashSet<&str> {
+        self.files
+            .values()
+            .filter_map(|entry| match entry {
+                Entry::File { hash, .. } => Some(hash.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+// Takes a snapshot of the workspace on every sync. The content of files goes to the store once
+// per distinct content, so a snapshot only costs the files that changed since the last one plus
+// the list of files. Files whose size and modification time are the same as in the last
+// snapshot are taken to have the same content, rather than being hashed again
+pub struct SnapshotSyncer {
+    store: Box<dyn SnapshotStore>,
+    filter: Option<Arc<Filter>>,
+    // The last snapshot taken, which the next one is made from. Read from the store first time
+    last: Mutex<Option<Snapshot>>,
+}
+
+impl SnapshotSyncer {
+    pub fn new(store: Box<dyn SnapshotStore>) -> Self {
+        SnapshotSyncer {
+            store,
+            filter: None,
+            last: Mutex::new(None),
+        }
+    }
+
+    // Files the workspace leaves out are left out of snapshots too
+    pub fn with_filter(mut self, filter: Arc<Filter>) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    // Adds path and everything below it to files
+    async fn scan(
+        &self,
+        workspace_path: &Path,
+        path: &Path,
+        previous: &BTreeMap<PathBuf, Entry>,
+        files: &mut BTreeMap<PathBuf, Entry>,
+    ) -> Result {
+        let mut pending = vec![path.to_path_buf()];
+        while let Some(path) = pending.pop() {
+            let relative = match path.strip_prefix(workspace_path) {
+                Ok(relative) => relative.to_path_buf(),
+                Err(_) => continue,
+            };
+            if !filter::allows(self.filter.as_deref(), &path) {
+                continue;
+            }
+            let metadata = match tokio::fs::symlink_metadata(&path).await {
+                Ok(metadata) => metadata,
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(Error::io(format!("failed to read {:?}", path))(e)),
+            };
+            let mode = metadata.permissions().mode() & 0o7777;
+            let entry = if metadata.is_symlink() {
+                let target = tokio::fs::read_link(&path)
+                    .await
+                    .map_err(Error::io(format!("failed to read symlink {:?}", path)))?;
+                Entry::Symlink { target }
+            } else if metadata.is_dir() {
+                pending.extend(list_dir(&path).await?);
+                Entry::Dir { mode }
+            } else if metadata.is_file() {
+                let size = metadata.len();
+                let modified_ns = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                    .map(|since_epoch| since_epoch.as_nanos() as u64)
+                    .unwrap_or_default();
+                let hash = match previous.get(&relative) {
+                    Some(Entry::File {
+                        hash,
+                        size: previous_size,
+                        modified_ns: previous_modified_ns,
+                        ..
+                    }) if *previous_size == size && *previous_modified_ns == modified_ns => {
+                        hash.clone()
+                    }
+                    _ => match hash_file(&path).await {
+                        Ok(hash) => hash,
+                        Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                        Err(e) => return Err(Error::io(format!("failed to read {:?}", path))(e)),
+                    },
+                };
+                Entry::File {
+                    hash,
+                    size,
+                    mode,
+                    modified_ns,
+                }
+            } else {
+                // Sockets, fifos and the like have no content to keep
+                continue;
+            };
+            // The workspace itself is where snapshots are restored to, rather than part of them
+            if !relative.as_os_str().is_empty() {
+                files.insert(relative, entry);
+            }
+        }
+        Ok(())
+    }
+
+    // Takes the next snapshot, unless nothing changed since the previous one. Returns the number
+    // of bytes stored along with the snapshot
+    async fn take_snapshot(
+        &self,
+        workspace_path: &Path,
+        changes: &Changes,
+        previous: &Snapshot,
+    ) -> Result<(u64, Option<Snapshot>)> {
+        let mut files = BTreeMap::new();
+        if changes.is_full() {
+            self.scan(workspace_path, workspace_path, &previous.files, &mut files)
+                .await?;
+        } else {
+            // Whatever is at or below a changed path is looked at again, the rest is as it was
+            let changed: HashSet<&Path> = changes
+                .iter()
+                .filter_map(|(path, _)| path.strip_prefix(workspace_path).ok())
+                .collect();
+            files.extend(
+                previous
+                    .files
+                    .iter()
+                    .filter(|(path, _)| !path.ancestors().any(|path| changed.contains(path)))
+                    .map(|(path, entry)| (path.clone(), entry.clone())),
+            );
+            for path in &changed {
+                self.scan(
+                    workspace_path,
+                    &workspace_path.join(path),
+                    &previous.files,
+                    &mut files,
+                )
+                .await?;
+            }
+        }
+        if files == previous.files {
+            return Ok((0, None));
+        }
+
+        let mut stored = previous.hashes();
+        let mut bytes = 0;
+        for (path, entry) in &files {
+            if let Entry::File { hash, .. } = entry {
+                if stored.insert(hash) {
+                    bytes += self
+                        .store
+                        .put_object(hash, &workspace_path.join(path))
+                        .await?;
+                }
+            }
+        }
+        // Snapshots are told apart by their time, so two of them can't share a millisecond
+        let snapshot = Snapshot {
+            created_ms: now_ms().max(previous.created_ms + 1),
+            files,
+        };
+        let data = serde_json::to_vec(&snapshot).map_err(|source| Error::Snapshot {
+            name: snapshot.created_ms.to_string(),
+            source,
+        })?;
+        bytes += data.len() as u64;
+        self.store
+            .put_snapshot(&snapshot.created_ms.to_string(), data)
+            .await?;
+        debug!(
+            "Took snapshot {} of {} path(s) in {}",
+            snapshot.created_ms,
+            snapshot.files.len(),
+            self.store.name()
+        );
+        Ok((bytes, Some(snapshot)))
+    }
+}
+
+#[async_trait]
+impl Syncer for SnapshotSyncer {
+    fn name(&self) -> String {
+        format!("snapshot:{}", self.store.name())
+    }
+
+    async fn sync(&self, workspace_path: &Path, changes: &Changes) -> SyncerResult {
+        let mut last = self.last.lock().await;
+        let previous = match &*last {
+            Some(previous) => previous,
+            None => last.insert(
+                load_snapshot(self.store.as_ref(), None)
+                    .await?
+                    .unwrap_or_default(),
+            ),
+        };
+        let (bytes, snapshot) = self
+            .take_snapshot(workspace_path, changes, previous)
+            .await?;
+        if let Some(snapshot) = snapshot {
+            *last = Some(snapshot);
+        }
+        Ok(SyncStats {
+            bytes_transferred: Some(bytes),
+        })
+    }
+}
+
+// The times of the snapshots in the store, oldest first
+pub async fn list_snapshots(store: &dyn SnapshotStore) -> Result<Vec<u64>> {
+    let mut times: Vec<u64> = store
+        .list_snapshots()
+        .await?
+        .iter()
+        .filter_map(|name| name.parse().ok())
+        .collect();
+    times.sort_unstable();
+    Ok(times)
+}
+
+// The last snapshot taken at or before the given time, or the last one taken at all
+pub async fn load_snapshot(
+    store: &dyn SnapshotStore,
+    at_ms: Option<u64>,
+) -> Result<Option<Snapshot>> {
+    let latest = list_snapshots(store)
+        .await?
+        .into_iter()
+        .rfind(|created_ms| at_ms.is_none_or(|at_ms| *created_ms <= at_ms));
+    let name = match latest {
+        Some(created_ms) => created_ms.to_string(),
+        None => return Ok(None),
+    };
+    let data = store.get_snapshot(&name).await?;
+    serde_json::from_slice(&data)
+        .map(Some)
+        .map_err(|source| Error::Snapshot { name, source })
+}
+
+// Makes dst the same as the snapshot. Files already matching their size and modification time
+// are left alone. With a filter, whatever it lets through that isn't in the snapshot is deleted,
+// which is only meant for restoring into the workspace the filter belongs to. Directories where
+// the snapshot has a file or symlink are only replaced along with everything in them with a filter
+// too, and kept otherwise. Returns the number of files written and the directories kept
+pub async fn restore(
+    store: &dyn SnapshotStore,
+    snapshot: &Snapshot,
+    dst: &Path,
+    filter: Option<&Filter>,
+) -> Result<(usize, Vec<PathBuf>)> {
+    create_dir(dst).await?;
+    if let Some(filter) = filter {
+        delete_extras(snapshot, dst, filter).await?;
+    }
+    let mut written = 0;
+    let mut kept = Vec::new();
+    for (relative, entry) in &snapshot.files {
+        let path = dst.join(relative);
+        match entry {
+            Entry::Dir { .. } => create_dir(&path).await?,
+            _ if filter.is_none() && is_dir(&path).await => kept.push(path),
+            _ if is_restored(&path, entry).await => {}
+            Entry::File {
+                hash,
+                mode,
+                modified_ns,
+                ..
+            } => {
+                let modified = UNIX_EPOCH + Duration::from_nanos(*modified_ns);
+                replace_with(&path, |temp| async move {
+                    store
+                        .get_object(hash, &temp)
+                        .await
+                        .map_err(std::io::Error::other)?;
+                    let times = FileTimes::new().set_modified(modified);
+                    std::fs::File::open(&temp)?.set_times(times)?;
+                    tokio::fs::set_permissions(&temp, Permissions::from_mode(*mode)).await
+                })
+                .await
+                .map_err(Error::io(format!("failed to restore {:?}", path)))?;
+                written += 1;
+            }
+            Entry::Symlink { target } => {
+                replace_with(&path, |temp| tokio::fs::symlink(target, temp))
+                    .await
+                    .map_err(Error::io(format!("failed to restore {:?}", path)))?;
+                written += 1;
+            }
+        }
+    }
+    // Directories are only locked down once everything in them has been written
+    for (relative, entry) in snapshot.files.iter().rev() {
+        if let Entry::Dir { mode } = entry {
+            let path = dst.join(relative);
+            tokio::fs::set_permissions(&path, Permissions::from_mode(*mode))
+                .await
+                .map_err(Error::io(format!("failed to restore {:?}", path)))?;
+        }
+    }
+    Ok((written, kept))
+}
+
+async fn is_dir(path: &Path) -> bool {
+    tokio::fs::symlink_metadata(path)
+        .await
+        .is_ok_and(|metadata| metadata.is_dir())
+}
+
+// Whether path already is what the snapshot has for it. Files are compared by their size and
+// modification time
+async fn is_restored(path: &Path, entry: &Entry) -> bool {
+    match entry {
+        Entry::Dir { .. } => is_dir(path).await,
+        Entry::File {
+            size, modified_ns, ..
+        } => {
+            let modified = UNIX_EPOCH + Duration::from_nanos(*modified_ns);
+            tokio::fs::symlink_metadata(path)
+                .await
+                .is_ok_and(|existing| {
+                    existing.is_file()
+                        && existing.len() == *size
+                        && existing
+                            .modified()
+                            .is_ok_and(|existing| existing == modified)
+                })
+        }
+        Entry::Symlink { target } => tokio::fs::read_link(path).await.ok().as_ref() == Some(target),
+    }
+}
+
+// Paths of dst that restoring the snapshot would write, and the directories it would keep where
+// the snapshot has a file or symlink unless they are replaced
+pub async fn find_outdated(
+    snapshot: &Snapshot,
+    dst: &Path,
+    replace_dirs: bool,
+) -> (Vec<PathBuf>, Vec<PathBuf>) {
+    let (mut outdated, mut kept) = (Vec::new(), Vec::new());
+    for (relative, entry) in &snapshot.files {
+        let path = dst.join(relative);
+        match entry {
+            Entry::File { .. } | Entry::Symlink { .. } if !replace_dirs && is_dir(&path).await => {
+                kept.push(path)
+            }
+            _ if !is_restored(&path, entry).await => outdated.push(path),
+            _ => {}
+        }
+    }
+    (outdated, kept)
+}
+
+// Paths of dst that aren't in the snapshot and that the filter lets through, each listed after
+// the directory it is in. Directories are only listed when nothing the filter leaves out is in them
+pub async fn find_extras(snapshot: &Snapshot, dst: &Path, filter: &Filter) -> Result<Vec<PathBuf>> {
+    if !tokio::fs::try_exists(dst).await.unwrap_or(false) {
+        return Ok(Vec::new());
+    }
+    let mut pending = list_dir(dst).await?;
+    let mut extras = Vec::new();
+    let mut kept = Vec::new();
+    while let Some(path) = pending.pop() {
+        let relative = path.strip_prefix(dst).unwrap_or(&path);
+        let is_dir = is_dir(&path).await;
+        match snapshot.files.get(relative) {
+            Some(Entry::Dir { .. }) if is_dir => pending.extend(list_dir(&path).await?),
+            Some(_) => {}
+            None if !filter.should_sync(&path) => kept.push(path),
+            None => {
+                if is_dir {
+                    pending.extend(list_dir(&path).await?);
+                }
+                extras.push(path);
+            }
+        }
+    }
+    extras.retain(|extra| !kept.iter().any(|kept| kept.starts_with(extra)));
+    Ok(extras)
+}
+
+async fn delete_extras(snapshot: &Snapshot, dst: &Path, filter: &Filter) -> Result {
+    // What is in a directory goes before it
+    for path in find_extras(snapshot, dst, filter).await?.iter().rev() {
+        remove(path).await?;
+    }
+    Ok(())
+}
+
+async fn list_dir(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    let mut entries = tokio::fs::read_dir(dir)
+        .await
+        .map_err(Error::io(format!("failed to list {:?}", dir)))?;
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .map_err(Error::io(format!("failed to list {:?}", dir)))?
+    {
+        paths.push(entry.path());
+    }
+    Ok(paths)
+}
+
+async fn hash_file(path: &Path) -> std::io::Result<String> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; BUFFER_SIZE];
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            return Ok(hex::encode(hasher.finalize()));
+        }
+        hasher.update(&buffer[..read]);
+    }
+}
+
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+// Parses the time to restore a snapshot at, in milliseconds since the unix epoch: seconds since
+// the epoch, a UTC date and time like 2024-05-01T13:45:00Z, or how long ago like 30s, 15m, 2h or
+// 1d. Times given to the second include the whole second, so that the times snapshots are listed
+// with pick those snapshots
+pub fn parse_time(value: &str, now_ms: u64) -> Result<u64> {
+    let invalid = || {
+        Error::Config(format!(
+            "invalid time '{}', expected seconds since the epoch, a date like \
+             2024-05-01T13:45:00Z or a duration like 15m",
+            value
+        ))
+    };
+    let value = value.trim();
+    let unit_ms = match value.chars().last() {
+        Some('s') => Some(1000),
+        Some('m') => Some(60 * 1000),
+        Some('h') => Some(60 * 60 * 1000),
+        Some('d') => Some(MILLIS_PER_DAY as u64),
+        _ => None,
+    };
+    if let Some(unit_ms) = unit_ms {
+        let amount: u64 = value[..value.len() - 1].parse().map_err(|_| invalid())?;
+        return Ok(now_ms.saturating_sub(amount.saturating_mul(unit_ms)));
+    }
+    if let Ok(seconds) = value.parse::<u64>() {
+        return seconds
+            .checked_mul(1000)
+            .and_then(|ms| ms.checked_add(999))
+            .ok_or_else(invalid);
+    }
+    let value = value.strip_suffix('Z').unwrap_or(value);
+    let (date, time) = value.split_once(['T', ' ']).unwrap_or((value, "00:00:00"));
+    let numbers = |part: &str, separator| {
+        part.split(separator)
+            .map(|number| number.parse::<i64>().map_err(|_| invalid()))
+            .collect::<Result<Vec<i64>>>()
+    };
+    let (date, time) = (numbers(date, '-')?, numbers(time, ':')?);
+    let (year, month, day) = match date[..] {
+        // Larger years would overflow once in milliseconds
+        [year, month, day]
+            if (0..=9999).contains(&year)
+                && (1..=12).contains(&month)
+                && (1..=31).contains(&day) =>
+        {
+            (year, month, day)
+        }
+        _ => return Err(invalid()),
+    };
+    let (hour, minute, second) = match time[..] {
+        [hour, minute] => (hour, minute, 0),
+        [hour, minute, second] => (hour, minute, second),
+        _ => return Err(invalid()),
+    };
+    if hour > 23 || minute > 59 || second > 59 {
+        return Err(invalid());
+    }
+    let ms = days_from_civil(year, month, day) * MILLIS_PER_DAY
+        + ((hour * 60 + minute) * 60 + second) * 1000
+        + 999;
+    u64::try_from(ms).map_err(|_| invalid())
+}
+
+// Formats milliseconds since the unix epoch the way parse_time takes them, in UTC
+pub fn format_time(ms: u64) -> String {
+    let ms = ms as i64;
+    let (year, month, day) = civil_from_days(ms.div_euclid(MILLIS_PER_DAY));
+    let seconds = ms.rem_euclid(MILLIS_PER_DAY) / 1000;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+// Days between the unix epoch and a date of the proleptic Gregorian calendar, see
+// http://howardhinnant.github.io/date_algorithms.html
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "type")]
+pub enum StoreProperties {
+    #[serde(rename = "local")]
+    Local { path: String },
+    #[serde(rename = "s3")]
+    S3 {
+        #[serde(flatten)]
+        bucket: S3BucketProperties,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        prefix: Option<String>,
+    },
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SnapshotProperties {
+    store: StoreProperties,
+}
+
+impl SnapshotProperties {
+    pub fn as_store(&self) -> Box<dyn SnapshotStore> {
+        match &self.store {
+            StoreProperties::Local { path } => Box::new(LocalStore::new(path)),
+            StoreProperties::S3 { bucket, prefix } => Box::new(S3Store::new(
+                bucket.as_bucket(),
+                prefix.as_deref().unwrap_or_default(),
+            )),
+        }
+    }
+
+    pub fn as_syncer(&self) -> SnapshotSyncer {
+        SnapshotSyncer::new(self.as_store())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::filter::{Filter, FilterAction};
+    use crate::syncers::snapshot::store::LocalStore;
+    use crate::syncers::snapshot::{
+        find_extras, find_outdated, format_time, list_snapshots, load_snapshot, parse_time,
+        restore, SnapshotSyncer,
+    };
+    use crate::syncers::{Changes, Syncer};
+    use notify::event::ModifyKind;
+    use notify::EventKind;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+
+    fn write(path: &Path, contents: &str) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+
+    fn read(path: &Path) -> String {
+        std::fs::read_to_string(path).unwrap()
+    }
+
+    fn count_files(dir: &Path) -> usize {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .map(|path| match path.is_dir() {
+                true => count_files(&path),
+                false => 1,
+            })
+            .sum()
+    }
+
+    #[tokio::test]
+    async fn snapshots_are_deduplicated_and_restored() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let (src, store) = (root.join("src"), root.join("store"));
+        write(&src.join("file1"), "one");
+        write(&src.join("sub/file2"), "one");
+        write(&src.join("kept.log"), "excluded");
+        std::os::unix::fs::symlink("file1", src.join("link")).unwrap();
+        let filter = Arc::new(Filter::new(&src, [(FilterAction::Exclude, "*.log")], None).unwrap());
+        let syncer = SnapshotSyncer::new(Box::new(LocalStore::new(store.to_str().unwrap())))
+            .with_filter(filter.clone());
+
+        // Both files have the same content, which is only stored once
+        syncer.sync(&src, &Changes::full()).await.unwrap();
+        assert_eq!(count_files(&store.join("objects")), 1);
+        syncer.sync(&src, &Changes::full()).await.unwrap();
+        let store = LocalStore::new(store.to_str().unwrap());
+        let first = list_snapshots(&store).await.unwrap();
+        assert_eq!(first.len(), 1);
+
+        write(&src.join("file1"), "changed");
+        let mut changes = Changes::new();
+        changes.insert(src.join("file1"), EventKind::Modify(ModifyKind::Any));
+        syncer.sync(&src, &changes).await.unwrap();
+        assert_eq!(list_snapshots(&store).await.unwrap().len(), 2);
+        assert_eq!(count_files(&root.join("store/objects")), 2);
+
+        // Restoring into the workspace deletes what wasn't there, except for ignored files
+        write(&src.join("new"), "new");
+        write(&src.join("new.log"), "excluded");
+        write(&src.join("added/file"), "new");
+        write(&src.join("mixed/file"), "new");
+        write(&src.join("mixed/file.log"), "excluded");
+        std::fs::remove_dir_all(src.join("sub")).unwrap();
+        let snapshot = load_snapshot(&store, Some(first[0]))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            find_outdated(&snapshot, &src, true).await,
+            (
+                vec![src.join("file1"), src.join("sub"), src.join("sub/file2")],
+                vec![]
+            )
+        );
+        // Directories are only deleted along with everything in them
+        let mut extras = find_extras(&snapshot, &src, &filter).await.unwrap();
+        extras.sort();
+        assert_eq!(
+            extras,
+            [
+                src.join("added"),
+                src.join("added/file"),
+                src.join("mixed/file"),
+                src.join("new")
+            ]
+        );
+        restore(&store, &snapshot, &src, Some(&filter))
+            .await
+            .unwrap();
+        assert_eq!(read(&src.join("file1")), "one");
+        assert_eq!(read(&src.join("sub/file2")), "one");
+        assert_eq!(
+            std::fs::read_link(src.join("link")).unwrap(),
+            PathBuf::from("file1")
+        );
+        assert!(!src.join("new").exists());
+        assert!(!src.join("added").exists());
+        assert_eq!(read(&src.join("mixed/file.log")), "excluded");
+        assert_eq!(read(&src.join("new.log")), "excluded");
+
+        let latest = load_snapshot(&store, None).await.unwrap().unwrap();
+        let dst = root.join("restored");
+        restore(&store, &latest, &dst, None).await.unwrap();
+        assert_eq!(read(&dst.join("file1")), "changed");
+        assert_eq!(read(&dst.join("sub/file2")), "one");
+        assert!(!dst.join("kept.log").exists());
+
+        // A directory where the snapshot has a file is only replaced when deleting
+        std::fs::remove_file(dst.join("file1")).unwrap();
+        write(&dst.join("file1/kept.log"), "excluded");
+        assert_eq!(
+            find_outdated(&latest, &dst, false).await,
+            (vec![], vec![dst.join("file1")])
+        );
+        let (written, kept) = restore(&store, &latest, &dst, None).await.unwrap();
+        assert_eq!((written, kept), (0, vec![dst.join("file1")]));
+        assert_eq!(read(&dst.join("file1/kept.log")), "excluded");
+    }
+
+    #[test]
+    fn times_are_parsed_and_formatted() {
+        let now_ms = 10_000_000;
+        assert_eq!(parse_time("2h", now_ms).unwrap(), 2_800_000);
+        assert_eq!(parse_time("1714571100", now_ms).unwrap(), 1714571100999);
+        assert_eq!(
+            parse_time("2024-05-01T13:45:00Z", now_ms).unwrap(),
+            1714571100999
+        );
+        assert_eq!(
+            parse_time("2024-05-01 13:45", now_ms).unwrap(),
+            1714571100999
+        );
+        assert_eq!(format_time(1714571100999), "2024-05-01T13:45:00Z");
+        assert_eq!(format_time(0), "1970-01-01T00:00:00Z");
+        assert!(parse_time("yesterday", now_ms).is_err());
+        assert!(parse_time("18446744073709551615", now_ms).is_err());
+        assert!(parse_time("9223372036854775807-01-01", now_ms).is_err());
+        assert!(parse_time("2024-13-01", now_ms).is_err());
+        assert!(parse_time("2w", now_ms).is_err());
+    }
+}
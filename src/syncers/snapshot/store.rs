@@ -0,0 +1,292 @@
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use aws_sdk_s3::primitives::ByteStream;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::error::{Error, Result};
+use crate::syncers::local::replace_with;
+use crate::syncers::s3::{object_key, s3_error, S3Bucket};
+use crate::syncers::snapshot::BUFFER_SIZE;
+
+// Where snapshots are kept: the content of files, stored once per distinct content under its
+// SHA-256, and the snapshots listing which file had which content, stored by name
+#[async_trait]
+pub trait SnapshotStore: Send + Sync {
+    fn name(&self) -> String;
+
+    // Stores the content of the file under its hash, unless the store already has it. Fails
+    // rather than storing anything else if the file no longer has that hash. Returns the number
+    // of bytes stored
+    async fn put_object(&self, hash: &str, path: &Path) -> Result<u64>;
+
+    // Writes the content stored under the hash to dst
+    async fn get_object(&self, hash: &str, dst: &Path) -> Result;
+
+    async fn put_snapshot(&self, name: &str, data: Vec<u8>) -> Result;
+
+    async fn get_snapshot(&self, name: &str) -> Result<Vec<u8>>;
+
+    async fn list_snapshots(&self) -> Result<Vec<String>>;
+}
+
+// Keeps everything in a local directory, e.g. on another disk:
+// objects/<first two characters of the hash>/<hash> and snapshots/<name>.json
+pub struct LocalStore {
+    root: PathBuf,
+}
+
+impl LocalStore {
+    pub fn new(root: &str) -> Self {
+        LocalStore {
+            root: PathBuf::from(root),
+        }
+    }
+
+    fn object_path(&self, hash: &str) -> PathBuf {
+        self.root.join("objects").join(&hash[..2]).join(hash)
+    }
+
+    fn snapshot_path(&self, name: &str) -> PathBuf {
+        self.root.join("snapshots").join(format!("{}.json", name))
+    }
+}
+
+#[async_trait]
+impl SnapshotStore for LocalStore {
+    fn name(&self) -> String {
+        format!("local:{}", self.root.display())
+    }
+
+    async fn put_object(&self, hash: &str, path: &Path) -> Result<u64> {
+        let object = self.object_path(hash);
+        if tokio::fs::try_exists(&object).await.unwrap_or(false) {
+            return Ok(0);
+        }
+        // The file is hashed again while being copied, as it may have changed since
+        replace_with(&object, |temp| async move {
+            let mut src = tokio::fs::File::open(path).await?;
+            let mut dst = tokio::fs::File::create(&temp).await?;
+            let mut hasher = Sha256::new();
+            let mut buffer = vec![0; BUFFER_SIZE];
+            let mut size = 0;
+            loop {
+                let read = src.read(&mut buffer).await?;
+                if read == 0 {
+                    break;
+                }
+                hasher.update(&buffer[..read]);
+                dst.write_all(&buffer[..read]).await?;
+                size += read as u64;
+            }
+            dst.sync_all().await?;
+            if hex::encode(hasher.finalize()) != hash {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    "file changed while being stored",
+                ));
+            }
+            Ok(size)
+        })
+        .await
+        .map_err(Error::io(format!(
+            "failed to store {:?} in {:?}",
+            path, object
+        )))
+    }
+
+    async fn get_object(&self, hash: &str, dst: &Path) -> Result {
+        let object = self.object_path(hash);
+        tokio::fs::copy(&object, dst)
+            .await
+            .map(|_| ())
+            .map_err(Error::io(format!(
+                "failed to copy {:?} to {:?}",
+                object, dst
+            )))
+    }
+
+    async fn put_snapshot(&self, name: &str, data: Vec<u8>) -> Result {
+        let path = self.snapshot_path(name);
+        replace_with(&path, |temp| tokio::fs::write(temp, data))
+            .await
+            .map_err(Error::io(format!("failed to write snapshot {:?}", path)))
+    }
+
+    async fn get_snapshot(&self, name: &str) -> Result<Vec<u8>> {
+        let path = self.snapshot_path(name);
+        tokio::fs::read(&path)
+            .await
+            .map_err(Error::io(format!("failed to read snapshot {:?}", path)))
+    }
+
+    async fn list_snapshots(&self) -> Result<Vec<String>> {
+        let dir = self.root.join("snapshots");
+        let mut names = Vec::new();
+        let mut entries = match tokio::fs::read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(names),
+            Err(e) => return Err(Error::io(format!("failed to list {:?}", dir))(e)),
+        };
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .map_err(Error::io(format!("failed to list {:?}", dir)))?
+        {
+            if let Some(name) = entry.file_name().to_str().and_then(|name| {
+                name.strip_suffix(".json")
+                    .filter(|name| !name.starts_with('.'))
+            }) {
+                names.push(name.to_string());
+            }
+        }
+        Ok(names)
+    }
+}
+
+// Keeps everything in a bucket, under a prefix, laid out as in the local store. Objects are
+// uploaded along with their SHA-256 so that S3 turns down content that changed while being read
+pub struct S3Store {
+    bucket: S3Bucket,
+    prefix: String,
+}
+
+impl S3Store {
+    pub fn new(bucket: S3Bucket, prefix: &str) -> Self {
+        S3Store {
+            bucket,
+            prefix: prefix.trim_matches('/').to_string(),
+        }
+    }
+
+    fn object_key(&self, hash: &str) -> String {
+        object_key(&self.prefix, &format!("objects/{}/{}", &hash[..2], hash))
+    }
+
+    fn snapshot_key(&self, name: &str) -> String {
+        object_key(&self.prefix, &format!("snapshots/{}.json", name))
+    }
+
+    fn location(&self, key: &str) -> String {
+        format!("s3://{}/{}", self.bucket.name, key)
+    }
+
+    async fn get(&self, key: &str) -> Result<ByteStream> {
+        self.bucket
+            .client()
+            .await
+            .get_object()
+            .bucket(&self.bucket.name)
+            .key(key)
+            .send()
+            .await
+            .map(|output| output.body)
+            .map_err(s3_error(format!(
+                "failed to download {}",
+                self.location(key)
+            )))
+    }
+}
+
+#[async_trait]
+impl SnapshotStore for S3Store {
+    fn name(&self) -> String {
+        self.location(&self.prefix)
+            .trim_end_matches('/')
+            .to_string()
+    }
+
+    async fn put_object(&self, hash: &str, path: &Path) -> Result<u64> {
+        let key = self.object_key(hash);
+        if self.bucket.head(&key).await?.is_some() {
+            return Ok(0);
+        }
+        let checksum = hex::decode(hash)
+            .map_err(|_| Error::Config(format!("invalid content hash {} for {:?}", hash, path)))?;
+        let body = ByteStream::from_path(path).await.map_err(|e| Error::S3 {
+            context: format!("failed to read {:?}", path),
+            transient: false,
+            source: Box::new(e),
+        })?;
+        let size = body.size_hint().0;
+        self.bucket
+            .client()
+            .await
+            .put_object()
+            .bucket(&self.bucket.name)
+            .key(&key)
+            .checksum_sha256(aws_smithy_types::base64::encode(checksum))
+            .body(body)
+            .send()
+            .await
+            .map_err(s3_error(format!(
+                "failed to upload {:?} to {}",
+                path,
+                self.location(&key)
+            )))?;
+        Ok(size)
+    }
+
+    async fn get_object(&self, hash: &str, dst: &Path) -> Result {
+        let key = self.object_key(hash);
+        let mut body = self.get(&key).await?.into_async_read();
+        let context = format!("failed to download {} to {:?}", self.location(&key), dst);
+        let mut file = tokio::fs::File::create(dst)
+            .await
+            .map_err(Error::io(context.clone()))?;
+        tokio::io::copy_buf(&mut body, &mut file)
+            .await
+            .map_err(Error::io(context))?;
+        Ok(())
+    }
+
+    async fn put_snapshot(&self, name: &str, data: Vec<u8>) -> Result {
+        let key = self.snapshot_key(name);
+        self.bucket
+            .client()
+            .await
+            .put_object()
+            .bucket(&self.bucket.name)
+            .key(&key)
+            .body(ByteStream::from(data))
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(s3_error(format!(
+                "failed to upload {}",
+                self.location(&key)
+            )))
+    }
+
+    async fn get_snapshot(&self, name: &str) -> Result<Vec<u8>> {
+        let key = self.snapshot_key(name);
+        let data = self
+            .get(&key)
+            .await?
+            .collect()
+            .await
+            .map_err(|e| Error::S3 {
+                context: format!("failed to download {}", self.location(&key)),
+                transient: true,
+                source: Box::new(e),
+            })?;
+        Ok(data.to_vec())
+    }
+
+    async fn list_snapshots(&self) -> Result<Vec<String>> {
+        let prefix = object_key(&self.prefix, "snapshots/");
+        Ok(self
+            .bucket
+            .list(&prefix)
+            .await?
+            .into_keys()
+            .filter_map(|key| {
+                key.strip_prefix(&prefix)
+                    .and_then(|name| name.strip_suffix(".json"))
+                    .map(String::from)
+            })
+            .collect())
+    }
+}
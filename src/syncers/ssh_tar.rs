@@ -0,0 +1,548 @@
+use std::collections::BTreeSet;
+use std::io::{BufWriter, ErrorKind, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::{Output, Stdio};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+use crate::error::{Error, Result};
+use crate::filter::{self, Filter};
+use crate::rsync::shell::ssh::SSHShell;
+use crate::syncers::rsyncer::{
+    default_dst_host, ssh_shell, RsyncGlobalProperties, SSHAdditionalProperties,
+};
+use crate::syncers::{Changes, Result as SyncerResult, SyncStats, Syncer};
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+// How scripts get to run where the files go
+pub enum Transport {
+    Ssh {
+        host: String,
+        shell: SSHShell,
+    },
+    // Through the runtime of a local container, e.g. docker or podman
+    Exec {
+        runtime: String,
+        container: String,
+        user: Option<String>,
+    },
+}
+
+impl Transport {
+    fn command(&self, script: &str) -> Command {
+        match self {
+            Transport::Ssh { host, shell } => shell.command(host, &sh_command(script)),
+            Transport::Exec {
+                runtime,
+                container,
+                user,
+            } => {
+                let mut cmd = Command::new(runtime);
+                cmd.kill_on_drop(true);
+                cmd.args(["exec", "-i"]);
+                if let Some(user) = user {
+                    cmd.args(["--user", user]);
+                }
+                cmd.args([container, "sh", "-c", script]);
+                cmd
+            }
+        }
+    }
+
+    fn program(&self) -> &str {
+        match self {
+            Transport::Ssh { .. } => "ssh",
+            Transport::Exec { runtime, .. } => runtime,
+        }
+    }
+
+    // The host or container the files go to
+    fn target(&self) -> &str {
+        match self {
+            Transport::Ssh { host, .. } => host,
+            Transport::Exec { container, .. } => container,
+        }
+    }
+}
+
+// Syncs the workspace to a host that doesn't have rsync, or into a container, using nothing but
+// sh, tar and find on the other end. Changed files are streamed through ssh (or the container
+// runtime) as a tar archive and unpacked into the destination, preceded by the paths to delete,
+// one per line. Full syncs send every file and list the destination to find what to delete
+pub struct TarSyncer {
+    transport: Transport,
+    dst_dir: String,
+    filter: Option<Arc<Filter>>,
+}
+
+impl TarSyncer {
+    pub fn new(transport: Transport, dst_dir: &str) -> Self {
+        TarSyncer {
+            transport,
+            dst_dir: String::from(dst_dir),
+            filter: None,
+        }
+    }
+
+    // Files the workspace leaves out are neither sent nor deleted from the destination
+    pub fn with_filter(mut self, filter: Arc<Filter>) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    // Adds path (relative to the workspace) to files, along with everything below it when
+    // recursive. Returns false if there is nothing at path
+    async fn collect(
+        &self,
+        workspace_path: &Path,
+        start: &Path,
+        recursive: bool,
+        files: &mut BTreeSet<PathBuf>,
+    ) -> Result<bool> {
+        let mut pending = vec![start.to_path_buf()];
+        while let Some(relative) = pending.pop() {
+            let path = workspace_path.join(&relative);
+            if !filter::allows(self.filter.as_deref(), &path) {
+                continue;
+            }
+            let metadata = match tokio::fs::symlink_metadata(&path).await {
+                Ok(metadata) => metadata,
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    if relative == start {
+                        return Ok(false);
+                    }
+                    continue;
+                }
+                Err(e) => return Err(Error::io(format!("failed to read {:?}", path))(e)),
+            };
+            if metadata.is_dir() && (recursive || relative != start) {
+                let mut entries = tokio::fs::read_dir(&path)
+                    .await
+                    .map_err(Error::io(format!("failed to list {:?}", path)))?;
+                while let Some(entry) = entries
+                    .next_entry()
+                    .await
+                    .map_err(Error::io(format!("failed to list {:?}", path)))?
+                {
+                    pending.push(relative.join(entry.file_name()));
+                }
+            } else if !metadata.is_dir() && !metadata.is_file() && !metadata.is_symlink() {
+                // Sockets, fifos and the like can't be sent
+                continue;
+            }
+            // The workspace itself is the destination directory, which always exists
+            if !relative.as_os_str().is_empty() {
+                files.insert(relative);
+            }
+        }
+        Ok(true)
+    }
+
+    // Paths in the destination, relative to it. Separated by NUL, the only byte names can't have
+    async fn list_remote(&self) -> Result<Vec<PathBuf>> {
+        let script = format!(
+            "if cd {} 2>/dev/null; then find . ! -name . -print0; fi",
+            shell_quote(&self.dst_dir)
+        );
+        let output = self.run(&script).await?;
+        Ok(output
+            .stdout
+            .split(|byte| *byte == 0)
+            .filter_map(|line| line.strip_prefix(b"./"))
+            .map(|line| PathBuf::from(std::ffi::OsStr::from_bytes(line)))
+            .collect())
+    }
+
+    // Deletes paths from the destination, then unpacks the files into it. Returns the number of
+    // bytes sent
+    async fn push(
+        &self,
+        workspace_path: &Path,
+        files: BTreeSet<PathBuf>,
+        deletions: &[PathBuf],
+    ) -> Result<u64> {
+        let mut header = Vec::new();
+        for path in deletions {
+            let path = path.as_os_str().as_bytes();
+            if path.contains(&b'\n') {
+                warn!(path = ?path, "Unable to delete a path with a line break in its name");
+                continue;
+            }
+            header.extend_from_slice(path);
+            header.push(b'\n');
+        }
+        header.push(b'\n');
+        let script = format!(
+            "set -e; mkdir -p {dir}; cd {dir}; \
+             while IFS= read -r path && [ -n \"$path\" ]; do rm -rf -- \"./$path\"; done; \
+             exec tar -xf -",
+            dir = shell_quote(&self.dst_dir)
+        );
+        let mut cmd = self.transport.command(&script);
+        cmd.stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        debug!("Running: '{:?}'", cmd);
+        let mut child = cmd.spawn().map_err(|source| Error::Spawn {
+            program: self.transport.program().to_string(),
+            source,
+        })?;
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+
+        // The archive is written on a blocking thread and handed over in chunks as it goes
+        let (sender, mut chunks) = mpsc::channel(8);
+        let workspace = workspace_path.to_path_buf();
+        let archive = tokio::task::spawn_blocking(move || {
+            write_archive(&workspace, &files, ChunkWriter { sender })
+        });
+        let send = async move {
+            let mut bytes = header.len() as u64;
+            stdin.write_all(&header).await?;
+            while let Some(chunk) = chunks.recv().await {
+                bytes += chunk.len() as u64;
+                stdin.write_all(&chunk).await?;
+            }
+            Ok(bytes)
+        };
+        // Whatever the command outputs is read along the way, so that it never blocks on it
+        let (sent, output) = tokio::join!(send, child.wait_with_output());
+        let output = output.map_err(|source| Error::Spawn {
+            program: self.transport.program().to_string(),
+            source,
+        })?;
+        self.check(output)?;
+        archive
+            .await
+            .expect("writing the archive panicked")
+            .map_err(Error::io(format!(
+                "failed to send files to {}",
+                self.destination()
+            )))?;
+        sent.map_err(Error::io(format!(
+            "failed to send files to {}",
+            self.destination()
+        )))
+    }
+
+    // Runs the script on the host, failing if it does
+    async fn run(&self, script: &str) -> Result<Output> {
+        let mut cmd = self.transport.command(script);
+        cmd.stdin(Stdio::null());
+        debug!("Running: '{:?}'", cmd);
+        let output = cmd.output().await.map_err(|source| Error::Spawn {
+            program: self.transport.program().to_string(),
+            source,
+        })?;
+        self.check(output)
+    }
+
+    // ssh exits with 255 when it couldn't reach the host, and with the status of the command
+    // otherwise. Container runtimes don't tell their own failures apart as clearly
+    fn check(&self, output: Output) -> Result<Output> {
+        if output.status.success() {
+            return Ok(output);
+        }
+        let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+        Err(match (&self.transport, output.status.code()) {
+            (Transport::Ssh { host, .. }, Some(255)) => Error::Ssh {
+                host: host.clone(),
+                stderr,
+            },
+            (transport, _) => Error::Remote {
+                host: transport.target().to_string(),
+                status: output.status,
+                stderr,
+            },
+        })
+    }
+
+    fn destination(&self) -> String {
+        format!("{}:{}", self.transport.target(), self.dst_dir)
+    }
+}
+
+#[async_trait]
+impl Syncer for TarSyncer {
+    fn name(&self) -> String {
+        match self.transport {
+            Transport::Ssh { .. } => format!("ssh-tar:{}", self.destination()),
+            Transport::Exec { .. } => format!("container:{}", self.destination()),
+        }
+    }
+
+    async fn sync(&self, workspace_path: &Path, changes: &Changes) -> SyncerResult {
+        let mut files = BTreeSet::new();
+        let mut deletions = Vec::new();
+        let root_changed = changes.iter().any(|(path, _)| path == workspace_path);
+        if changes.is_full() || root_changed {
+            self.collect(workspace_path, Path::new(""), true, &mut files)
+                .await?;
+            let mut remote = self.list_remote().await?;
+            remote.sort();
+            for path in remote {
+                if files.contains(&path)
+                    || !filter::allows(self.filter.as_deref(), &workspace_path.join(&path))
+                    || deletions
+                        .last()
+                        .is_some_and(|deleted| path.starts_with(deleted))
+                {
+                    continue;
+                }
+                deletions.push(path);
+            }
+        } else {
+            for (relative, recursive) in changes.roots(workspace_path) {
+                if !self
+                    .collect(workspace_path, &relative, recursive, &mut files)
+                    .await?
+                    && filter::allows(self.filter.as_deref(), &workspace_path.join(&relative))
+                {
+                    deletions.push(relative);
+                }
+            }
+        }
+        if files.is_empty() && deletions.is_empty() {
+            return Ok(SyncStats::default());
+        }
+        let bytes = self.push(workspace_path, files, &deletions).await?;
+        Ok(SyncStats {
+            bytes_transferred: Some(bytes),
+        })
+    }
+
+    async fn close(&self) {
+        if let Transport::Ssh { host, shell } = &self.transport {
+            shell.close(Some(host)).await;
+        }
+    }
+}
+
+// Hands what is written to it over to the task sending it through ssh or the container runtime
+struct ChunkWriter {
+    sender: mpsc::Sender<Vec<u8>>,
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.sender
+            .blocking_send(buf.to_vec())
+            .map_err(|_| std::io::Error::new(ErrorKind::BrokenPipe, "stopped reading"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Writes the files (relative to the workspace) as a tar archive. Symlinks are archived as
+// symlinks, and files that disappeared in the meantime are skipped
+fn write_archive<W: Write>(
+    workspace_path: &Path,
+    files: &BTreeSet<PathBuf>,
+    writer: W,
+) -> std::io::Result<()> {
+    let mut builder = tar::Builder::new(BufWriter::with_capacity(BUFFER_SIZE, writer));
+    builder.follow_symlinks(false);
+    for relative in files {
+        match builder.append_path_with_name(workspace_path.join(relative), relative) {
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            result => result?,
+        }
+    }
+    builder.into_inner()?.flush()
+}
+
+// Makes the script run by sh whatever the login shell of the remote user is
+fn sh_command(script: &str) -> String {
+    format!("sh -c {}", shell_quote(script))
+}
+
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TarProperties {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dst_host: Option<String>,
+    dst_dir: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ssh: Option<SSHAdditionalProperties>,
+}
+
+impl TarProperties {
+    // Reaches hosts the same way the rsync syncers do, with their default host and ssh options
+    pub fn as_syncer(&self, global_props: Option<&RsyncGlobalProperties>) -> Result<TarSyncer> {
+        let dst_host = self
+            .dst_host
+            .as_deref()
+            .or_else(|| default_dst_host(global_props))
+            .ok_or_else(|| {
+                Error::Config(
+                    "ssh_tar syncers need a dst_host, or a global rsync.default_dst_host"
+                        .to_string(),
+                )
+            })?;
+        let shell =
+            ssh_shell(global_props, self.ssh.as_ref())?.unwrap_or_else(|| SSHShell::new(vec![]));
+        let transport = Transport::Ssh {
+            host: String::from(dst_host),
+            shell,
+        };
+        Ok(TarSyncer::new(transport, &self.dst_dir))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::filter::{Filter, FilterAction};
+    use crate::rsync::shell::ssh::SSHShell;
+    use crate::syncers::ssh_tar::{sh_command, write_archive, TarProperties, TarSyncer, Transport};
+    use crate::syncers::{Changes, Syncer};
+    use notify::event::RemoveKind;
+    use notify::EventKind;
+    use std::collections::BTreeSet;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn changed_files_are_archived() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::create_dir_all(root.join("sub/deep")).unwrap();
+        std::fs::write(root.join("file1"), "one").unwrap();
+        std::fs::write(root.join("sub/deep/file2"), "two").unwrap();
+        std::fs::write(root.join("sub/skipped.log"), "excluded").unwrap();
+        std::os::unix::fs::symlink("file1", root.join("link")).unwrap();
+        let filter = Filter::new(root, [(FilterAction::Exclude, "*.log")], None).unwrap();
+        let transport = Transport::Ssh {
+            host: String::from("host"),
+            shell: SSHShell::new(vec![]),
+        };
+        let syncer = TarSyncer::new(transport, "/remote/dir").with_filter(Arc::new(filter));
+
+        let mut files = BTreeSet::new();
+        assert!(syncer
+            .collect(root, Path::new(""), true, &mut files)
+            .await
+            .unwrap());
+        let expected = ["file1", "link", "sub", "sub/deep", "sub/deep/file2"];
+        assert_eq!(files, expected.iter().map(PathBuf::from).collect());
+        // Only the directory itself is looked at when its content didn't change
+        let mut dir_only = BTreeSet::new();
+        syncer
+            .collect(root, Path::new("sub"), false, &mut dir_only)
+            .await
+            .unwrap();
+        assert_eq!(dir_only, BTreeSet::from([PathBuf::from("sub")]));
+        assert!(!syncer
+            .collect(root, Path::new("deleted"), true, &mut BTreeSet::new())
+            .await
+            .unwrap());
+
+        let mut archive = Vec::new();
+        write_archive(root, &files, &mut archive).unwrap();
+        let mut archive = tar::Archive::new(archive.as_slice());
+        let entries = archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                let link = entry.link_name().unwrap().map(|link| link.into_owned());
+                (entry.path().unwrap().into_owned(), link)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(entries.len(), expected.len());
+        assert!(entries.contains(&(PathBuf::from("link"), Some(PathBuf::from("file1")))));
+        assert!(entries.contains(&(PathBuf::from("sub/deep/file2"), None)));
+    }
+
+    #[test]
+    fn scripts_are_quoted_for_sh() {
+        assert_eq!(
+            sh_command("cd '/dir'; ls"),
+            r#"sh -c 'cd '\''/dir'\''; ls'"#
+        );
+        let props: TarProperties = serde_yaml::from_str("dst_dir: /remote/dir").unwrap();
+        assert!(props.as_syncer(None).is_err());
+        // Container runtimes take the script as an argument of its own, with no shell in between
+        let transport = Transport::Exec {
+            runtime: String::from("podman"),
+            container: String::from("app"),
+            user: Some(String::from("dev")),
+        };
+        let cmd = transport.command("cd '/dir'; ls");
+        assert_eq!(cmd.as_std().get_program(), "podman");
+        assert_eq!(
+            cmd.as_std().get_args().collect::<Vec<_>>(),
+            [
+                "exec",
+                "-i",
+                "--user",
+                "dev",
+                "app",
+                "sh",
+                "-c",
+                "cd '/dir'; ls"
+            ]
+        );
+    }
+
+    // Runs the scripts with the local sh, through a runtime that drops the exec arguments
+    #[tokio::test]
+    async fn scripts_unpack_and_delete_in_the_destination() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("workspace");
+        let dst_dir = dir.path().join("dst");
+        let runtime = dir.path().join("runtime");
+        std::fs::write(&runtime, "#!/bin/sh\nshift 3\nexec \"$@\"\n").unwrap();
+        std::fs::set_permissions(&runtime, PermissionsExt::from_mode(0o755)).unwrap();
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("file1"), "one").unwrap();
+        std::fs::write(root.join("it's a file"), "two").unwrap();
+        std::fs::write(root.join("sub/file3"), "three").unwrap();
+        let transport = Transport::Exec {
+            runtime: runtime.to_str().unwrap().to_string(),
+            container: String::from("app"),
+            user: None,
+        };
+        let syncer = TarSyncer::new(transport, dst_dir.to_str().unwrap());
+
+        assert!(syncer.list_remote().await.unwrap().is_empty());
+        syncer.sync(&root, &Changes::full()).await.unwrap();
+        assert_eq!(std::fs::read(dst_dir.join("it's a file")).unwrap(), b"two");
+        assert_eq!(std::fs::read(dst_dir.join("sub/file3")).unwrap(), b"three");
+
+        std::fs::remove_file(root.join("it's a file")).unwrap();
+        std::fs::remove_dir_all(root.join("sub")).unwrap();
+        let mut changes = Changes::new();
+        changes.insert(
+            root.join("it's a file"),
+            EventKind::Remove(RemoveKind::File),
+        );
+        changes.insert(root.join("sub"), EventKind::Remove(RemoveKind::Folder));
+        syncer.sync(&root, &changes).await.unwrap();
+        assert!(!dst_dir.join("it's a file").exists());
+        assert!(!dst_dir.join("sub").exists());
+        assert!(dst_dir.join("file1").exists());
+
+        // Line breaks in names don't split them up, even though they can't be deleted
+        std::fs::write(dst_dir.join("two\nlines"), "").unwrap();
+        let mut remote = syncer.list_remote().await.unwrap();
+        remote.sort();
+        assert_eq!(
+            remote,
+            [PathBuf::from("file1"), PathBuf::from("two\nlines")]
+        );
+    }
+}